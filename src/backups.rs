@@ -0,0 +1,338 @@
+use crate::command::CommandRunner;
+use crate::executor::Executor;
+use crate::facts::FactsProvider;
+use crate::filesystem::Filesystem;
+use crate::prompt::Prompter;
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Duration, Utc};
+use std::path::{Component, Path, PathBuf};
+
+const BACKUPS_VERSION: u32 = 1;
+const BACKUPS_DIR_NAME: &str = "dotfilers";
+const BACKUPS_SUBDIR_NAME: &str = "backups";
+const RUN_MANIFEST_FILE_NAME: &str = "manifest.json";
+
+/// Something moved out of the way by the backup conflict strategy.
+#[derive(Debug, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct BackupEntry {
+    /// Where it was found
+    pub original: PathBuf,
+    /// Where it is kept, inside the directory of its run
+    pub backup: PathBuf,
+    pub section: String,
+    /// RFC 3339 timestamp of the moment it was backed up
+    pub backed_up_at: String,
+}
+
+/// Every backup made by a single run, stored in `<root>/<id>/manifest.json`.
+#[derive(Debug, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct BackupRun {
+    pub version: u32,
+    pub id: String,
+    /// RFC 3339 timestamp of the moment the run started
+    pub started_at: String,
+    pub entries: Vec<BackupEntry>,
+}
+
+/// Which backup runs are kept when pruning. Runs have to satisfy every limit that is set to be kept.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct Retention {
+    /// How many of the most recent runs are kept
+    pub keep: Option<usize>,
+    /// How old runs can be
+    pub max_age: Option<Duration>,
+}
+
+/// Directory where displaced files are kept, grouped by the run that displaced them.
+#[derive(Debug, Clone)]
+pub struct BackupStore {
+    root: PathBuf,
+    run: String,
+    started_at: DateTime<Utc>,
+}
+
+impl BackupStore {
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        Self::started_at(root, Utc::now())
+    }
+
+    /// A store whose backups belong to a run that started at `started_at`.
+    pub fn started_at<P: AsRef<Path>>(root: P, started_at: DateTime<Utc>) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+            run: run_id(started_at),
+            started_at,
+        }
+    }
+
+    /// Default location of the store: `$XDG_DATA_HOME/dotfilers/backups`.
+    pub fn default_path(data_home: &Path) -> PathBuf {
+        data_home.join(BACKUPS_DIR_NAME).join(BACKUPS_SUBDIR_NAME)
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Id of the current run.
+    pub fn run(&self) -> &str {
+        &self.run
+    }
+
+    /// Where the current run keeps the backup of `original`.
+    pub fn path_for(&self, original: &Path) -> PathBuf {
+        below(&self.root.join(&self.run), original)
+    }
+
+    /// Every run that made backups, oldest first.
+    pub fn runs(&self, fs: &dyn Filesystem) -> Result<Vec<BackupRun>> {
+        if !fs.exists(&self.root) {
+            return Ok(Vec::new());
+        }
+        let mut runs = Vec::new();
+        for dir in fs
+            .read_dir(&self.root)
+            .context(format!("Error listing backups in {}", self.root.display()))?
+        {
+            let id = match dir.file_name().and_then(|name| name.to_str()) {
+                Some(id) if fs.is_file(&dir.join(RUN_MANIFEST_FILE_NAME)) => id.to_string(),
+                _ => {
+                    debug!("Ignoring {} as it is not a backup run", dir.display());
+                    continue;
+                }
+            };
+            runs.push(self.load(fs, &id)?);
+        }
+        runs.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(runs)
+    }
+
+    pub fn load(&self, fs: &dyn Filesystem, run: &str) -> Result<BackupRun> {
+        let path = self.root.join(run).join(RUN_MANIFEST_FILE_NAME);
+        if !fs.exists(&path) {
+            return Err(anyhow!("Could not find backup run {}", run));
+        }
+        let contents = fs
+            .read_to_string(&path)
+            .context(format!("Error reading backup manifest {}", path.display()))?;
+        let loaded: BackupRun = serde_json::from_str(&contents).context(format!("Error parsing backup manifest {}", path.display()))?;
+        if loaded.version != BACKUPS_VERSION {
+            return Err(anyhow!(
+                "Unsupported backup manifest version {} in {} (expected {})",
+                loaded.version,
+                path.display(),
+                BACKUPS_VERSION
+            ));
+        }
+        Ok(loaded)
+    }
+
+    /// Writes the manifest of `run` atomically, or removes the whole run if it has no backups left.
+    fn save(&self, fs: &dyn Filesystem, run: &BackupRun) -> Result<()> {
+        let dir = self.root.join(&run.id);
+        if run.entries.is_empty() {
+            debug!("Removing backup run {} as it is empty", run.id);
+            return fs.remove(&dir).context(format!("Error removing backup run {}", dir.display()));
+        }
+        let path = dir.join(RUN_MANIFEST_FILE_NAME);
+        let contents = serde_json::to_string_pretty(run).context("Error serializing backup manifest")?;
+        let tmp_path = path.with_extension(format!("tmp.{}", std::process::id()));
+        fs.write(&tmp_path, contents.as_bytes())
+            .context(format!("Error writing backup manifest {}", tmp_path.display()))?;
+        fs.rename(&tmp_path, &path)
+            .context(format!("Error moving backup manifest into {}", path.display()))
+    }
+
+    /// Records a backup made by the current run, whose file has already been moved into the store.
+    pub(crate) fn add(&self, fs: &dyn Filesystem, entry: BackupEntry) -> Result<()> {
+        let mut run = match fs.exists(&self.root.join(&self.run).join(RUN_MANIFEST_FILE_NAME)) {
+            true => self.load(fs, &self.run)?,
+            false => BackupRun {
+                version: BACKUPS_VERSION,
+                id: self.run.clone(),
+                started_at: self.started_at.to_rfc3339(),
+                entries: Vec::new(),
+            },
+        };
+        debug!("Recording backup of {} in run {}", entry.original.display(), run.id);
+        run.entries.push(entry);
+        self.save(fs, &run)
+    }
+
+    /// Forgets the backup kept in `backup`, once it has been moved out of the store. Paths outside of it are ignored.
+    pub(crate) fn forget(&self, fs: &dyn Filesystem, backup: &Path) -> Result<()> {
+        let run_id = match backup.strip_prefix(&self.root).ok().and_then(|rest| rest.components().next()) {
+            Some(Component::Normal(id)) => id.to_string_lossy().to_string(),
+            _ => return Ok(()),
+        };
+        if !fs.exists(&self.root.join(&run_id).join(RUN_MANIFEST_FILE_NAME)) {
+            // The run was pruned already
+            return Ok(());
+        }
+        let mut run = self.load(fs, &run_id)?;
+        run.entries.retain(|e| e.backup != backup);
+        self.save(fs, &run)
+    }
+}
+
+impl<T, F, C, I> Executor<T, F, C, I>
+where
+    T: FactsProvider,
+    F: Filesystem,
+    C: CommandRunner,
+    I: Prompter,
+{
+    /// Moves the backups of `run` back to where they were found. If `paths` is not empty, only the backups of those
+    /// paths are restored.
+    ///
+    /// If something is in the way of any of them, nothing is restored unless `force` is set, in which case it is removed.
+    pub fn restore_backups(&self, run: &str, paths: &[PathBuf], force: bool) -> Result<()> {
+        let store = self.backup_store()?;
+        let backup_run = store.load(&self.filesystem, run)?;
+        let entries: Vec<&BackupEntry> = backup_run
+            .entries
+            .iter()
+            .filter(|e| paths.is_empty() || paths.contains(&e.original))
+            .collect();
+        if entries.is_empty() {
+            return Err(anyhow!("Backup run {} has nothing to restore", run));
+        }
+
+        let in_the_way: Vec<String> = entries
+            .iter()
+            .filter(|e| self.filesystem.symlink_metadata(&e.original).is_ok())
+            .map(|e| e.original.display().to_string())
+            .collect();
+        if !in_the_way.is_empty() {
+            if !force {
+                return Err(anyhow!(
+                    "Refusing to restore backups as these paths already exist: {}. Use force to replace them",
+                    in_the_way.join(", ")
+                ));
+            }
+            warn!("Replacing with their backups: {}", in_the_way.join(", "));
+        }
+
+        for entry in entries {
+            if self.dry_run {
+                info!(
+                    "Would restore backup [src={}] -> [dst={}]",
+                    entry.backup.display(),
+                    entry.original.display()
+                );
+                continue;
+            }
+            if self.filesystem.symlink_metadata(&entry.original).is_ok() {
+                warn!("Removing {}", entry.original.display());
+                self.filesystem
+                    .remove(&entry.original)
+                    .context(format!("Error removing {}", entry.original.display()))?;
+            }
+            if let Some(parent) = entry.original.parent() {
+                create_dir_all(&self.filesystem, parent)?;
+            }
+            self.filesystem.rename(&entry.backup, &entry.original).context(format!(
+                "Error restoring backup [src={}] -> [dst={}]",
+                entry.backup.display(),
+                entry.original.display()
+            ))?;
+            store.forget(&self.filesystem, &entry.backup)?;
+            info!(
+                "Restored backup [src={}] -> [dst={}]",
+                entry.backup.display(),
+                entry.original.display()
+            );
+        }
+        Ok(())
+    }
+
+    /// Removes the backup runs that fall outside of `retention`, returning their ids.
+    pub fn prune_backups(&self, retention: Retention) -> Result<Vec<String>> {
+        let store = self.backup_store()?;
+        let runs = store.runs(&self.filesystem)?;
+        let now = Utc::now();
+        let mut pruned = Vec::new();
+        for (position, run) in runs.iter().rev().enumerate() {
+            let too_many = retention.keep.map(|keep| position >= keep).unwrap_or(false);
+            let too_old = match retention.max_age {
+                Some(max_age) => {
+                    let started_at = DateTime::parse_from_rfc3339(&run.started_at)
+                        .context(format!("Error parsing start time of backup run {}", run.id))?;
+                    now.signed_duration_since(started_at) > max_age
+                }
+                None => false,
+            };
+            if !too_many && !too_old {
+                continue;
+            }
+            let dir = store.root().join(&run.id);
+            if self.dry_run {
+                info!("Would remove backup run {}", run.id);
+            } else {
+                warn!("Removing backup run {}", run.id);
+                self.filesystem
+                    .remove(&dir)
+                    .context(format!("Error removing backup run {}", dir.display()))?;
+            }
+            pruned.push(run.id.clone());
+        }
+        pruned.reverse();
+        Ok(pruned)
+    }
+
+    pub(crate) fn backup_store(&self) -> Result<&BackupStore> {
+        self.backup_store.as_ref().ok_or_else(|| anyhow!("There is no backup store"))
+    }
+}
+
+/// Id of a run that started at `started_at`. Ids sort in the same order as the runs, and are valid file names
+/// everywhere.
+pub(crate) fn run_id(started_at: DateTime<Utc>) -> String {
+    started_at.format("%Y-%m-%dT%H-%M-%S%.3fZ").to_string()
+}
+
+/// Where `original` is kept inside `root`, which mirrors the layout of the filesystem.
+pub(crate) fn below(root: &Path, original: &Path) -> PathBuf {
+    let mut relative = PathBuf::new();
+    for component in original.components() {
+        match component {
+            Component::Normal(name) => relative.push(name),
+            Component::ParentDir => {
+                relative.pop();
+            }
+            _ => {}
+        }
+    }
+    root.join(relative)
+}
+
+/// Creates `path` and all its missing parents.
+pub(crate) fn create_dir_all(fs: &dyn Filesystem, path: &Path) -> Result<()> {
+    let missing: Vec<&Path> = path.ancestors().take_while(|p| !fs.exists(p)).collect();
+    for dir in missing.into_iter().rev() {
+        fs.create_dir(dir).context(format!("Error creating directory {}", dir.display()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn backups_are_kept_below_their_run() {
+        let started_at = Utc.with_ymd_and_hms(2024, 3, 1, 10, 30, 0).unwrap();
+        let store = BackupStore::started_at("/data/dotfilers/backups", started_at);
+
+        assert_eq!(store.run(), "2024-03-01T10-30-00.000Z");
+        assert_eq!(
+            store.path_for(Path::new("/home/user/.config/nvim")),
+            PathBuf::from("/data/dotfilers/backups/2024-03-01T10-30-00.000Z/home/user/.config/nvim")
+        );
+        assert_eq!(
+            store.path_for(Path::new("/home/user/dotfiles/../.zshrc")),
+            PathBuf::from("/data/dotfilers/backups/2024-03-01T10-30-00.000Z/home/user/.zshrc")
+        );
+    }
+}
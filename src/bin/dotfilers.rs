@@ -1,14 +1,36 @@
 #[macro_use]
 extern crate tracing;
 
-use anyhow::{Context, Result};
-use clap::{App, Arg};
-use dotfilers::{Config, Executor};
+use anyhow::{anyhow, Context, Result};
+use clap::{App, Arg, SubCommand};
+use dotfilers::{
+    BackupRun, BackupStore, Config, DestinationStatus, Executor, Failure, ManifestStore, RealFactsProvider, Retention, RunJournal,
+    RunSummary,
+};
+use std::path::PathBuf;
 
 const CONFIG_FILE_ARG: &str = "config";
 const DRY_RUN_ARG: &str = "dry-run";
 const SECTIONS_ARG: &str = "sections";
+const FORCE_ARG: &str = "force";
+const JSON_ARG: &str = "json";
+const TRANSACTIONAL_ARG: &str = "transactional";
+const KEEP_GOING_ARG: &str = "keep-going";
+const RUN_ARG: &str = "run";
+const PATHS_ARG: &str = "paths";
+const KEEP_ARG: &str = "keep";
+const OLDER_THAN_ARG: &str = "older-than";
 const DEFAULT_FILE_NAME: &str = "dotfilers.yaml";
+const VALIDATE_COMMAND: &str = "validate";
+const UNDEPLOY_COMMAND: &str = "undeploy";
+const STATUS_COMMAND: &str = "status";
+const PLAN_COMMAND: &str = "plan";
+const BACKUPS_COMMAND: &str = "backups";
+const LIST_COMMAND: &str = "list";
+const RESTORE_COMMAND: &str = "restore";
+const PRUNE_COMMAND: &str = "prune";
+const UNDO_COMMAND: &str = "undo";
+const HISTORY_COMMAND: &str = "history";
 
 const VERSION: &str = git_version::git_version!(
     args = ["--tags", "--always", "--abbrev=1", "--dirty=-modified"],
@@ -47,13 +69,28 @@ fn main() -> Result<()> {
                 .help("Config file to be executed")
                 .takes_value(true)
                 .default_value(DEFAULT_FILE_NAME)
-                .required(true),
+                .global(true),
         )
         .arg(
             Arg::with_name(DRY_RUN_ARG)
                 .short("d")
                 .long("dry-run")
                 .help("Do not actually perform any operation")
+                .takes_value(false)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name(TRANSACTIONAL_ARG)
+                .short("t")
+                .long("transactional")
+                .help("If anything fails, undo every change made by the run")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name(KEEP_GOING_ARG)
+                .short("k")
+                .long("keep-going")
+                .help("Carry on when a directive fails, and list every failure at the end")
                 .takes_value(false),
         )
         .arg(
@@ -63,37 +100,361 @@ fn main() -> Result<()> {
                 .multiple(true)
                 .required(false),
         )
+        .subcommand(
+            SubCommand::with_name(VALIDATE_COMMAND)
+                .about("Checks the config and every included file without executing anything, and lists all the problems found"),
+        )
+        .subcommand(
+            SubCommand::with_name(UNDEPLOY_COMMAND)
+                .about("Removes everything that was deployed, restoring the files that were there before")
+                .arg(
+                    Arg::with_name(FORCE_ARG)
+                        .short("f")
+                        .long("force")
+                        .help("Also remove files that were modified after being deployed")
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name(SECTIONS_ARG)
+                        .help("Which sections to undeploy (if not specified, all of them will be undeployed)")
+                        .multiple(true)
+                        .required(false),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(STATUS_COMMAND)
+                .about("Compares what would be deployed with what is in the filesystem, without changing anything. Exits with 1 if anything differs")
+                .arg(
+                    Arg::with_name(JSON_ARG)
+                        .long("json")
+                        .help("Print the status as JSON")
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name(SECTIONS_ARG)
+                        .help("Which sections to check (if not specified, all of them will be checked)")
+                        .multiple(true)
+                        .required(false),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(PLAN_COMMAND)
+                .about("Prints as JSON the operations that would be performed, without performing any of them")
+                .arg(
+                    Arg::with_name(SECTIONS_ARG)
+                        .help("Which sections to plan (if not specified, all of them will be planned)")
+                        .multiple(true)
+                        .required(false),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(BACKUPS_COMMAND)
+                .about("Manages the files moved into the backup store by the backup conflict strategy")
+                .subcommand(SubCommand::with_name(LIST_COMMAND).about("Lists every run that made backups, and what they backed up"))
+                .subcommand(
+                    SubCommand::with_name(RESTORE_COMMAND)
+                        .about("Moves the backups of a run back to where they were found")
+                        .arg(
+                            Arg::with_name(FORCE_ARG)
+                                .short("f")
+                                .long("force")
+                                .help("Replace whatever is in the way of the backups")
+                                .takes_value(false),
+                        )
+                        .arg(Arg::with_name(RUN_ARG).help("Run whose backups are restored").required(true))
+                        .arg(
+                            Arg::with_name(PATHS_ARG)
+                                .help("Which original paths to restore (if not specified, all of them will be restored)")
+                                .multiple(true)
+                                .required(false),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name(PRUNE_COMMAND)
+                        .about("Removes old backup runs")
+                        .arg(
+                            Arg::with_name(KEEP_ARG)
+                                .long("keep")
+                                .help("How many of the most recent runs to keep")
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::with_name(OLDER_THAN_ARG)
+                                .long("older-than")
+                                .help("Remove runs older than this many days")
+                                .takes_value(true),
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(UNDO_COMMAND)
+                .about("Undoes every change made by a run, putting the machine back as it was before it")
+                .arg(
+                    Arg::with_name(RUN_ARG)
+                        .help("Run to undo (if not specified, the last run that has not been undone yet)")
+                        .required(false),
+                ),
+        )
+        .subcommand(SubCommand::with_name(HISTORY_COMMAND).about("Lists the past runs that changed something"))
         .get_matches();
 
     let config_file = app.value_of(CONFIG_FILE_ARG).context("config argument should be present")?;
     let dry_run = app.is_present(DRY_RUN_ARG);
 
-    let config = Config::from_file(config_file).context("Error loading config")?;
+    let config = match Config::from_file(config_file) {
+        Ok(config) => config,
+        Err(e) => {
+            let e = anyhow::Error::from(e).context("Error loading config");
+            print_config_error(&e);
+            return Err(e);
+        }
+    };
     setup_logging(&config.program.log_level);
-    let executor = if dry_run {
+    let mut executor = if dry_run {
         Executor::dry_run(&config.program.shell, config.program.conflict_strategy)
     } else {
         Executor::new(&config.program.shell, config.program.conflict_strategy)
     };
+    executor.strict = config.program.strict;
+    executor.prompt_fallback = config.program.prompt_fallback;
+    executor.keep_going = app.is_present(KEEP_GOING_ARG);
 
     let root_dir = std::env::current_dir().context("Error getting current dir")?;
 
-    if let Some(sections) = app.values_of(SECTIONS_ARG) {
-        for section_name in sections {
-            match config.state_config.states.get(section_name) {
-                Some(directives) => {
-                    executor.execute(&root_dir, section_name, directives)?;
-                }
-                None => {
-                    error!("Could not find a section named {}", section_name);
+    if app.subcommand_matches(VALIDATE_COMMAND).is_some() {
+        let problems = executor.validate(&root_dir, config_file, &config.state_config);
+        if problems.is_empty() {
+            println!("{} is valid", config_file);
+            return Ok(());
+        }
+        for problem in &problems {
+            println!("{}", problem);
+        }
+        eprintln!("Found {} problem(s) in {}", problems.len(), config_file);
+        std::process::exit(1);
+    }
+
+    let manifest_path = ManifestStore::default_path(&executor.facts()?.xdg.state_home);
+    debug!("Using manifest {}", manifest_path.display());
+    executor.manifest = Some(ManifestStore::open(&executor.filesystem, &manifest_path).context("Error loading manifest")?);
+    let backups_path = BackupStore::default_path(&executor.facts()?.xdg.data_home);
+    debug!("Using backup store {}", backups_path.display());
+    // Backups and the journal of a run share its id
+    let started_at = chrono::Utc::now();
+    executor.backup_store = Some(BackupStore::started_at(backups_path, started_at));
+    let runs_path = RunJournal::default_path(&executor.facts()?.xdg.state_home);
+    debug!("Using run journals in {}", runs_path.display());
+    executor.run_journal = Some(RunJournal::started_at(runs_path, started_at).with_kept_runs(config.program.kept_runs));
+
+    if let Some(backups) = app.subcommand_matches(BACKUPS_COMMAND) {
+        return run_backups_command(&executor, backups);
+    }
+
+    if app.subcommand_matches(HISTORY_COMMAND).is_some() {
+        let journal = executor.run_journal.as_ref().context("run journal should be set")?;
+        let runs = journal.runs(&executor.filesystem)?;
+        if runs.is_empty() {
+            println!("No runs found in {}", journal.root().display());
+        } else {
+            print_history(&runs);
+        }
+        return Ok(());
+    }
+
+    if let Some(undo) = app.subcommand_matches(UNDO_COMMAND) {
+        let summary = executor.undo_run(undo.value_of(RUN_ARG))?;
+        println!("{}", summary);
+        return Ok(());
+    }
+
+    if let Some(status) = app.subcommand_matches(STATUS_COMMAND) {
+        let requested = status.values_of(SECTIONS_ARG).map(|s| s.collect::<Vec<&str>>());
+        let statuses = executor.status(&root_dir, &config.state_config, requested.as_deref())?;
+        if status.is_present(JSON_ARG) {
+            println!("{}", serde_json::to_string_pretty(&statuses).context("Error serializing status")?);
+        } else {
+            print_status_table(&statuses);
+        }
+        if statuses.iter().any(|s| s.state.is_drift()) {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if let Some(plan) = app.subcommand_matches(PLAN_COMMAND) {
+        let requested = plan.values_of(SECTIONS_ARG).map(|s| s.collect::<Vec<&str>>());
+        let plan = executor.plan(&root_dir, &config.state_config, requested.as_deref())?;
+        println!("{}", serde_json::to_string_pretty(&plan).context("Error serializing plan")?);
+        return Ok(());
+    }
+
+    if let Some(undeploy) = app.subcommand_matches(UNDEPLOY_COMMAND) {
+        let sections = undeploy.values_of(SECTIONS_ARG).map(|s| s.collect()).unwrap_or_else(Vec::new);
+        return executor.undeploy(&sections, undeploy.is_present(FORCE_ARG));
+    }
+
+    let requested = app.values_of(SECTIONS_ARG).map(|sections| {
+        sections
+            .filter(|name| {
+                let exists = config.state_config.states.contains_key(*name);
+                if !exists {
+                    error!("Could not find a section named {}", name);
                 }
+                exists
+            })
+            .collect::<Vec<&str>>()
+    });
+    let order = config
+        .state_config
+        .execution_order(requested.as_deref())
+        .context("Error resolving section dependencies")?;
+
+    let execute = |executor: &Executor<RealFactsProvider>| {
+        order
+            .iter()
+            .try_for_each(|name| executor.execute_section(&root_dir, name, &config.state_config.states[*name]))
+    };
+    let result = if app.is_present(TRANSACTIONAL_ARG) {
+        executor.transaction(execute)
+    } else {
+        execute(&executor)
+    };
+    print_failures(&executor.failures());
+    if let Err(e) = result {
+        print_config_error(&e);
+        return Err(e);
+    }
+    if !executor.failures().is_empty() {
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
+
+fn run_backups_command(executor: &Executor<RealFactsProvider>, backups: &clap::ArgMatches) -> Result<()> {
+    let store = executor.backup_store.as_ref().context("backup store should be set")?;
+    match backups.subcommand() {
+        (LIST_COMMAND, _) => {
+            let runs = store.runs(&executor.filesystem)?;
+            if runs.is_empty() {
+                println!("No backups found in {}", store.root().display());
             }
+            print_backup_runs(&runs);
+            Ok(())
         }
-    } else {
-        for (name, directives) in config.state_config.states {
-            executor.execute(&root_dir, &name, &directives)?;
+        (RESTORE_COMMAND, Some(restore)) => {
+            let run = restore.value_of(RUN_ARG).context("run argument should be present")?;
+            let paths: Vec<PathBuf> = restore
+                .values_of(PATHS_ARG)
+                .map(|p| p.map(PathBuf::from).collect())
+                .unwrap_or_default();
+            executor.restore_backups(run, &paths, restore.is_present(FORCE_ARG))
+        }
+        (PRUNE_COMMAND, Some(prune)) => {
+            let retention = Retention {
+                keep: prune
+                    .value_of(KEEP_ARG)
+                    .map(|keep| keep.parse().context(format!("Invalid number of runs to keep: {}", keep)))
+                    .transpose()?,
+                max_age: prune
+                    .value_of(OLDER_THAN_ARG)
+                    .map(|days| {
+                        days.parse()
+                            .map(chrono::Duration::days)
+                            .context(format!("Invalid number of days: {}", days))
+                    })
+                    .transpose()?,
+            };
+            if retention == Retention::default() {
+                return Err(anyhow!("Pass --keep, --older-than or both to choose which runs to remove"));
+            }
+            let pruned = executor.prune_backups(retention)?;
+            println!("Removed {} backup run(s)", pruned.len());
+            Ok(())
         }
+        _ => Err(anyhow!("Pass one of {}, {} or {}", LIST_COMMAND, RESTORE_COMMAND, PRUNE_COMMAND)),
     }
+}
 
-    Ok(())
+fn print_backup_runs(runs: &[BackupRun]) {
+    for run in runs {
+        println!("{}  {} file(s)", run.id, run.entries.len());
+        for entry in &run.entries {
+            println!("  [section={}] {}", entry.section, entry.original.display());
+        }
+    }
+}
+
+/// Prints `rows` below `header`, with every column as wide as its widest cell.
+fn print_table<const N: usize>(header: [&str; N], rows: &[[String; N]]) {
+    let header = header.map(String::from);
+    let mut widths = [0; N];
+    for row in std::iter::once(&header).chain(rows.iter()) {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    for row in std::iter::once(&header).chain(rows.iter()) {
+        let cells: Vec<String> = row.iter().zip(widths).map(|(cell, width)| format!("{:width$}", cell)).collect();
+        println!("{}", cells.join("  ").trim_end());
+    }
+}
+
+fn print_history(runs: &[RunSummary]) {
+    let rows: Vec<[String; 4]> = runs
+        .iter()
+        .map(|run| {
+            let id = match run.undone_at {
+                Some(_) => format!("{} (undone)", run.id),
+                None => run.id.clone(),
+            };
+            [id, run.finished_at.clone(), run.changes.to_string(), run.sections.join(", ")]
+        })
+        .collect();
+    print_table(["RUN", "FINISHED", "CHANGES", "SECTIONS"], &rows);
+}
+
+fn print_failures(failures: &[Failure]) {
+    if failures.is_empty() {
+        return;
+    }
+    eprintln!("{} directive(s) failed:", failures.len());
+    for failure in failures {
+        eprintln!("  {}", failure);
+    }
+}
+
+fn print_status_table(statuses: &[DestinationStatus]) {
+    let rows: Vec<[String; 4]> = statuses
+        .iter()
+        .map(|s| {
+            [
+                s.state.to_string(),
+                s.section.clone(),
+                s.destination.display().to_string(),
+                s.source.display().to_string(),
+            ]
+        })
+        .collect();
+    print_table(["STATE", "SECTION", "DESTINATION", "SOURCE"], &rows);
+    let drifted = statuses.iter().filter(|s| s.state.is_drift()).count();
+    println!("\n{} of {} destination(s) differ from the config", drifted, statuses.len());
+}
+
+/// If the error was caused by an invalid config, prints the offending lines the way rustc does and exits.
+fn print_config_error(err: &anyhow::Error) {
+    let config_error = err
+        .chain()
+        .find_map(|e| e.downcast_ref::<dotfilers::Error>())
+        .and_then(|e| e.config_error());
+    if let Some(config_error) = config_error {
+        if config_error.location.is_none() {
+            return;
+        }
+        eprint!("{}", config_error.render());
+        for context in err.chain().take_while(|e| e.downcast_ref::<dotfilers::Error>().is_none()) {
+            eprintln!("  = note: {}", context);
+        }
+        std::process::exit(1);
+    }
 }
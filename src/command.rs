@@ -0,0 +1,118 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::Result;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// A command to be run for a `run` directive.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CommandInvocation {
+    pub program: String,
+    pub args: Vec<String>,
+    pub current_dir: PathBuf,
+}
+
+/// How a command finished.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct CommandOutput {
+    /// None if the command was terminated by a signal
+    pub exit_code: Option<i32>,
+    /// Output captured by the runner. Runners that let the command write into the terminal leave them empty.
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl CommandOutput {
+    pub fn success() -> Self {
+        Self::exit_code(0)
+    }
+
+    pub fn exit_code(code: i32) -> Self {
+        Self {
+            exit_code: Some(code),
+            ..Self::default()
+        }
+    }
+
+    pub fn with_stdout(mut self, stdout: &str) -> Self {
+        self.stdout = stdout.to_string();
+        self
+    }
+
+    pub fn with_stderr(mut self, stderr: &str) -> Self {
+        self.stderr = stderr.to_string();
+        self
+    }
+}
+
+/// Runs the commands of `run` directives, so they can be intercepted instead of spawned.
+pub trait CommandRunner {
+    /// Runs the command until it finishes. Errors are only returned if it could not be run at all.
+    fn run(&self, invocation: &CommandInvocation) -> Result<CommandOutput>;
+}
+
+/// Spawns commands as child processes, which write directly into the terminal.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RealCommandRunner;
+
+impl CommandRunner for RealCommandRunner {
+    fn run(&self, invocation: &CommandInvocation) -> Result<CommandOutput> {
+        let mut command = Command::new(&invocation.program);
+        command.args(&invocation.args).current_dir(&invocation.current_dir);
+        let exit_status = command.spawn()?.wait()?;
+        Ok(CommandOutput {
+            exit_code: exit_status.code(),
+            ..CommandOutput::default()
+        })
+    }
+}
+
+/// Records every command instead of running it, and answers with scripted outputs.
+#[derive(Debug, Default)]
+pub struct RecordingCommandRunner {
+    invocations: RefCell<Vec<CommandInvocation>>,
+    outputs: RefCell<VecDeque<CommandOutput>>,
+}
+
+impl RecordingCommandRunner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queues the output of the next command. Once the queue is empty, commands succeed without any output.
+    pub fn push_output(&self, output: CommandOutput) {
+        self.outputs.borrow_mut().push_back(output);
+    }
+
+    /// Every command run so far, in order.
+    pub fn invocations(&self) -> Vec<CommandInvocation> {
+        self.invocations.borrow().clone()
+    }
+}
+
+impl CommandRunner for RecordingCommandRunner {
+    fn run(&self, invocation: &CommandInvocation) -> Result<CommandOutput> {
+        self.invocations.borrow_mut().push(invocation.clone());
+        Ok(self.outputs.borrow_mut().pop_front().unwrap_or_else(CommandOutput::success))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn recording_runner_returns_scripted_outputs() {
+        let runner = RecordingCommandRunner::new();
+        runner.push_output(CommandOutput::exit_code(2).with_stderr("boom"));
+        let invocation = CommandInvocation {
+            program: "/bin/sh".to_string(),
+            args: vec!["-c".to_string(), "exit 2".to_string()],
+            current_dir: PathBuf::from("/dotfiles"),
+        };
+
+        assert_eq!(runner.run(&invocation).unwrap(), CommandOutput::exit_code(2).with_stderr("boom"));
+        assert_eq!(runner.run(&invocation).unwrap(), CommandOutput::success());
+        assert_eq!(runner.invocations(), vec![invocation.clone(), invocation]);
+    }
+}
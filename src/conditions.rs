@@ -0,0 +1,218 @@
+use crate::config::Condition;
+use crate::facts::Facts;
+use crate::filesystem::Filesystem;
+use anyhow::{anyhow, Result};
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+
+impl Condition {
+    /// Returns the reason why the condition does not hold for the current machine, or None if it does.
+    ///
+    /// Relative paths in `file_exists` predicates are resolved against `root_dir`, and looked up in `fs`. Conditions that
+    /// cannot be evaluated, such as expressions comparing values of different types, are errors.
+    pub fn mismatch(&self, facts: &Facts, root_dir: &Path, fs: &dyn Filesystem) -> Result<Option<String>> {
+        let holds = match self {
+            Condition::Always => true,
+            Condition::IfOs(os) => &facts.os.os == os,
+            Condition::IfDistro(distro) => facts.os.distro.as_deref() == Some(distro.as_str()),
+            Condition::IfArch(arch) => &facts.os.arch == arch,
+            Condition::IfHostname(pattern) => glob::Pattern::new(pattern)
+                .map_err(|e| anyhow!("Invalid hostname pattern {}: {}", pattern, e))?
+                .matches(&facts.hostname),
+            Condition::IfUser(user) => &facts.username == user,
+            Condition::IfEnvSet(name) => facts.env.contains_key(name),
+            Condition::IfEnvEquals { name, value } => facts.env.get(name) == Some(value),
+            Condition::IfFileExists(path) => fs.exists(&resolve_path(root_dir, path)),
+            Condition::IfCommand(command) => facts.find_in_path(fs, command).is_some(),
+            Condition::IfContainer => facts.os.is_container,
+            Condition::IfWsl => facts.os.is_wsl,
+            Condition::Expression(expression) => crate::expression::evaluate(expression, facts)?,
+            Condition::All(conditions) => {
+                for c in conditions {
+                    if let Some(reason) = c.mismatch(facts, root_dir, fs)? {
+                        return Ok(Some(reason));
+                    }
+                }
+                return Ok(None);
+            }
+            Condition::Any(conditions) => {
+                let mut reasons = Vec::new();
+                for c in conditions {
+                    match c.mismatch(facts, root_dir, fs)? {
+                        None => return Ok(None),
+                        Some(reason) => reasons.push(reason),
+                    }
+                }
+                return Ok(Some(format!("none of the alternatives hold ({})", reasons.join("; "))));
+            }
+            Condition::Not(inner) => inner.mismatch(facts, root_dir, fs)?.is_some(),
+        };
+
+        if holds {
+            Ok(None)
+        } else {
+            Ok(Some(self.describe_mismatch(facts)))
+        }
+    }
+
+    fn describe_mismatch(&self, facts: &Facts) -> String {
+        match self {
+            Condition::IfOs(_) => format!("expected {}, but os is {}", self, facts.os.os),
+            Condition::IfDistro(_) => format!(
+                "expected {}, but distro is {}",
+                self,
+                facts.os.distro.as_deref().unwrap_or("unknown")
+            ),
+            Condition::IfArch(_) => format!("expected {}, but arch is {}", self, facts.os.arch),
+            Condition::IfHostname(_) => format!("expected {}, but hostname is {}", self, facts.hostname),
+            Condition::IfUser(_) => format!("expected {}, but user is {}", self, facts.username),
+            Condition::IfEnvEquals { name, .. } => match facts.env.get(name) {
+                Some(current) => format!("expected {}, but it is {:?}", self, current),
+                None => format!("expected {}, but it is not set", self),
+            },
+            _ => format!("{} does not hold", self),
+        }
+    }
+}
+
+impl Display for Condition {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let join =
+            |conditions: &[Condition], separator: &str| conditions.iter().map(|c| c.to_string()).collect::<Vec<String>>().join(separator);
+        match self {
+            Condition::Always => write!(f, "always"),
+            Condition::IfOs(os) => write!(f, "os == {}", os),
+            Condition::IfDistro(distro) => write!(f, "distro == {}", distro),
+            Condition::IfArch(arch) => write!(f, "arch == {}", arch),
+            Condition::IfHostname(pattern) => write!(f, "hostname matches {}", pattern),
+            Condition::IfUser(user) => write!(f, "user == {}", user),
+            Condition::IfEnvSet(name) => write!(f, "env {} is set", name),
+            Condition::IfEnvEquals { name, value } => write!(f, "env {} == {:?}", name, value),
+            Condition::IfFileExists(path) => write!(f, "file {} exists", path),
+            Condition::IfCommand(command) => write!(f, "command {} is in PATH", command),
+            Condition::IfContainer => write!(f, "running in a container"),
+            Condition::IfWsl => write!(f, "running in WSL"),
+            Condition::Expression(expression) => write!(f, "{}", expression),
+            Condition::All(conditions) => write!(f, "({})", join(conditions, " and ")),
+            Condition::Any(conditions) => write!(f, "({})", join(conditions, " or ")),
+            Condition::Not(inner) => write!(f, "not {}", inner),
+        }
+    }
+}
+
+fn resolve_path(root_dir: &Path, path: &str) -> PathBuf {
+    if path.contains('~') {
+        PathBuf::from(shellexpand::tilde(path).to_string())
+    } else {
+        root_dir.join(path)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::Os;
+    use crate::detection::OsInfo;
+    use crate::facts::XdgDirs;
+    use crate::filesystem::RealFilesystem;
+    use std::collections::BTreeMap;
+
+    fn facts() -> Facts {
+        let home_dir = PathBuf::from("/home/me");
+        let mut env = BTreeMap::new();
+        env.insert("WORK".to_string(), "1".to_string());
+        Facts {
+            os: OsInfo {
+                os: Os::Linux,
+                arch: "x86_64".to_string(),
+                distro: Some("ubuntu".to_string()),
+                distro_version: Some("22.04".to_string()),
+                is_wsl: false,
+                is_container: false,
+            },
+            hostname: "work-laptop".to_string(),
+            username: "me".to_string(),
+            xdg: XdgDirs::from_env(&home_dir, &env),
+            home_dir,
+            shell: None,
+            kernel_version: None,
+            cpu_count: 4,
+            env,
+        }
+    }
+
+    #[test]
+    fn evaluates_tree() {
+        let facts = facts();
+        let root = Path::new("/");
+        let condition = Condition::All(vec![
+            Condition::IfOs(Os::Linux),
+            Condition::IfHostname("work-*".to_string()),
+            Condition::Not(Box::new(Condition::IfContainer)),
+            Condition::Any(vec![
+                Condition::IfUser("someone".to_string()),
+                Condition::IfEnvEquals {
+                    name: "WORK".to_string(),
+                    value: "1".to_string(),
+                },
+            ]),
+        ]);
+        assert_eq!(condition.mismatch(&facts, root, &RealFilesystem).unwrap(), None);
+    }
+
+    #[test]
+    fn explains_mismatch() {
+        let facts = facts();
+        let root = Path::new("/");
+
+        let condition = Condition::All(vec![Condition::IfOs(Os::Linux), Condition::IfHostname("home-*".to_string())]);
+        assert_eq!(
+            condition.mismatch(&facts, root, &RealFilesystem).unwrap(),
+            Some("expected hostname matches home-*, but hostname is work-laptop".to_string())
+        );
+
+        let condition = Condition::Not(Box::new(Condition::IfEnvSet("WORK".to_string())));
+        assert_eq!(
+            condition.mismatch(&facts, root, &RealFilesystem).unwrap(),
+            Some("not env WORK is set does not hold".to_string())
+        );
+
+        let condition = Condition::Any(vec![Condition::IfUser("root".to_string()), Condition::IfWsl]);
+        assert_eq!(
+            condition.mismatch(&facts, root, &RealFilesystem).unwrap(),
+            Some("none of the alternatives hold (expected user == root, but user is me; running in WSL does not hold)".to_string())
+        );
+    }
+
+    #[test]
+    fn evaluates_expressions() {
+        let facts = facts();
+        let root = Path::new("/");
+
+        let condition = Condition::Expression("os == 'linux' && arch == 'x86_64' && env.WORK == '1' && cpu_count >= 2".to_string());
+        assert_eq!(condition.mismatch(&facts, root, &RealFilesystem).unwrap(), None);
+
+        let condition = Condition::Expression("dotfilers_hostname == 'home' || is_container".to_string());
+        assert_eq!(
+            condition.mismatch(&facts, root, &RealFilesystem).unwrap(),
+            Some("dotfilers_hostname == 'home' || is_container does not hold".to_string())
+        );
+
+        let condition = Condition::Expression("env.UNSET == '1'".to_string());
+        assert!(condition.mismatch(&facts, root, &RealFilesystem).unwrap().is_some());
+
+        let condition = Condition::Expression("os > 2".to_string());
+        let error = condition
+            .mismatch(&facts, root, &RealFilesystem)
+            .expect_err("Should not be able to evaluate");
+        assert!(error.to_string().contains("os > 2"), "{}", error);
+    }
+
+    #[test]
+    fn every_fact_is_known_to_expressions() {
+        let names: Vec<String> = facts().template_variables().into_keys().collect();
+        let mut known: Vec<String> = crate::facts::FACT_NAMES.iter().map(|name| format!("dotfilers_{}", name)).collect();
+        known.sort();
+        assert_eq!(names, known);
+    }
+}
@@ -1,5 +1,9 @@
+use crate::detection::normalize_arch;
+use crate::history::DEFAULT_KEPT_RUNS;
+use crate::location::YamlPathSegment;
 use crate::{Error, Result};
-use std::collections::HashMap;
+use indexmap::IndexMap;
+use std::collections::{HashMap, HashSet};
 use std::path::Path;
 use std::str::FromStr;
 
@@ -7,11 +11,40 @@ const SPECIAL_CONFIG_SECTION_NAME: &str = ".dotfilers";
 const DEFAULT_LOG_LEVEL: &str = "info";
 const DEFAULT_SHELL: &str = "/bin/bash -c";
 
-#[derive(Debug, PartialEq, Eq)]
+const PROGRAM_KEYS: &[&str] = &["shell", "log_level", "conflict_strategy", "prompt_fallback", "strict", "kept_runs"];
+const SECTION_KEYS: &[&str] = &[
+    "if",
+    "when",
+    "description",
+    "enabled",
+    "depends_on",
+    "on_error",
+    "conflict_strategy",
+    "steps",
+];
+const CONDITION_KEYS: &[&str] = &["if", "if_os", "if_distro", "if_arch", "when"];
+const STEP_KEYS: &[&str] = &["on_error", "conflict_strategy"];
+const DIRECTIVE_KEYS: &[(&str, &[&str])] = &[
+    ("link", &["link_from", "link_to", "link_directory_behaviour"]),
+    ("copy", &["copy_from", "copy_to"]),
+    ("template", &["template", "template_to", "template_vars"]),
+    ("run", &["run"]),
+    ("include", &["include"]),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ConflictStrategy {
     Abort,
     Overwrite,
     RenameOld,
+    /// Ask what to do on every conflict
+    Prompt,
+    /// Leave the existing destination as it is
+    Skip,
+    /// Move the existing destination into the repo, replacing the source, and then deploy it
+    Adopt,
+    /// Move the existing destination into the backup store
+    Backup,
 }
 
 impl FromStr for ConflictStrategy {
@@ -22,37 +55,132 @@ impl FromStr for ConflictStrategy {
             "abort" => Ok(Self::Abort),
             "overwrite" => Ok(Self::Overwrite),
             "rename-old" => Ok(Self::RenameOld),
+            "prompt" => Ok(Self::Prompt),
+            "skip" => Ok(Self::Skip),
+            "adopt" => Ok(Self::Adopt),
+            "backup" => Ok(Self::Backup),
             _ => Err(Error::Config(format!("Unknown ConflictStrategy: {s}"))),
         }
     }
 }
 
+impl std::fmt::Display for ConflictStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            ConflictStrategy::Abort => "abort",
+            ConflictStrategy::Overwrite => "overwrite",
+            ConflictStrategy::RenameOld => "rename-old",
+            ConflictStrategy::Prompt => "prompt",
+            ConflictStrategy::Skip => "skip",
+            ConflictStrategy::Adopt => "adopt",
+            ConflictStrategy::Backup => "backup",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// What to do when a directive fails.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum OnError {
+    /// Stop the whole run
+    Abort,
+    /// Report the failure at the end of the run, and carry on with the next directive
+    Continue,
+}
+
+impl FromStr for OnError {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "abort" => Ok(Self::Abort),
+            "continue" => Ok(Self::Continue),
+            _ => Err(Error::Config(format!("unknown on_error value: {s} (expected abort or continue)"))),
+        }
+    }
+}
+
+impl std::fmt::Display for OnError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            OnError::Abort => "abort",
+            OnError::Continue => "continue",
+        };
+        write!(f, "{}", name)
+    }
+}
+
 #[derive(Debug)]
 pub struct ProgramConfig {
     pub shell: String,
     pub log_level: String,
     pub conflict_strategy: ConflictStrategy,
+    /// Strategy used instead of `prompt` when there is nobody to ask, such as when stdin is not a terminal
+    pub prompt_fallback: ConflictStrategy,
+    /// When enabled, unknown keys and entries that mix several directives are errors rather than warnings.
+    pub strict: bool,
+    /// How many runs are kept in the run journals, counting the current one.
+    pub kept_runs: usize,
 }
 
 impl ProgramConfig {
     pub fn from_yaml(yaml: &str) -> Result<Self> {
+        let parsed: Option<YamlProgramConfig> = serde_yaml::from_str(yaml).map_err(|e| Error::from_yaml(e, yaml, "Error parsing yaml"))?;
+        match parsed.and_then(|p| p.config) {
+            Some(config) => Self::from_yaml_value(config).map_err(|e| e.locate(yaml)),
+            None => Ok(Self::default()),
+        }
+    }
+
+    fn from_yaml_value(config: serde_yaml::Value) -> Result<Self> {
+        let path = [SPECIAL_CONFIG_SECTION_NAME.into()];
         let mut instance = Self::default();
-        let parsed: Option<YamlProgramConfig> =
-            serde_yaml::from_str(yaml).map_err(|e| Error::Config(format!("Error reading program config: {}", e)))?;
-        if let Some(parsed) = parsed {
-            if let Some(config) = parsed.config {
-                if let Some(log_level) = config.log_level {
-                    instance.log_level = log_level;
-                }
-                if let Some(strat) = config.conflict_strategy {
-                    instance.conflict_strategy =
-                        ConflictStrategy::from_str(&strat).map_err(|e| Error::Config(format!("Error parsing ConflictStrategy: {}", e)))?;
-                }
-                if let Some(shell) = config.shell {
-                    instance.shell = shell;
-                }
+        if let Some(strict) = config.get("strict") {
+            instance.strict = strict.as_bool().ok_or_else(|| {
+                Error::at(
+                    vec![SPECIAL_CONFIG_SECTION_NAME.into(), "strict".into()],
+                    format!("'strict' must be either true or false, found {:?}", strict),
+                )
+            })?;
+        }
+        check_unknown_keys(&config, PROGRAM_KEYS, instance.strict)
+            .map_err(|e| e.nested(&path, &format!("Error in {} section", SPECIAL_CONFIG_SECTION_NAME)))?;
+        let config: YamlInnerProgramConfig =
+            serde_yaml::from_value(config).map_err(|e| Error::at(path.to_vec(), format!("Error reading program config: {}", e)))?;
+        if let Some(log_level) = config.log_level {
+            instance.log_level = log_level;
+        }
+        if let Some(strat) = config.conflict_strategy {
+            instance.conflict_strategy = ConflictStrategy::from_str(&strat).map_err(|e| {
+                e.nested(
+                    &[SPECIAL_CONFIG_SECTION_NAME.into(), "conflict_strategy".into()],
+                    "Error parsing ConflictStrategy",
+                )
+            })?;
+        }
+        if let Some(strat) = config.prompt_fallback {
+            let fallback_path = [SPECIAL_CONFIG_SECTION_NAME.into(), "prompt_fallback".into()];
+            instance.prompt_fallback =
+                ConflictStrategy::from_str(&strat).map_err(|e| e.nested(&fallback_path, "Error parsing ConflictStrategy"))?;
+            if instance.prompt_fallback == ConflictStrategy::Prompt {
+                return Err(Error::at(
+                    fallback_path.to_vec(),
+                    "prompt_fallback must be a strategy that does not ask anything".to_string(),
+                ));
             }
         }
+        if let Some(shell) = config.shell {
+            instance.shell = shell;
+        }
+        if let Some(kept_runs) = config.kept_runs {
+            if kept_runs == 0 {
+                return Err(Error::at(
+                    vec![SPECIAL_CONFIG_SECTION_NAME.into(), "kept_runs".into()],
+                    "kept_runs must be at least 1, as the current run is always kept".to_string(),
+                ));
+            }
+            instance.kept_runs = kept_runs;
+        }
         Ok(instance)
     }
 }
@@ -63,6 +191,9 @@ impl Default for ProgramConfig {
             shell: DEFAULT_SHELL.to_string(),
             log_level: DEFAULT_LOG_LEVEL.to_string(),
             conflict_strategy: ConflictStrategy::RenameOld,
+            prompt_fallback: ConflictStrategy::Abort,
+            strict: true,
+            kept_runs: DEFAULT_KEPT_RUNS,
         }
     }
 }
@@ -72,18 +203,70 @@ struct YamlInnerProgramConfig {
     pub shell: Option<String>,
     pub log_level: Option<String>,
     pub conflict_strategy: Option<String>,
+    pub prompt_fallback: Option<String>,
+    pub kept_runs: Option<usize>,
 }
 
 #[derive(Debug, serde::Deserialize)]
 struct YamlProgramConfig {
     #[serde(rename = ".dotfilers")]
-    config: Option<YamlInnerProgramConfig>,
+    config: Option<serde_yaml::Value>,
 }
 
-#[derive(Debug, Eq, PartialEq)]
+/// Checks that all the keys of `value` are known. Unknown keys are errors in strict mode, and warnings otherwise.
+fn check_unknown_keys(value: &serde_yaml::Value, known: &[&str], strict: bool) -> Result<()> {
+    let mapping = match value.as_mapping() {
+        Some(mapping) => mapping,
+        None => return Ok(()),
+    };
+    for (key, _) in mapping {
+        let key = match key.as_str() {
+            Some(key) => key,
+            None => return Err(Error::Config(format!("Keys must be strings, found {:?}", key))),
+        };
+        if known.contains(&key) {
+            continue;
+        }
+        let message = match did_you_mean(key, known) {
+            Some(suggestion) => format!("unknown key '{}' (did you mean '{}'?)", key, suggestion),
+            None => format!("unknown key '{}'", key),
+        };
+        if strict {
+            return Err(Error::at(vec![key.into()], message));
+        }
+        warn!("Ignoring {}", message);
+    }
+    Ok(())
+}
+
+pub(crate) fn did_you_mean<'a>(key: &str, candidates: &[&'a str]) -> Option<&'a str> {
+    // Abbreviations such as 'vars' for 'template_vars' are far away in edit distance, so check them first
+    let abbreviation = candidates
+        .iter()
+        .find(|c| c.ends_with(&format!("_{}", key)) || c.starts_with(&format!("{}_", key)));
+    if let Some(candidate) = abbreviation {
+        return Some(candidate);
+    }
+
+    candidates
+        .iter()
+        .map(|c| (strsim::damerau_levenshtein(key, c), *c))
+        .filter(|(distance, c)| *distance <= 2.max(c.len() / 3))
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, c)| c)
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum Os {
     Darwin,
     Linux,
+    Windows,
+    FreeBsd,
+    OpenBsd,
+    NetBsd,
+    DragonFly,
+    Solaris,
+    Android,
 }
 
 impl FromStr for Os {
@@ -91,20 +274,34 @@ impl FromStr for Os {
 
     fn from_str(s: &str) -> Result<Self> {
         match s.to_lowercase().as_str() {
-            "darwin" => Ok(Self::Darwin),
+            "darwin" | "macos" => Ok(Self::Darwin),
             "linux" => Ok(Self::Linux),
+            "windows" => Ok(Self::Windows),
+            "freebsd" => Ok(Self::FreeBsd),
+            "openbsd" => Ok(Self::OpenBsd),
+            "netbsd" => Ok(Self::NetBsd),
+            "dragonfly" => Ok(Self::DragonFly),
+            "solaris" | "illumos" => Ok(Self::Solaris),
+            "android" => Ok(Self::Android),
             _ => Err(Error::Config(format!("Unknown OS: {s}"))),
         }
     }
 }
 
-impl ToString for Os {
-    fn to_string(&self) -> String {
-        match self {
+impl std::fmt::Display for Os {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
             Os::Darwin => "darwin",
             Os::Linux => "linux",
-        }
-        .to_string()
+            Os::Windows => "windows",
+            Os::FreeBsd => "freebsd",
+            Os::OpenBsd => "openbsd",
+            Os::NetBsd => "netbsd",
+            Os::DragonFly => "dragonfly",
+            Os::Solaris => "solaris",
+            Os::Android => "android",
+        };
+        write!(f, "{}", name)
     }
 }
 
@@ -112,27 +309,132 @@ impl ToString for Os {
 pub enum Condition {
     Always,
     IfOs(Os),
+    IfDistro(String),
+    IfArch(String),
+    IfHostname(String),
+    IfUser(String),
+    IfEnvSet(String),
+    IfEnvEquals { name: String, value: String },
+    IfFileExists(String),
+    IfCommand(String),
+    IfContainer,
+    IfWsl,
+    Expression(String),
+    All(Vec<Condition>),
+    Any(Vec<Condition>),
+    Not(Box<Condition>),
+}
+
+impl Condition {
+    fn from_yaml_value(value: &serde_yaml::Value) -> Result<Self> {
+        match value {
+            serde_yaml::Value::Mapping(mapping) => {
+                let mut conditions = Vec::new();
+                for (key, value) in mapping {
+                    let key = key
+                        .as_str()
+                        .ok_or_else(|| Error::Config(format!("Condition keys must be strings, found {:?}", key)))?;
+                    conditions.push(Self::predicate_from_yaml(key, value)?);
+                }
+                match conditions.len() {
+                    0 => Err(Error::Config("Found an empty condition".to_string())),
+                    1 => Ok(conditions.remove(0)),
+                    _ => Ok(Self::All(conditions)),
+                }
+            }
+            serde_yaml::Value::Sequence(_) => Ok(Self::All(Self::list_from_yaml("all", value)?)),
+            other => Err(Error::Config(format!("A condition must be a mapping or a list, found {:?}", other))),
+        }
+    }
+
+    fn list_from_yaml(key: &str, value: &serde_yaml::Value) -> Result<Vec<Self>> {
+        match value.as_sequence() {
+            Some(items) if !items.is_empty() => items.iter().map(Self::from_yaml_value).collect(),
+            _ => Err(Error::Config(format!("'{}' must contain a non-empty list of conditions", key))),
+        }
+    }
+
+    fn predicate_from_yaml(key: &str, value: &serde_yaml::Value) -> Result<Self> {
+        let as_string = || -> Result<String> {
+            scalar_to_string(value).ok_or_else(|| Error::Config(format!("'{}' must be a string, found {:?}", key, value)))
+        };
+        let as_bool = || -> Result<bool> {
+            value
+                .as_bool()
+                .ok_or_else(|| Error::Config(format!("'{}' must be either true or false, found {:?}", key, value)))
+        };
+        let negate_if_false = |condition: Self, expected: bool| if expected { condition } else { Self::Not(Box::new(condition)) };
+
+        match key {
+            "all" => Ok(Self::All(Self::list_from_yaml(key, value)?)),
+            "any" => Ok(Self::Any(Self::list_from_yaml(key, value)?)),
+            "not" => Ok(Self::Not(Box::new(Self::from_yaml_value(value)?))),
+            "os" => Ok(Self::IfOs(Os::from_str(&as_string()?)?)),
+            "distro" => Ok(Self::IfDistro(as_string()?.to_lowercase())),
+            "arch" => Ok(Self::IfArch(normalize_arch(&as_string()?))),
+            "hostname" => {
+                let pattern = as_string()?;
+                glob::Pattern::new(&pattern).map_err(|e| Error::Config(format!("Invalid hostname pattern {}: {}", pattern, e)))?;
+                Ok(Self::IfHostname(pattern))
+            }
+            "user" => Ok(Self::IfUser(as_string()?)),
+            "env" => match value {
+                serde_yaml::Value::Mapping(vars) => {
+                    let mut conditions = Vec::new();
+                    for (name, expected) in vars {
+                        let name = name
+                            .as_str()
+                            .ok_or_else(|| Error::Config(format!("Env var names must be strings, found {:?}", name)))?;
+                        let value = scalar_to_string(expected)
+                            .ok_or_else(|| Error::Config(format!("Value for env var {} must be a string, found {:?}", name, expected)))?;
+                        conditions.push(Self::IfEnvEquals {
+                            name: name.to_string(),
+                            value,
+                        });
+                    }
+                    match conditions.len() {
+                        1 => Ok(conditions.remove(0)),
+                        _ => Ok(Self::All(conditions)),
+                    }
+                }
+                _ => Ok(Self::IfEnvSet(as_string()?)),
+            },
+            "file_exists" => Ok(Self::IfFileExists(as_string()?)),
+            "command" => Ok(Self::IfCommand(as_string()?)),
+            "container" => Ok(negate_if_false(Self::IfContainer, as_bool()?)),
+            "wsl" => Ok(negate_if_false(Self::IfWsl, as_bool()?)),
+            _ => Err(Error::Config(format!("Unknown condition: {}", key))),
+        }
+    }
+}
+
+fn scalar_to_string(value: &serde_yaml::Value) -> Option<String> {
+    match value {
+        serde_yaml::Value::String(s) => Some(s.to_string()),
+        serde_yaml::Value::Number(n) => Some(n.to_string()),
+        serde_yaml::Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
 }
 
 #[derive(Debug, Eq, PartialEq)]
 pub struct DirectiveStep {
     pub condition: Condition,
+    /// Overrides the `on_error` of the section, if set
+    pub on_error: Option<OnError>,
+    /// Overrides the `conflict_strategy` of the section, if set
+    pub conflict_strategy: Option<ConflictStrategy>,
     pub directive: Directive,
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Default, Eq, PartialEq)]
 pub enum LinkDirectoryBehaviour {
+    #[default]
     LinkDirectory,
     CreateDirectory,
     IgnoreDirectories,
 }
 
-impl Default for LinkDirectoryBehaviour {
-    fn default() -> Self {
-        LinkDirectoryBehaviour::LinkDirectory
-    }
-}
-
 impl FromStr for LinkDirectoryBehaviour {
     type Err = Error;
 
@@ -146,14 +448,14 @@ impl FromStr for LinkDirectoryBehaviour {
     }
 }
 
-impl ToString for LinkDirectoryBehaviour {
-    fn to_string(&self) -> String {
-        match self {
+impl std::fmt::Display for LinkDirectoryBehaviour {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
             LinkDirectoryBehaviour::CreateDirectory => "create",
             LinkDirectoryBehaviour::LinkDirectory => "link",
             LinkDirectoryBehaviour::IgnoreDirectories => "ignore",
-        }
-        .to_string()
+        };
+        write!(f, "{}", name)
     }
 }
 
@@ -179,12 +481,47 @@ pub enum Directive {
 
 #[derive(Debug)]
 pub struct StateConfig {
-    pub states: HashMap<String, Vec<DirectiveStep>>,
+    /// Sections in the order they appear in the document.
+    pub states: IndexMap<String, Section>,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct Section {
+    pub description: Option<String>,
+    pub enabled: bool,
+    pub condition: Condition,
+    pub depends_on: Vec<String>,
+    /// What to do when one of its directives fails. If not set, it depends on how dotfilers was invoked.
+    pub on_error: Option<OnError>,
+    /// Overrides the global `conflict_strategy` for its directives, if set
+    pub conflict_strategy: Option<ConflictStrategy>,
+    pub steps: Vec<DirectiveStep>,
+}
+
+impl Section {
+    pub fn from_steps(steps: Vec<DirectiveStep>) -> Self {
+        Self {
+            description: None,
+            enabled: true,
+            condition: Condition::Always,
+            depends_on: Vec::new(),
+            on_error: None,
+            conflict_strategy: None,
+            steps,
+        }
+    }
 }
 
 #[derive(Debug, serde::Deserialize)]
 struct YamlDirectiveStep {
+    #[serde(rename = "if")]
+    if_expression: Option<String>,
     if_os: Option<String>,
+    if_distro: Option<String>,
+    if_arch: Option<String>,
+    when: Option<serde_yaml::Value>,
+    on_error: Option<String>,
+    conflict_strategy: Option<String>,
     link_from: Option<String>,
     link_to: Option<String>,
     link_directory_behaviour: Option<String>,
@@ -197,57 +534,267 @@ struct YamlDirectiveStep {
     template_vars: Option<String>,
 }
 
+#[derive(Debug, serde::Deserialize)]
+struct YamlSection {
+    #[serde(rename = "if")]
+    if_expression: Option<String>,
+    when: Option<serde_yaml::Value>,
+    description: Option<String>,
+    enabled: Option<bool>,
+    #[serde(default)]
+    depends_on: Vec<String>,
+    on_error: Option<String>,
+    conflict_strategy: Option<String>,
+    steps: Vec<serde_yaml::Value>,
+}
+
 #[derive(Debug, serde::Deserialize)]
 struct YamlStateConfig {
     #[serde(rename = ".dotfilers")]
     _ignore: Option<HashMap<String, serde_yaml::Value>>,
     #[serde(flatten)]
-    contents: HashMap<String, Vec<YamlDirectiveStep>>,
+    contents: IndexMap<String, serde_yaml::Value>,
 }
 
 impl StateConfig {
     pub fn from_yaml(contents: &str) -> Result<Self> {
-        let parsed: YamlStateConfig = serde_yaml::from_str(contents).map_err(|e| Error::Config(format!("Error parsing yaml: {}", e)))?;
-        let mut states = HashMap::new();
+        Self::from_yaml_with_strictness(contents, true)
+    }
+
+    /// Parses the sections of a config. If `strict` is false, unknown keys and mixed directives are only logged.
+    pub fn from_yaml_with_strictness(contents: &str, strict: bool) -> Result<Self> {
+        let parsed: YamlStateConfig = serde_yaml::from_str(contents).map_err(|e| Error::from_yaml(e, contents, "Error parsing yaml"))?;
+        Self::from_sections(parsed.contents, strict).map_err(|e| e.locate(contents))
+    }
 
-        for (k, v) in parsed.contents {
+    fn from_sections(sections: IndexMap<String, serde_yaml::Value>, strict: bool) -> Result<Self> {
+        let mut states = IndexMap::new();
+        for (k, v) in sections {
             if k == SPECIAL_CONFIG_SECTION_NAME {
                 continue;
             }
-            let mut directives = Vec::new();
-            for (idx, directive) in v.into_iter().enumerate() {
-                let d = Self::parse_directive(directive)
-                    .map_err(|e| Error::Config(format!("error parsing directive in section {}, index {}: {}", k, idx, e)))?;
-                directives.push(d);
+            let section = Self::parse_section(&k, v, strict)?;
+            states.insert(k, section);
+        }
+
+        let instance = Self { states };
+        // Fail early on unknown dependencies and cycles
+        instance.execution_order(None)?;
+        Ok(instance)
+    }
+
+    /// Returns the names of the sections to execute, making sure every section runs after its dependencies.
+    ///
+    /// If `requested` is given, only those sections (and their dependencies) are returned. Otherwise, all of them are.
+    /// Sections that do not depend on each other keep the order in which they were declared.
+    pub fn execution_order(&self, requested: Option<&[&str]>) -> Result<Vec<&str>> {
+        let roots: Vec<&str> = match requested {
+            Some(requested) => requested.to_vec(),
+            None => self.states.keys().map(|k| k.as_str()).collect(),
+        };
+
+        let mut order = Vec::new();
+        let mut visited = HashSet::new();
+        let mut stack = Vec::new();
+        for root in roots {
+            self.visit_section(root, &mut visited, &mut stack, &mut order)?;
+        }
+        Ok(order)
+    }
+
+    fn visit_section<'a>(
+        &'a self,
+        name: &str,
+        visited: &mut HashSet<&'a str>,
+        stack: &mut Vec<&'a str>,
+        order: &mut Vec<&'a str>,
+    ) -> Result<()> {
+        let (name, section) = match self.states.get_key_value(name) {
+            Some((name, section)) => (name.as_str(), section),
+            None => {
+                return Err(match stack.last() {
+                    Some(parent) => Error::at(
+                        vec![(*parent).into(), "depends_on".into()],
+                        format!("Section {} depends on unknown section {}", parent, name),
+                    ),
+                    None => Error::Config(format!("Could not find a section named {}", name)),
+                })
             }
-            states.insert(k, directives);
+        };
+        if visited.contains(name) {
+            return Ok(());
+        }
+        if let Some(position) = stack.iter().position(|s| *s == name) {
+            let mut cycle = stack[position..].to_vec();
+            cycle.push(name);
+            return Err(Error::at(
+                vec![name.into(), "depends_on".into()],
+                format!("Found a dependency cycle between sections: {}", cycle.join(" -> ")),
+            ));
         }
 
-        Ok(Self { states })
+        stack.push(name);
+        for dependency in &section.depends_on {
+            self.visit_section(dependency, visited, stack, order)?;
+        }
+        stack.pop();
+
+        visited.insert(name);
+        order.push(name);
+        Ok(())
+    }
+
+    fn parse_section(name: &str, value: serde_yaml::Value, strict: bool) -> Result<Section> {
+        let section_path = [name.into()];
+        let section_context = format!("Error parsing section {}", name);
+        // Sections can either be a plain list of directives or a mapping with the directives under 'steps'
+        let is_list = value.is_sequence();
+        let yaml_section = if is_list {
+            let steps =
+                serde_yaml::from_value(value).map_err(|e| Error::at(section_path.to_vec(), format!("{}: {}", section_context, e)))?;
+            YamlSection {
+                if_expression: None,
+                when: None,
+                description: None,
+                enabled: None,
+                depends_on: Vec::new(),
+                on_error: None,
+                conflict_strategy: None,
+                steps,
+            }
+        } else {
+            check_unknown_keys(&value, SECTION_KEYS, strict).map_err(|e| e.nested(&section_path, &section_context))?;
+            serde_yaml::from_value(value).map_err(|e| Error::at(section_path.to_vec(), format!("{}: {}", section_context, e)))?
+        };
+
+        let condition = Self::extract_common_conditions(&yaml_section.if_expression, &yaml_section.when, Vec::new())
+            .map_err(|e| e.nested(&section_path, &format!("error parsing condition of section {}", name)))?;
+        let on_error = Self::extract_on_error(&yaml_section.on_error).map_err(|e| e.nested(&section_path, &section_context))?;
+        let conflict_strategy =
+            Self::extract_conflict_strategy(&yaml_section.conflict_strategy).map_err(|e| e.nested(&section_path, &section_context))?;
+
+        let mut steps = Vec::new();
+        for (idx, directive) in yaml_section.steps.into_iter().enumerate() {
+            let directive_path: Vec<YamlPathSegment> = match is_list {
+                true => vec![name.into(), idx.into()],
+                false => vec![name.into(), "steps".into(), idx.into()],
+            };
+            let d = Self::parse_directive(directive, strict).map_err(|e| {
+                e.nested(
+                    &directive_path,
+                    &format!("error parsing directive in section {}, index {}", name, idx),
+                )
+            })?;
+            steps.push(d);
+        }
+
+        Ok(Section {
+            description: yaml_section.description,
+            enabled: yaml_section.enabled.unwrap_or(true),
+            condition,
+            depends_on: yaml_section.depends_on,
+            on_error,
+            conflict_strategy,
+            steps,
+        })
     }
 
-    fn parse_directive(d: YamlDirectiveStep) -> Result<DirectiveStep> {
+    fn parse_directive(value: serde_yaml::Value, strict: bool) -> Result<DirectiveStep> {
+        let known_keys: Vec<&str> = CONDITION_KEYS
+            .iter()
+            .chain(STEP_KEYS.iter())
+            .chain(DIRECTIVE_KEYS.iter().flat_map(|(_, keys)| keys.iter()))
+            .copied()
+            .collect();
+        check_unknown_keys(&value, &known_keys, strict)?;
+        let present_directives: Vec<&str> = DIRECTIVE_KEYS
+            .iter()
+            .filter(|(_, keys)| keys.iter().any(|k| value.get(k).is_some()))
+            .map(|(name, _)| *name)
+            .collect();
+
+        let d: YamlDirectiveStep = serde_yaml::from_value(value).map_err(|e| Error::Config(format!("Error reading directive: {}", e)))?;
         let condition = Self::extract_condition(&d)?;
-        let directive = Self::extract_directive(&d)?;
-        Ok(DirectiveStep { condition, directive })
+        let on_error = Self::extract_on_error(&d.on_error)?;
+        let conflict_strategy = Self::extract_conflict_strategy(&d.conflict_strategy)?;
+        let directive = Self::extract_directive(&d, &present_directives, strict)?;
+        Ok(DirectiveStep {
+            condition,
+            on_error,
+            conflict_strategy,
+            directive,
+        })
+    }
+
+    fn extract_on_error(on_error: &Option<String>) -> Result<Option<OnError>> {
+        on_error
+            .as_deref()
+            .map(|o| OnError::from_str(o).map_err(|e| e.nested(&["on_error".into()], "Error parsing 'on_error'")))
+            .transpose()
+    }
+
+    fn extract_conflict_strategy(conflict_strategy: &Option<String>) -> Result<Option<ConflictStrategy>> {
+        conflict_strategy
+            .as_deref()
+            .map(|s| ConflictStrategy::from_str(s).map_err(|e| e.nested(&["conflict_strategy".into()], "Error parsing ConflictStrategy")))
+            .transpose()
     }
 
     fn extract_condition(d: &YamlDirectiveStep) -> Result<Condition> {
-        match d.if_os {
-            None => Ok(Condition::Always),
-            Some(ref os) => {
-                let parsed_os = Os::from_str(os)?;
-                Ok(Condition::IfOs(parsed_os))
-            }
+        let mut conditions = Vec::new();
+        if let Some(ref os) = d.if_os {
+            conditions.push(Condition::IfOs(
+                Os::from_str(os).map_err(|e| e.nested(&["if_os".into()], "Error parsing 'if_os'"))?,
+            ));
+        }
+        if let Some(ref distro) = d.if_distro {
+            conditions.push(Condition::IfDistro(distro.to_lowercase()));
         }
+        if let Some(ref arch) = d.if_arch {
+            conditions.push(Condition::IfArch(normalize_arch(arch)));
+        }
+        Self::extract_common_conditions(&d.if_expression, &d.when, conditions)
     }
 
-    fn extract_directive(d: &YamlDirectiveStep) -> Result<Directive> {
+    /// Conditions that can be used both in sections and in directives.
+    fn extract_common_conditions(
+        if_expression: &Option<String>,
+        when: &Option<serde_yaml::Value>,
+        mut conditions: Vec<Condition>,
+    ) -> Result<Condition> {
+        if let Some(ref expression) = if_expression {
+            crate::expression::parse(expression)
+                .map_err(|e| Error::at(vec!["if".into()], format!("Error parsing 'if' expression {}: {}", expression, e)))?;
+            conditions.push(Condition::Expression(expression.to_string()));
+        }
+        if let Some(ref when) = when {
+            conditions.push(Condition::from_yaml_value(when).map_err(|e| e.nested(&["when".into()], "Error parsing 'when'"))?);
+        }
+
+        match conditions.len() {
+            0 => Ok(Condition::Always),
+            1 => Ok(conditions.remove(0)),
+            _ => Ok(Condition::All(conditions)),
+        }
+    }
+
+    fn extract_directive(d: &YamlDirectiveStep, present_directives: &[&str], strict: bool) -> Result<Directive> {
+        if present_directives.len() > 1 {
+            let message = format!(
+                "entry mixes several directives ({}), please split them into separate entries",
+                present_directives.join(", ")
+            );
+            if strict {
+                return Err(Error::Config(message));
+            }
+            warn!("Using only the first directive: {}", message);
+        }
+
         match (&d.link_from, &d.link_to) {
             (Some(from), Some(to)) => {
                 let behaviour = match d.link_directory_behaviour {
                     Some(ref b) => LinkDirectoryBehaviour::from_str(b)
-                        .map_err(|e| Error::Config(format!("Error reading LinkDirectoryBehaviour: {}", e)))?,
+                        .map_err(|e| e.nested(&["link_directory_behaviour".into()], "Error reading LinkDirectoryBehaviour"))?,
                     None => LinkDirectoryBehaviour::LinkDirectory,
                 };
                 return Ok(Directive::Link {
@@ -339,12 +886,12 @@ impl Config {
 
         let contents = std::fs::read_to_string(path)
             .map_err(|e| Error::Config(format!("Error reading config from path [path={}]: {}", path.display(), e)))?;
-        Self::from_yaml(&contents)
+        Self::from_yaml(&contents).map_err(|e| e.with_file(path))
     }
 
     pub fn from_yaml(yaml: &str) -> Result<Self> {
-        let program = ProgramConfig::from_yaml(yaml).map_err(|e| Error::Config(format!("Error reading program config: {}", e)))?;
-        let state = StateConfig::from_yaml(yaml).map_err(|e| Error::Config(format!("Error reading directives: {}", e)))?;
+        let program = ProgramConfig::from_yaml(yaml).map_err(|e| e.nested(&[], "Error reading program config"))?;
+        let state = StateConfig::from_yaml_with_strictness(yaml, program.strict).map_err(|e| e.nested(&[], "Error reading directives"))?;
 
         Ok(Self {
             program,
@@ -389,7 +936,12 @@ nvim:
 
         assert_eq!(parsed.state_config.states.len(), 1);
 
-        let nvim = parsed.state_config.states.get("nvim").expect("Should contain an nvim section");
+        let nvim = &parsed
+            .state_config
+            .states
+            .get("nvim")
+            .expect("Should contain an nvim section")
+            .steps;
         assert_eq!(nvim.len(), 1);
 
         assert_eq!(nvim[0].condition, Condition::IfOs(Os::Linux));
@@ -403,6 +955,38 @@ nvim:
         );
     }
 
+    #[test]
+    fn extract_prompt_fallback() {
+        let yaml = r#"
+.dotfilers:
+  conflict_strategy: prompt
+  prompt_fallback: rename-old
+        "#;
+        let parsed = Config::from_yaml(yaml).expect("Should be able to parse");
+        assert_eq!(parsed.program.conflict_strategy, ConflictStrategy::Prompt);
+        assert_eq!(parsed.program.prompt_fallback, ConflictStrategy::RenameOld);
+
+        let parsed = Config::from_yaml(".dotfilers:\n  conflict_strategy: prompt\n").expect("Should be able to parse");
+        assert_eq!(parsed.program.prompt_fallback, ConflictStrategy::Abort);
+
+        let error = Config::from_yaml(".dotfilers:\n  prompt_fallback: prompt\n").expect_err("Should not accept prompt");
+        assert!(error
+            .to_string()
+            .contains("prompt_fallback must be a strategy that does not ask anything"));
+    }
+
+    #[test]
+    fn extract_kept_runs() {
+        let parsed = Config::from_yaml(".dotfilers:\n  kept_runs: 5\n").expect("Should be able to parse");
+        assert_eq!(parsed.program.kept_runs, 5);
+
+        let parsed = Config::from_yaml(".dotfilers:\n  shell: sh -c\n").expect("Should be able to parse");
+        assert_eq!(parsed.program.kept_runs, DEFAULT_KEPT_RUNS);
+
+        let error = Config::from_yaml(".dotfilers:\n  kept_runs: 0\n").expect_err("Should not accept 0");
+        assert!(error.to_string().contains("kept_runs must be at least 1"));
+    }
+
     #[test]
     fn extract_directives() {
         let yaml = r#"
@@ -434,7 +1018,7 @@ ssh:
 
         assert_eq!(parsed.states.len(), 2);
 
-        let nvim = parsed.states.get("nvim").expect("Should contain an nvim section");
+        let nvim = &parsed.states.get("nvim").expect("Should contain an nvim section").steps;
         assert_eq!(nvim.len(), 3);
 
         assert_eq!(nvim[0].condition, Condition::IfOs(Os::Linux));
@@ -451,7 +1035,7 @@ ssh:
         assert_eq!(nvim[2].condition, Condition::Always);
         assert_eq!(nvim[2].directive, Directive::Include("nvim/things.yaml".to_string()));
 
-        let ssh = parsed.states.get("ssh").expect("Should contain a ssh section");
+        let ssh = &parsed.states.get("ssh").expect("Should contain a ssh section").steps;
         assert_eq!(ssh.len(), 5);
 
         assert_eq!(ssh[0].condition, Condition::IfOs(Os::Darwin));
@@ -493,9 +1077,438 @@ ssh:
         );
     }
 
+    #[test]
+    fn extract_distro_and_arch_conditions() {
+        let yaml = r#"
+pkgs:
+  - if_distro: Ubuntu
+    run: apt install -y neovim
+  - if_arch: arm64
+    run: ./install_arm.sh
+  - if_os: macos
+    run: brew install neovim
+        "#;
+        let parsed = StateConfig::from_yaml(yaml).expect("Should not have failed");
+        let pkgs = &parsed.states.get("pkgs").expect("Should contain a pkgs section").steps;
+
+        assert_eq!(pkgs[0].condition, Condition::IfDistro("ubuntu".to_string()));
+        assert_eq!(pkgs[1].condition, Condition::IfArch("aarch64".to_string()));
+        assert_eq!(pkgs[2].condition, Condition::IfOs(Os::Darwin));
+    }
+
+    #[test]
+    fn extract_condition_tree() {
+        let yaml = r#"
+work:
+  - if_os: linux
+    when:
+      hostname: work-*
+      not:
+        container: true
+    run: ./setup_work.sh
+  - when:
+      any:
+        - env: WORK
+        - env:
+            PROFILE: work
+        - all:
+            - user: me
+            - file_exists: ~/.work
+      command: git
+    run: ./other.sh
+        "#;
+        let parsed = StateConfig::from_yaml(yaml).expect("Should not have failed");
+        let work = &parsed.states.get("work").expect("Should contain a work section").steps;
+
+        assert_eq!(
+            work[0].condition,
+            Condition::All(vec![
+                Condition::IfOs(Os::Linux),
+                Condition::All(vec![
+                    Condition::IfHostname("work-*".to_string()),
+                    Condition::Not(Box::new(Condition::IfContainer)),
+                ]),
+            ])
+        );
+        assert_eq!(
+            work[1].condition,
+            Condition::All(vec![
+                Condition::Any(vec![
+                    Condition::IfEnvSet("WORK".to_string()),
+                    Condition::IfEnvEquals {
+                        name: "PROFILE".to_string(),
+                        value: "work".to_string()
+                    },
+                    Condition::All(vec![
+                        Condition::IfUser("me".to_string()),
+                        Condition::IfFileExists("~/.work".to_string()),
+                    ]),
+                ]),
+                Condition::IfCommand("git".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn extract_expression_condition() {
+        let yaml = r#"
+work:
+  - if: "os == 'linux' && arch == 'aarch64' && env.WORK == '1'"
+    run: ./setup_work.sh
+        "#;
+        let parsed = StateConfig::from_yaml(yaml).expect("Should not have failed");
+        let work = &parsed.states.get("work").expect("Should contain a work section").steps;
+
+        assert_eq!(
+            work[0].condition,
+            Condition::Expression("os == 'linux' && arch == 'aarch64' && env.WORK == '1'".to_string())
+        );
+    }
+
+    #[test]
+    fn extract_conflict_strategy_overrides() {
+        let yaml = r#"
+caches:
+  conflict_strategy: overwrite
+  steps:
+    - copy_from: cache
+      copy_to: ~/.cache/tool
+    - link_from: ssh
+      link_to: ~/.ssh
+      conflict_strategy: abort
+        "#;
+        let parsed = StateConfig::from_yaml(yaml).expect("Should not have failed");
+        let caches = parsed.states.get("caches").expect("Should contain a caches section");
+        assert_eq!(caches.conflict_strategy, Some(ConflictStrategy::Overwrite));
+        assert_eq!(caches.steps[0].conflict_strategy, None);
+        assert_eq!(caches.steps[1].conflict_strategy, Some(ConflictStrategy::Abort));
+
+        let yaml = r#"
+caches:
+  - copy_from: cache
+    copy_to: ~/.cache/tool
+    conflict_strategy: clobber
+        "#;
+        let err = StateConfig::from_yaml(yaml).expect_err("Should have failed");
+        assert!(err.to_string().contains("Unknown ConflictStrategy: clobber"), "{}", err);
+    }
+
+    #[test]
+    fn extract_on_error() {
+        let yaml = r#"
+caches:
+  on_error: continue
+  steps:
+    - run: ./rebuild_cache.sh
+    - link_from: ssh
+      link_to: ~/.ssh
+      on_error: abort
+        "#;
+        let parsed = StateConfig::from_yaml(yaml).expect("Should not have failed");
+        let caches = parsed.states.get("caches").expect("Should contain a caches section");
+        assert_eq!(caches.on_error, Some(OnError::Continue));
+        assert_eq!(caches.steps[0].on_error, None);
+        assert_eq!(caches.steps[1].on_error, Some(OnError::Abort));
+
+        let yaml = r#"
+caches:
+  - run: ./rebuild_cache.sh
+    on_error: ignore
+        "#;
+        let err = StateConfig::from_yaml(yaml).expect_err("Should have failed");
+        assert!(err.to_string().contains("unknown on_error value: ignore"), "{}", err);
+    }
+
+    #[test]
+    fn extract_sections() {
+        let yaml = r#"
+macos:
+  description: Things that only make sense on a mac
+  if: "os == 'darwin'"
+  steps:
+    - run: brew bundle
+disabled:
+  enabled: false
+  when:
+    user: me
+  steps:
+    - run: ./disabled.sh
+plain:
+  - run: ./plain.sh
+        "#;
+        let parsed = StateConfig::from_yaml(yaml).expect("Should not have failed");
+        assert_eq!(parsed.states.len(), 3);
+
+        let macos = parsed.states.get("macos").expect("Should contain a macos section");
+        assert_eq!(macos.description, Some("Things that only make sense on a mac".to_string()));
+        assert!(macos.enabled);
+        assert_eq!(macos.condition, Condition::Expression("os == 'darwin'".to_string()));
+        assert_eq!(macos.steps.len(), 1);
+
+        let disabled = parsed.states.get("disabled").expect("Should contain a disabled section");
+        assert!(!disabled.enabled);
+        assert_eq!(disabled.condition, Condition::IfUser("me".to_string()));
+
+        let plain = parsed.states.get("plain").expect("Should contain a plain section");
+        assert_eq!(
+            plain,
+            &Section::from_steps(vec![DirectiveStep {
+                condition: Condition::Always,
+                on_error: None,
+                conflict_strategy: None,
+                directive: Directive::Run("./plain.sh".to_string()),
+            }])
+        );
+    }
+
+    #[test]
+    fn dependencies_run_first() {
+        let yaml = r#"
+git:
+  depends_on: [ssh]
+  steps:
+    - run: ./git.sh
+ssh:
+  depends_on:
+    - base
+  steps:
+    - run: ./ssh.sh
+base:
+  - run: ./base.sh
+        "#;
+        let parsed = StateConfig::from_yaml(yaml).expect("Should not have failed");
+        assert_eq!(parsed.states.get("git").unwrap().depends_on, vec!["ssh".to_string()]);
+
+        assert_eq!(parsed.execution_order(None).unwrap(), vec!["base", "ssh", "git"]);
+        assert_eq!(parsed.execution_order(Some(&["git"])).unwrap(), vec!["base", "ssh", "git"]);
+        assert_eq!(parsed.execution_order(Some(&["ssh"])).unwrap(), vec!["base", "ssh"]);
+        assert_eq!(parsed.execution_order(Some(&["base", "git"])).unwrap(), vec!["base", "ssh", "git"]);
+        parsed
+            .execution_order(Some(&["unknown"]))
+            .expect_err("Should fail on unknown sections");
+    }
+
+    #[test]
+    fn sections_keep_declaration_order() {
+        let yaml = r#"
+zsh:
+  - run: ./zsh.sh
+.dotfilers:
+  log_level: debug
+alacritty:
+  - run: ./alacritty.sh
+nvim:
+  - run: ./nvim.sh
+git:
+  - run: ./git.sh
+bash:
+  - run: ./bash.sh
+        "#;
+        let parsed = StateConfig::from_yaml(yaml).expect("Should not have failed");
+        let names: Vec<&str> = parsed.states.keys().map(|k| k.as_str()).collect();
+        assert_eq!(names, vec!["zsh", "alacritty", "nvim", "git", "bash"]);
+        assert_eq!(parsed.execution_order(None).unwrap(), names);
+    }
+
+    #[test]
+    fn independent_sections_keep_their_order() {
+        let mut states = IndexMap::new();
+        let mut c = Section::from_steps(Vec::new());
+        c.depends_on = vec!["b".to_string()];
+        states.insert("c".to_string(), c);
+        states.insert("a".to_string(), Section::from_steps(Vec::new()));
+        states.insert("b".to_string(), Section::from_steps(Vec::new()));
+        states.insert("d".to_string(), Section::from_steps(Vec::new()));
+
+        let config = StateConfig { states };
+        assert_eq!(config.execution_order(None).unwrap(), vec!["b", "c", "a", "d"]);
+    }
+
+    #[test]
+    fn lenient_mode_ignores_unknown_keys() {
+        let yaml = r#"
+.dotfilers:
+  strict: false
+  colour: true
+ssh:
+  - template: ssh/config.tpl
+    template_to: ~/.ssh/config
+    vars: ssh/vars
+  - link_from: a
+    link_to: b
+    copy_from: c
+    copy_to: d
+        "#;
+        let parsed = Config::from_yaml(yaml).expect("Should not have failed");
+        assert!(!parsed.program.strict);
+
+        let ssh = &parsed.state_config.states.get("ssh").unwrap().steps;
+        assert_eq!(
+            ssh[1].directive,
+            Directive::Link {
+                from: "a".to_string(),
+                to: "b".to_string(),
+                directory_behaviour: LinkDirectoryBehaviour::default(),
+            }
+        );
+    }
+
+    #[test]
+    fn example_is_valid() {
+        Config::from_yaml(include_str!("../example.yaml")).expect("The example config should be valid");
+    }
+
+    #[test]
+    fn suggestions() {
+        let keys = ["link_from", "link_to", "template", "template_vars", "depends_on"];
+        assert_eq!(did_you_mean("vars", &keys), Some("template_vars"));
+        assert_eq!(did_you_mean("link_form", &keys), Some("link_from"));
+        assert_eq!(did_you_mean("depend_on", &keys), Some("depends_on"));
+        assert_eq!(did_you_mean("templte", &keys), Some("template"));
+        assert_eq!(did_you_mean("something", &keys), None);
+    }
+
     mod errors {
         use super::*;
 
+        fn expect_error_containing(yaml: &str, expected: &[&str]) {
+            let err = Config::from_yaml(yaml).expect_err("Should have failed");
+            let message = err.to_string();
+            for e in expected {
+                assert!(message.contains(e), "{} does not contain {}", message, e);
+            }
+        }
+
+        #[test]
+        fn unknown_directive_key() {
+            expect_error_containing(
+                r#"
+ssh:
+  - run: echo
+  - template: ssh/config.tpl
+    template_to: ~/.ssh/config
+    vars: ssh/vars
+            "#,
+                &["section ssh, index 1", "unknown key 'vars'", "did you mean 'template_vars'?"],
+            )
+        }
+
+        #[test]
+        fn mixed_directives() {
+            expect_error_containing(
+                r#"
+ssh:
+  - link_from: a
+    link_to: b
+    copy_from: c
+    copy_to: d
+            "#,
+                &["section ssh, index 0", "mixes several directives (link, copy)"],
+            )
+        }
+
+        #[test]
+        fn unknown_section_key() {
+            expect_error_containing(
+                r#"
+git:
+  depend_on: [ssh]
+  steps: []
+            "#,
+                &["section git", "did you mean 'depends_on'?"],
+            )
+        }
+
+        #[test]
+        fn unknown_program_key() {
+            expect_error_containing(
+                r#"
+.dotfilers:
+  log_levl: debug
+            "#,
+                &["unknown key 'log_levl'", "did you mean 'log_level'?"],
+            )
+        }
+
+        fn expect_location(err: Error, line: usize, column: usize) {
+            let location = err
+                .config_error()
+                .and_then(|e| e.location.clone())
+                .unwrap_or_else(|| panic!("{} should have a location", err));
+            assert_eq!((location.line, location.column), (line, column), "{}", err);
+        }
+
+        #[test]
+        fn directive_error_location() {
+            let err = Config::from_yaml(
+                r#"
+ssh:
+  - run: echo
+  - template: ssh/config.tpl
+    template_to: ~/.ssh/config
+    vars: ssh/vars
+            "#,
+            )
+            .expect_err("Should have failed");
+            assert!(matches!(err, Error::Invalid(_)), "{:?}", err);
+            expect_location(err, 6, 5);
+        }
+
+        #[test]
+        fn section_error_location() {
+            let err = StateConfig::from_yaml(
+                r#"
+ssh:
+  steps: []
+git:
+  depends_on: [ssh, gpg]
+  steps:
+    - run: ./git.sh
+    - if_os: plan9
+      run: ./other.sh
+            "#,
+            )
+            .expect_err("Should have failed");
+            expect_location(err, 8, 7);
+
+            let err = StateConfig::from_yaml(
+                r#"
+git:
+  depends_on: [ssh, gpg]
+  steps: []
+            "#,
+            )
+            .expect_err("Should have failed");
+            expect_location(err, 3, 3);
+        }
+
+        #[test]
+        fn program_error_location() {
+            let err = Config::from_yaml(
+                r#"
+.dotfilers:
+  shell: /bin/zsh -c
+  conflict_strategy: rename
+            "#,
+            )
+            .expect_err("Should have failed");
+            expect_location(err, 4, 3);
+        }
+
+        #[test]
+        fn syntax_error_location() {
+            let err = Config::from_yaml(
+                r#"
+nvim:
+  - run: echo
+   link_from: a
+            "#,
+            )
+            .expect_err("Should have failed");
+            assert!(matches!(err, Error::Syntax(_)), "{:?}", err);
+            expect_location(err, 4, 13);
+        }
+
         fn expect_error(yaml: &str) {
             StateConfig::from_yaml(yaml).expect_err("Should have failed");
         }
@@ -551,6 +1564,131 @@ nvim:
             )
         }
 
+        #[test]
+        fn unknown_predicate() {
+            expect_error(
+                r#"
+nvim:
+  - when:
+      hostnam: work-*
+    run: ./file
+            "#,
+            )
+        }
+
+        #[test]
+        fn invalid_expression() {
+            let err = StateConfig::from_yaml(
+                r#"
+work:
+  - run: ./other.sh
+  - if: "os == 'linux' &&"
+    run: ./setup_work.sh
+            "#,
+            )
+            .expect_err("Should have failed");
+            let message = err.to_string();
+            assert!(message.contains("section work, index 1"), "{}", message);
+            assert!(message.contains("'if' expression"), "{}", message);
+        }
+
+        #[test]
+        fn unknown_expression_variable() {
+            let err = StateConfig::from_yaml(
+                r#"
+work:
+  - run: ./other.sh
+  - if: "hostnme == 'work'"
+    run: ./setup_work.sh
+            "#,
+            )
+            .expect_err("Should have failed");
+            let message = err.to_string();
+            assert!(message.contains("section work, index 1"), "{}", message);
+            assert!(
+                message.contains("unknown variable 'hostnme' (did you mean 'hostname'?)"),
+                "{}",
+                message
+            );
+        }
+
+        #[test]
+        fn section_without_steps() {
+            expect_error(
+                r#"
+nvim:
+  description: Missing steps
+            "#,
+            )
+        }
+
+        #[test]
+        fn invalid_section_condition() {
+            expect_error(
+                r#"
+nvim:
+  if: "os =="
+  steps:
+    - run: ./file
+            "#,
+            )
+        }
+
+        #[test]
+        fn unknown_dependency() {
+            expect_error(
+                r#"
+git:
+  depends_on: [ssh]
+  steps:
+    - run: ./git.sh
+            "#,
+            )
+        }
+
+        #[test]
+        fn dependency_cycle() {
+            let err = StateConfig::from_yaml(
+                r#"
+a:
+  depends_on: [b]
+  steps: []
+b:
+  depends_on: [c]
+  steps: []
+c:
+  depends_on: [a]
+  steps: []
+            "#,
+            )
+            .expect_err("Should have failed");
+            assert!(err.to_string().contains("dependency cycle"), "{}", err);
+        }
+
+        #[test]
+        fn empty_any() {
+            expect_error(
+                r#"
+nvim:
+  - when:
+      any: []
+    run: ./file
+            "#,
+            )
+        }
+
+        #[test]
+        fn container_not_a_bool() {
+            expect_error(
+                r#"
+nvim:
+  - when:
+      container: maybe
+    run: ./file
+            "#,
+            )
+        }
+
         #[test]
         fn no_content() {
             expect_error(
@@ -0,0 +1,192 @@
+use crate::config::Os;
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const OS_RELEASE_PATHS: [&str; 2] = ["etc/os-release", "usr/lib/os-release"];
+const KERNEL_OSRELEASE_PATH: &str = "proc/sys/kernel/osrelease";
+const CONTAINER_MARKER_PATHS: [&str; 2] = [".dockerenv", "run/.containerenv"];
+const INIT_CGROUP_PATH: &str = "proc/1/cgroup";
+const CONTAINER_CGROUP_MARKERS: [&str; 5] = ["docker", "kubepods", "containerd", "lxc", "libpod"];
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct OsInfo {
+    pub os: Os,
+    pub arch: String,
+    pub distro: Option<String>,
+    pub distro_version: Option<String>,
+    pub is_wsl: bool,
+    pub is_container: bool,
+}
+
+/// Detects the running system by inspecting the filesystem below `root`.
+///
+/// `root` is `/` for the real system, but it can point to any directory that mimics its layout.
+pub struct OsDetector {
+    pub root: PathBuf,
+}
+
+impl OsDetector {
+    pub fn with_root<P: AsRef<Path>>(root: P) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+
+    pub fn detect(&self) -> Result<OsInfo> {
+        let os = Os::from_str(std::env::consts::OS).map_err(|e| anyhow!("Unsupported OS: {}", e))?;
+        let (distro, distro_version) = match os {
+            Os::Linux | Os::Android => self.detect_distro().context("Error detecting distro")?,
+            _ => (None, None),
+        };
+        let is_linux = os == Os::Linux;
+
+        let info = OsInfo {
+            os,
+            arch: normalize_arch(std::env::consts::ARCH),
+            distro,
+            distro_version,
+            is_wsl: is_linux && self.detect_wsl(),
+            is_container: is_linux && self.detect_container(),
+        };
+        debug!("Detected OS info: {:?}", info);
+        Ok(info)
+    }
+
+    /// Kernel release, read from `/proc` on Linux and from `uname(2)` everywhere else.
+    pub fn kernel_version(&self) -> Option<String> {
+        if let Ok(release) = std::fs::read_to_string(self.root.join(KERNEL_OSRELEASE_PATH)) {
+            return Some(release.trim().to_string());
+        }
+        uname_release()
+    }
+
+    fn detect_distro(&self) -> Result<(Option<String>, Option<String>)> {
+        for path in OS_RELEASE_PATHS {
+            let path = self.root.join(path);
+            if !path.is_file() {
+                continue;
+            }
+            debug!("Reading distro information from {}", path.display());
+            let contents = std::fs::read_to_string(&path).context(format!("Error reading {}", path.display()))?;
+            let fields = parse_os_release(&contents);
+            return Ok((fields.get("ID").cloned(), fields.get("VERSION_ID").cloned()));
+        }
+        debug!("Could not find any os-release file below {}", self.root.display());
+        Ok((None, None))
+    }
+
+    fn detect_wsl(&self) -> bool {
+        match std::fs::read_to_string(self.root.join(KERNEL_OSRELEASE_PATH)) {
+            Ok(release) => {
+                let release = release.to_lowercase();
+                release.contains("microsoft") || release.contains("wsl")
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn detect_container(&self) -> bool {
+        if CONTAINER_MARKER_PATHS.iter().any(|p| self.root.join(p).exists()) {
+            return true;
+        }
+        match std::fs::read_to_string(self.root.join(INIT_CGROUP_PATH)) {
+            Ok(cgroup) => CONTAINER_CGROUP_MARKERS.iter().any(|m| cgroup.contains(m)),
+            Err(_) => false,
+        }
+    }
+}
+
+impl Default for OsDetector {
+    fn default() -> Self {
+        Self::with_root("/")
+    }
+}
+
+#[cfg(unix)]
+fn uname_release() -> Option<String> {
+    // SAFETY: utsname only holds byte arrays, so all zeroes is a valid value, and uname only writes into it
+    let mut name: libc::utsname = unsafe { std::mem::zeroed() };
+    if unsafe { libc::uname(&mut name) } != 0 {
+        return None;
+    }
+    // SAFETY: uname fills every field with a nul-terminated string
+    let release = unsafe { std::ffi::CStr::from_ptr(name.release.as_ptr()) };
+    Some(release.to_string_lossy().trim().to_string())
+}
+
+#[cfg(not(unix))]
+fn uname_release() -> Option<String> {
+    None
+}
+
+/// Normalizes the different names an architecture is known by (`amd64`, `arm64`...) into the ones Rust uses.
+pub fn normalize_arch(arch: &str) -> String {
+    match arch.to_lowercase().as_str() {
+        "amd64" | "x64" => "x86_64",
+        "arm64" => "aarch64",
+        "i386" | "i686" => "x86",
+        "armv7" | "armv7l" | "armhf" => "arm",
+        other => return other.to_string(),
+    }
+    .to_string()
+}
+
+fn parse_os_release(contents: &str) -> HashMap<String, String> {
+    let mut fields = HashMap::new();
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some((name, value)) = line.split_once('=') {
+            let value = value.trim().trim_matches(|c| c == '"' || c == '\'');
+            fields.insert(name.trim().to_string(), value.to_string());
+        }
+    }
+    fields
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_os_release_fields() {
+        let fields = parse_os_release(
+            r#"
+NAME="Ubuntu"
+VERSION_ID="22.04"
+# ID=commented
+ID=ubuntu
+ID_LIKE=debian
+PRETTY_NAME='Ubuntu 22.04 LTS'
+        "#,
+        );
+
+        assert_eq!(fields.get("ID"), Some(&"ubuntu".to_string()));
+        assert_eq!(fields.get("VERSION_ID"), Some(&"22.04".to_string()));
+        assert_eq!(fields.get("NAME"), Some(&"Ubuntu".to_string()));
+        assert_eq!(fields.get("PRETTY_NAME"), Some(&"Ubuntu 22.04 LTS".to_string()));
+        assert_eq!(fields.len(), 5);
+    }
+
+    #[test]
+    fn normalize_arch_aliases() {
+        assert_eq!(normalize_arch("amd64"), "x86_64");
+        assert_eq!(normalize_arch("ARM64"), "aarch64");
+        assert_eq!(normalize_arch("x86_64"), "x86_64");
+        assert_eq!(normalize_arch("riscv64"), "riscv64");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn kernel_version_without_proc() {
+        // A root without /proc falls back to uname(2), without running anything
+        let release = OsDetector::with_root("/does/not/exist")
+            .kernel_version()
+            .expect("Should find the kernel release");
+        assert!(!release.is_empty());
+    }
+}
@@ -1,34 +1,80 @@
-use crate::config::{Condition, ConflictStrategy, Directive, DirectiveStep, Os, StateConfig};
+use crate::backups::{create_dir_all, BackupEntry, BackupStore};
+use crate::command::{CommandInvocation, CommandRunner, RealCommandRunner};
+use crate::config::{ConflictStrategy, Directive, DirectiveStep, OnError, Section, StateConfig};
+use crate::facts::{Facts, FactsProvider, RealFactsProvider};
+use crate::filesystem::{Filesystem, Metadata, RealFilesystem};
+use crate::history::RunJournal;
+use crate::manifest::{hash_bytes, EntryKind, ManifestEntry, ManifestStore};
+use crate::overlay::Overlay;
+use crate::plan::{Operation, Plan};
+use crate::prompt::{Answer, Conflict, Existing, Prompter, TerminalPrompter};
+use crate::transaction::Change;
 use crate::LinkDirectoryBehaviour;
 use anyhow::{anyhow, Context, Result};
-use fs_extra::dir::CopyOptions;
+use similar::TextDiff;
+use std::cell::{Cell, OnceCell, Ref, RefCell};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
 use std::path::{Path, PathBuf};
-use std::process::Command;
 use tera::{Context as TeraContext, Tera};
 
-pub trait OsDetector {
-    fn get_os(&self) -> Result<Os>;
-}
-
-pub struct RealOsDetector;
-
-impl OsDetector for RealOsDetector {
-    fn get_os(&self) -> Result<Os> {
-        Ok(Os::Linux)
-    }
-}
-
-pub struct Executor<T>
+pub struct Executor<T, F = RealFilesystem, C = RealCommandRunner, I = TerminalPrompter>
 where
-    T: OsDetector,
+    T: FactsProvider,
+    F: Filesystem,
+    C: CommandRunner,
+    I: Prompter,
 {
     pub dry_run: bool,
     pub shell: String,
-    pub os_detector: T,
+    pub facts_provider: T,
+    /// Where sources are read from and everything gets deployed into
+    pub filesystem: F,
+    /// What runs the commands of `run` directives
+    pub command_runner: C,
+    /// What asks how to resolve conflicts with the `prompt` strategy
+    pub prompter: I,
     pub conflict_strategy: ConflictStrategy,
+    /// Strategy used instead of `prompt` in dry-run mode, or when the prompter is not interactive
+    pub prompt_fallback: ConflictStrategy,
+    /// Whether included files are parsed in strict mode
+    pub strict: bool,
+    /// Where everything that gets deployed is recorded. Nothing is recorded in dry-run mode.
+    pub manifest: Option<ManifestStore>,
+    /// Where the backup conflict strategy moves displaced files into
+    pub backup_store: Option<BackupStore>,
+    /// Where every change applied to the machine is journaled, so the run can be undone later
+    pub run_journal: Option<RunJournal>,
+    /// Whether to carry on after a directive fails, unless its `on_error` (or the one of its section) says otherwise
+    pub keep_going: bool,
+    facts: OnceCell<Facts>,
+    /// Backups made for destinations that have not been recorded in the manifest yet
+    backups: RefCell<HashMap<PathBuf, PathBuf>>,
+    /// Everything planned so far in dry-run mode, which later directives see as if it had been applied
+    pending: RefCell<Plan>,
+    /// Changes applied inside the current transaction, if there is one
+    pub(crate) journal: RefCell<Option<Vec<Change>>>,
+    /// Directives that failed, but were allowed to carry on
+    failures: RefCell<Vec<Failure>>,
+    /// Answer given to every conflict that follows
+    answer_for_all: Cell<Option<Answer>>,
+}
+
+/// A directive that failed without stopping the run.
+#[derive(Debug)]
+pub struct Failure {
+    pub section: String,
+    pub index: usize,
+    pub error: anyhow::Error,
+}
+
+impl Display for Failure {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[section={}] [index={}]: {:#}", self.section, self.index, self.error)
+    }
 }
 
-impl Executor<RealOsDetector> {
+impl Executor<RealFactsProvider> {
     pub fn dry_run(shell: &str, conflict_strategy: ConflictStrategy) -> Self {
         Self::create(true, shell, conflict_strategy)
     }
@@ -41,304 +87,667 @@ impl Executor<RealOsDetector> {
         Self {
             shell: shell.to_string(),
             dry_run,
-            os_detector: RealOsDetector,
+            facts_provider: RealFactsProvider::default(),
+            filesystem: RealFilesystem,
+            command_runner: RealCommandRunner,
+            prompter: TerminalPrompter,
             conflict_strategy,
+            prompt_fallback: ConflictStrategy::Abort,
+            strict: true,
+            manifest: None,
+            backup_store: None,
+            run_journal: None,
+            keep_going: false,
+            facts: OnceCell::new(),
+            backups: RefCell::new(HashMap::new()),
+            pending: RefCell::new(Plan::default()),
+            journal: RefCell::new(None),
+            failures: RefCell::new(Vec::new()),
+            answer_for_all: Cell::new(None),
         }
     }
 }
 
-impl<T> Executor<T>
+impl<T, F, C, I> Executor<T, F, C, I>
 where
-    T: OsDetector,
+    T: FactsProvider,
+    F: Filesystem,
+    C: CommandRunner,
+    I: Prompter,
 {
-    pub fn execute<P: AsRef<Path>>(&self, root_dir: P, section: &str, directives: &[DirectiveStep]) -> Result<()> {
+    pub fn with_facts_provider<U: FactsProvider>(self, facts_provider: U) -> Executor<U, F, C, I> {
+        Executor {
+            dry_run: self.dry_run,
+            shell: self.shell,
+            facts_provider,
+            filesystem: self.filesystem,
+            command_runner: self.command_runner,
+            prompter: self.prompter,
+            conflict_strategy: self.conflict_strategy,
+            prompt_fallback: self.prompt_fallback,
+            strict: self.strict,
+            manifest: self.manifest,
+            backup_store: self.backup_store,
+            run_journal: self.run_journal,
+            keep_going: self.keep_going,
+            facts: OnceCell::new(),
+            backups: self.backups,
+            pending: self.pending,
+            journal: self.journal,
+            failures: self.failures,
+            answer_for_all: self.answer_for_all,
+        }
+    }
+
+    pub fn with_filesystem<G: Filesystem>(self, filesystem: G) -> Executor<T, G, C, I> {
+        Executor {
+            dry_run: self.dry_run,
+            shell: self.shell,
+            facts_provider: self.facts_provider,
+            filesystem,
+            command_runner: self.command_runner,
+            prompter: self.prompter,
+            conflict_strategy: self.conflict_strategy,
+            prompt_fallback: self.prompt_fallback,
+            strict: self.strict,
+            manifest: self.manifest,
+            backup_store: self.backup_store,
+            run_journal: self.run_journal,
+            keep_going: self.keep_going,
+            facts: self.facts,
+            backups: self.backups,
+            pending: self.pending,
+            journal: self.journal,
+            failures: self.failures,
+            answer_for_all: self.answer_for_all,
+        }
+    }
+
+    pub fn with_command_runner<D: CommandRunner>(self, command_runner: D) -> Executor<T, F, D, I> {
+        Executor {
+            dry_run: self.dry_run,
+            shell: self.shell,
+            facts_provider: self.facts_provider,
+            filesystem: self.filesystem,
+            command_runner,
+            prompter: self.prompter,
+            conflict_strategy: self.conflict_strategy,
+            prompt_fallback: self.prompt_fallback,
+            strict: self.strict,
+            manifest: self.manifest,
+            backup_store: self.backup_store,
+            run_journal: self.run_journal,
+            keep_going: self.keep_going,
+            facts: self.facts,
+            backups: self.backups,
+            pending: self.pending,
+            journal: self.journal,
+            failures: self.failures,
+            answer_for_all: self.answer_for_all,
+        }
+    }
+
+    pub fn with_prompter<J: Prompter>(self, prompter: J) -> Executor<T, F, C, J> {
+        Executor {
+            dry_run: self.dry_run,
+            shell: self.shell,
+            facts_provider: self.facts_provider,
+            filesystem: self.filesystem,
+            command_runner: self.command_runner,
+            prompter,
+            conflict_strategy: self.conflict_strategy,
+            prompt_fallback: self.prompt_fallback,
+            strict: self.strict,
+            manifest: self.manifest,
+            backup_store: self.backup_store,
+            run_journal: self.run_journal,
+            keep_going: self.keep_going,
+            facts: self.facts,
+            backups: self.backups,
+            pending: self.pending,
+            journal: self.journal,
+            failures: self.failures,
+            answer_for_all: self.answer_for_all,
+        }
+    }
+
+    /// Facts are only gathered the first time they are needed, and then reused for the rest of the run.
+    pub fn facts(&self) -> Result<&Facts> {
+        if let Some(facts) = self.facts.get() {
+            return Ok(facts);
+        }
+        let facts = self.facts_provider.gather().context("Error gathering facts")?;
+        Ok(self.facts.get_or_init(|| facts))
+    }
+
+    /// Executes a section, honouring its `enabled` flag and its condition.
+    pub fn execute_section<P: AsRef<Path>>(&self, root_dir: P, name: &str, section: &Section) -> Result<()> {
         let root_dir = root_dir.as_ref();
-        if self.dry_run {
-            info!("Using root_dir: {}", root_dir.display());
-        } else {
-            debug!("Using root_dir: {}", root_dir.display());
+        if !section.enabled {
+            info!("Skipping section {} as it is disabled", name);
+            return Ok(());
         }
 
-        for directive in directives {
+        let facts = self.facts()?;
+        let mismatch = section
+            .condition
+            .mismatch(facts, root_dir, &self.filesystem)
+            .context(format!("Error checking the condition of section {}", name))?;
+        if let Some(reason) = mismatch {
+            info!("Skipping section {}: {}", name, reason);
+            return Ok(());
+        }
+
+        match &section.description {
+            Some(description) => info!("Executing section {}: {}", name, description),
+            None => debug!("Executing section {}", name),
+        }
+        self.execute_steps(root_dir, name, section.on_error, section.conflict_strategy, &section.steps)
+    }
+
+    pub fn execute<P: AsRef<Path>>(&self, root_dir: P, section: &str, directives: &[DirectiveStep]) -> Result<()> {
+        self.execute_steps(root_dir.as_ref(), section, None, None, directives)
+    }
+
+    /// Directives that failed so far without stopping the run, because they were allowed to carry on.
+    pub fn failures(&self) -> Ref<'_, Vec<Failure>> {
+        self.failures.borrow()
+    }
+
+    fn execute_steps(
+        &self,
+        root_dir: &Path,
+        section: &str,
+        on_error: Option<OnError>,
+        conflict_strategy: Option<ConflictStrategy>,
+        directives: &[DirectiveStep],
+    ) -> Result<()> {
+        debug!("Using root_dir: {}", root_dir.display());
+
+        for (index, directive) in directives.iter().enumerate() {
             debug!("Executing [section={}] [directive={:?}]", section, directive);
-            self.execute_directive(root_dir, directive)?;
+            let strategy = self.effective_strategy(conflict_strategy, directive);
+            let error = match self.execute_directive(root_dir, section, index, directive, strategy) {
+                Ok(()) => continue,
+                Err(e) => e,
+            };
+            // The most specific setting wins
+            let on_error = directive.on_error.or(on_error).unwrap_or(match self.keep_going {
+                true => OnError::Continue,
+                false => OnError::Abort,
+            });
+            debug!("Directive failed [section={}] [index={}] [on_error={}]", section, index, on_error);
+            match on_error {
+                OnError::Abort => return Err(error),
+                OnError::Continue => {
+                    error!("Error executing [section={}] [index={}]: {:#}", section, index, error);
+                    self.failures.borrow_mut().push(Failure {
+                        section: section.to_string(),
+                        index,
+                        error,
+                    });
+                }
+            }
         }
 
         info!("Executed section {}", section);
         Ok(())
     }
 
-    fn execute_directive(&self, root_dir: &Path, directive: &DirectiveStep) -> Result<()> {
-        match &directive.condition {
-            Condition::Always => debug!("Directive has no condition. Executing"),
-            Condition::IfOs(os) => {
-                let current_os = self.os_detector.get_os().context("Error detecting current OS")?;
-                if &current_os == os {
-                    debug!("OS match. Executing");
-                } else {
-                    debug!("OS does not match. Not executing");
-                    return Ok(());
+    /// Everything that would have been done so far, in dry-run mode.
+    pub fn dry_run_plan(&self) -> Ref<'_, Plan> {
+        self.pending.borrow()
+    }
+
+    /// Plans the `requested` sections (or all of them, if None) without changing anything.
+    ///
+    /// Sections and directives whose conditions do not hold on this machine are left out of the plan.
+    pub fn plan<P: AsRef<Path>>(&self, root_dir: P, config: &StateConfig, requested: Option<&[&str]>) -> Result<Plan> {
+        let mut plan = Plan::default();
+        self.plan_into(root_dir.as_ref(), config, requested, None, &mut plan)?;
+        Ok(plan)
+    }
+
+    /// Plans like [`Executor::plan`], but resolving every conflict with `strategy` instead of the configured ones.
+    pub(crate) fn plan_with_strategy(
+        &self,
+        root_dir: &Path,
+        config: &StateConfig,
+        requested: Option<&[&str]>,
+        strategy: ConflictStrategy,
+    ) -> Result<Plan> {
+        let mut plan = Plan::default();
+        self.plan_into(root_dir, config, requested, Some(strategy), &mut plan)?;
+        Ok(plan)
+    }
+
+    /// Appends to `plan` the operations of the `requested` sections, planning them on top of what `plan` already does.
+    /// If `forced` is set, every conflict is resolved with it.
+    fn plan_into(
+        &self,
+        root_dir: &Path,
+        config: &StateConfig,
+        requested: Option<&[&str]>,
+        forced: Option<ConflictStrategy>,
+        plan: &mut Plan,
+    ) -> Result<()> {
+        let order = config.execution_order(requested).context("Error resolving section dependencies")?;
+        for name in order {
+            let section = &config.states[name];
+            let facts = self.facts()?;
+            if !section.enabled
+                || section
+                    .condition
+                    .mismatch(facts, root_dir, &self.filesystem)
+                    .context(format!("Error checking the condition of section {}", name))?
+                    .is_some()
+            {
+                debug!("Not planning section {} as it would not be executed", name);
+                continue;
+            }
+            for (index, step) in section.steps.iter().enumerate() {
+                let mismatch = step
+                    .condition
+                    .mismatch(facts, root_dir, &self.filesystem)
+                    .context(format!("Error checking the condition of [section={}] [index={}]", name, index))?;
+                if mismatch.is_some() {
+                    debug!("Not planning [section={}] [index={}] as it would not be executed", name, index);
+                    continue;
                 }
+                let strategy = forced.unwrap_or_else(|| self.effective_strategy(section.conflict_strategy, step));
+                self.plan_directive(root_dir, name, &step.directive, strategy, forced, plan)
+                    .context(format!("Error planning [section={}] [index={}]", name, index))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies every operation of `plan`, in order. Operations are not checked again before applying them.
+    pub fn apply(&self, plan: &Plan) -> Result<()> {
+        for planned in &plan.operations {
+            debug!("Applying [section={}] [operation={:?}]", planned.section, planned.operation);
+            self.apply_operation(&planned.section, &planned.operation)?;
+        }
+        Ok(())
+    }
+
+    /// The conflict strategy of a directive. The most specific setting wins.
+    fn effective_strategy(&self, section_strategy: Option<ConflictStrategy>, step: &DirectiveStep) -> ConflictStrategy {
+        step.conflict_strategy.or(section_strategy).unwrap_or(self.conflict_strategy)
+    }
+
+    fn execute_directive(
+        &self,
+        root_dir: &Path,
+        section: &str,
+        index: usize,
+        directive: &DirectiveStep,
+        strategy: ConflictStrategy,
+    ) -> Result<()> {
+        let facts = self.facts()?;
+        let mismatch = directive
+            .condition
+            .mismatch(facts, root_dir, &self.filesystem)
+            .context(format!("Error checking the condition of [section={}] [index={}]", section, index))?;
+        match mismatch {
+            None => debug!("Directive condition matches. Executing"),
+            Some(reason) => {
+                info!("Skipping [section={}] [index={}]: {}", section, index, reason);
+                return Ok(());
+            }
+        }
+
+        if let Directive::Include(path) = &directive.directive {
+            // Included sections are executed one by one, so each of them sees what the previous ones did
+            debug!("Include directive [path={}]", path);
+            return self.include(root_dir, path);
+        }
+
+        if self.dry_run {
+            // Nothing is applied, so later directives have to be planned on top of this one
+            let mut pending = self.pending.borrow_mut();
+            let first = pending.operations.len();
+            self.plan_directive(root_dir, section, &directive.directive, strategy, None, &mut pending)?;
+            for planned in &pending.operations[first..] {
+                info!("Would {}", planned.operation);
             }
+            Ok(())
+        } else {
+            let mut plan = Plan::default();
+            self.plan_directive(root_dir, section, &directive.directive, strategy, None, &mut plan)?;
+            self.apply(&plan)
         }
+    }
 
-        match &directive.directive {
+    /// Plans a single directive. `forced` is only passed on to the sections of included files.
+    fn plan_directive(
+        &self,
+        root_dir: &Path,
+        section: &str,
+        directive: &Directive,
+        strategy: ConflictStrategy,
+        forced: Option<ConflictStrategy>,
+        plan: &mut Plan,
+    ) -> Result<()> {
+        match directive {
             Directive::Link {
                 from,
                 to,
                 directory_behaviour,
             } => {
                 debug!(
-                    "Link directive [from={}] [to={}] [behaviour={}]",
+                    "Link directive [from={}] [to={}] [behaviour={}] [conflict_strategy={}]",
                     from,
                     to,
-                    directory_behaviour.to_string()
+                    directory_behaviour.to_string(),
+                    strategy
                 );
-                self.execute_symlink(root_dir, from, to, directory_behaviour)?;
+                self.plan_symlink(root_dir, section, from, to, directory_behaviour, strategy, plan)?;
             }
             Directive::Copy { from, to } => {
-                debug!("Copy directive [from={}] [to={}]", from, to);
-                self.execute_copy(root_dir, from, to)?;
+                debug!("Copy directive [from={}] [to={}] [conflict_strategy={}]", from, to, strategy);
+                self.plan_copy(root_dir, section, from, to, strategy, plan)?;
             }
             Directive::Run(cmd) => {
                 debug!("Run directive [cmd={}]", cmd);
-                self.run(root_dir, cmd)?;
+                self.plan_run(root_dir, section, cmd, plan)?;
             }
             Directive::Include(path) => {
                 debug!("Include directive [path={}]", path);
-                self.include(root_dir, path)?;
+                let (yaml_path, config) = self.load_include(root_dir, path)?;
+                let included_root_dir = yaml_path.parent().unwrap_or(root_dir);
+                self.plan_into(included_root_dir, &config, None, forced, plan)
+                    .context(format!("Error planning directives from file {}", yaml_path.display()))?;
             }
             Directive::Template { template, dest, vars } => {
-                debug!("Template directive [template={}] [dest={}] [vars={:?}]", template, dest, vars);
-                self.template(root_dir, template, dest, vars)?;
+                debug!(
+                    "Template directive [template={}] [dest={}] [vars={:?}] [conflict_strategy={}]",
+                    template, dest, vars, strategy
+                );
+                self.plan_template(root_dir, section, template, dest, vars, strategy, plan)?;
             }
         }
 
         Ok(())
     }
 
-    fn execute_symlink(&self, root_dir: &Path, from: &str, to: &str, behaviour: &LinkDirectoryBehaviour) -> Result<()> {
+    #[allow(clippy::too_many_arguments)]
+    fn plan_symlink(
+        &self,
+        root_dir: &Path,
+        section: &str,
+        from: &str,
+        to: &str,
+        behaviour: &LinkDirectoryBehaviour,
+        strategy: ConflictStrategy,
+        plan: &mut Plan,
+    ) -> Result<()> {
         let paths = self
-            .get_paths_to_process(root_dir, from, to)
+            .get_paths_to_process(root_dir, section, from, to, plan)
             .context("Error obtaining paths to process")?;
         let remove_dirs = behaviour.ne(&LinkDirectoryBehaviour::CreateDirectory);
         for (from, to) in paths {
-            let (from_path, to_path) = self
-                .check_for_conflicts(root_dir, &from, &to, remove_dirs)
+            let from_path = root_dir.join(&from);
+            let from_is_dir = self.filesystem.is_dir(&from_path);
+            let desired = match (from_is_dir, behaviour) {
+                (true, LinkDirectoryBehaviour::IgnoreDirectories) => {
+                    debug!(
+                        "Skipping dir {} as LinkDirectoryBehaviour is set to IgnoreDirectories",
+                        from_path.display()
+                    );
+                    continue;
+                }
+                (true, LinkDirectoryBehaviour::CreateDirectory) => Desired::Directory,
+                _ => Desired::Symlink,
+            };
+            let checked = self
+                .check_for_conflicts(root_dir, section, &from, &to, remove_dirs, desired, strategy, plan)
                 .context("Error in symlink prerequirements")?;
-            if from_path.is_dir() {
-                match behaviour {
-                    LinkDirectoryBehaviour::IgnoreDirectories => {
-                        if self.dry_run {
-                            info!(
-                                "Skipping dir {} as LinkDirectoryBehaviour is set to IgnoreDirectories",
-                                from_path.display()
-                            );
-                        } else {
-                            debug!(
-                                "Skipping dir {} as LinkDirectoryBehaviour is set to IgnoreDirectories",
-                                from_path.display()
-                            );
-                        }
-                    }
-                    LinkDirectoryBehaviour::LinkDirectory => {
-                        if self.dry_run {
-                            info!("Would symlink dir {} -> {}", from_path.display(), to_path.display());
-                        } else {
-                            symlink::symlink_dir(&from_path, &to_path).context(format!(
-                                "Error symlinking dir {} -> {}",
-                                from_path.display(),
-                                to_path.display()
-                            ))?;
-                            info!("Symlinked dir {} -> {}", from_path.display(), to_path.display());
-                        }
-                    }
-                    LinkDirectoryBehaviour::CreateDirectory => {
-                        if !to_path.exists() {
-                            if self.dry_run {
-                                info!(
-                                    "Would create dir {} as LinkDirectoryBehaviour is set to CreateDirectory",
-                                    to_path.display()
-                                );
-                            } else {
-                                debug!(
-                                    "Creating dir {} as LinkDirectoryBehaviour is set to CreateDirectory",
-                                    to_path.display()
-                                );
-                                std::fs::create_dir(&to_path).context(format!("Error creating directory {}", to_path.display()))?;
-                                info!("Created dir {}", to_path.display());
-                            }
-                        } else if self.dry_run {
-                            info!("To path already exists, no need to do anything {}", to_path.display());
-                        } else {
-                            debug!("To path already exists, no need to do anything {}", to_path.display());
-                        }
 
-                        // Now recurse in files inside from
-                        let from_files =
-                            std::fs::read_dir(&from_path).context(format!("Error getting dir contents of {}", from_path.display()))?;
-                        for entry in from_files {
-                            let entry = entry.context(format!("Error getting entry of dir {}", from_path.display()))?;
-                            let entry = entry.path();
-                            let entry_without_prefix = entry
-                                .strip_prefix(root_dir)
-                                .context(format!(
-                                    "Error stripping prefix from entry [entry={}] [prefix={}]",
-                                    entry.display(),
-                                    root_dir.display()
-                                ))?
-                                .to_path_buf();
-                            let from_path = entry_without_prefix.display().to_string();
-                            let from_filename = match entry.file_name() {
-                                Some(f) => match f.to_str() {
-                                    Some(filename) => filename.to_string(),
-                                    None => return Err(anyhow!("Cannot convert to str {:?}", f)),
-                                },
-                                None => return Err(anyhow!("Cannot obtain filename from {}", entry.display())),
-                            };
-                            let to_path = format!("{}/{}", to, from_filename);
-                            self.execute_symlink(root_dir, &from_path, &to_path, behaviour)?;
-                        }
+            if from_is_dir && behaviour == &LinkDirectoryBehaviour::CreateDirectory {
+                if checked.is_none() && !Overlay::new(&self.filesystem, plan).is_dir(&resolve_destination(root_dir, &to)) {
+                    // It was skipped, so there is no directory to recurse into
+                    continue;
+                }
+                match checked {
+                    Some((_, to_path)) if !Overlay::new(&self.filesystem, plan).exists(&to_path) => {
+                        debug!(
+                            "Creating dir {} as LinkDirectoryBehaviour is set to CreateDirectory",
+                            to_path.display()
+                        );
+                        plan.push(section, Operation::CreateDir { path: to_path });
                     }
+                    _ => debug!("To path already exists, no need to do anything {}", to),
                 }
-            } else if self.dry_run {
-                info!("Would symlink file {} -> {}", from_path.display(), to_path.display());
-            } else {
-                symlink::symlink_file(&from_path, &to_path).context(format!(
-                    "Error symlinking file {} -> {}",
-                    from_path.display(),
-                    to_path.display()
-                ))?;
-                info!("Symlinked file {} -> {}", from_path.display(), to_path.display());
+
+                // Now recurse in files inside from
+                let from_files = self
+                    .filesystem
+                    .read_dir(&from_path)
+                    .context(format!("Error getting dir contents of {}", from_path.display()))?;
+                for entry in from_files {
+                    let entry_without_prefix = entry
+                        .strip_prefix(root_dir)
+                        .context(format!(
+                            "Error stripping prefix from entry [entry={}] [prefix={}]",
+                            entry.display(),
+                            root_dir.display()
+                        ))?
+                        .to_path_buf();
+                    let from_path = entry_without_prefix.display().to_string();
+                    let from_filename = match entry.file_name() {
+                        Some(f) => match f.to_str() {
+                            Some(filename) => filename.to_string(),
+                            None => return Err(anyhow!("Cannot convert to str {:?}", f)),
+                        },
+                        None => return Err(anyhow!("Cannot obtain filename from {}", entry.display())),
+                    };
+                    let to_path = format!("{}/{}", to, from_filename);
+                    self.plan_symlink(root_dir, section, &from_path, &to_path, behaviour, strategy, plan)?;
+                }
+                continue;
+            }
+
+            if let Some((from_path, to_path)) = checked {
+                plan.push(
+                    section,
+                    Operation::Symlink {
+                        directory: from_is_dir,
+                        source: from_path,
+                        destination: to_path,
+                    },
+                );
             }
         }
 
         Ok(())
     }
 
-    fn execute_copy(&self, root_dir: &Path, from: &str, to: &str) -> Result<()> {
+    fn plan_copy(&self, root_dir: &Path, section: &str, from: &str, to: &str, strategy: ConflictStrategy, plan: &mut Plan) -> Result<()> {
         let paths = self
-            .get_paths_to_process(root_dir, from, to)
+            .get_paths_to_process(root_dir, section, from, to, plan)
             .context("Error obtaining paths to process")?;
         for (from, to) in paths {
-            let (from, to) = self
-                .check_for_conflicts(root_dir, &from, &to, true)
-                .context("Error in copy prerequirements")?;
-            if from.is_dir() {
-                if self.dry_run {
-                    info!("Would copy dir {} -> {}", from.display(), to.display());
-                } else {
-                    fs_extra::dir::copy(
-                        &from,
-                        &to,
-                        &CopyOptions {
-                            overwrite: true,
-                            ..CopyOptions::default()
-                        },
-                    )
-                    .context(format!("Error copying dir {} -> {}", from.display(), to.display()))?;
-                    info!("Copied dir {} -> {}", from.display(), to.display());
+            let (from, to) = match self
+                .check_for_conflicts(root_dir, section, &from, &to, true, Desired::Copy, strategy, plan)
+                .context("Error in copy prerequirements")?
+            {
+                Some(paths) => paths,
+                None => continue,
+            };
+            if self.filesystem.is_dir(&from) {
+                // Directories are copied inside the destination, which is either missing or about to be cleared
+                let copied_root = to.join(from.file_name().unwrap_or_default());
+                plan.push(section, Operation::CreateDir { path: to.clone() });
+                plan.push(section, Operation::CreateDir { path: copied_root.clone() });
+                for entry in tree_entries(&self.filesystem, &from)? {
+                    let operation = if self.filesystem.is_dir(&from.join(&entry)) {
+                        Operation::CreateDir {
+                            path: copied_root.join(&entry),
+                        }
+                    } else {
+                        Operation::Copy {
+                            source: from.join(&entry),
+                            destination: copied_root.join(&entry),
+                        }
+                    };
+                    plan.push(section, operation);
                 }
-            } else if self.dry_run {
-                info!("Would copy file {} -> {}", from.display(), to.display());
             } else {
-                debug!("Copying {} -> {}", from.display(), to.display());
-                std::fs::copy(&from, &to).context(format!("Error copying file {} -> {}", from.display(), to.display()))?;
-                info!("Copied file {} -> {}", from.display(), to.display());
+                plan.push(
+                    section,
+                    Operation::Copy {
+                        source: from,
+                        destination: to,
+                    },
+                );
             }
         }
 
         Ok(())
     }
 
-    fn get_paths_to_process(&self, root_dir: &Path, from: &str, to: &str) -> Result<Vec<(String, String)>> {
+    fn get_paths_to_process(&self, root_dir: &Path, section: &str, from: &str, to: &str, plan: &mut Plan) -> Result<Vec<(String, String)>> {
         let mut paths = vec![];
-        let to_dest = if to.contains('~') {
-            PathBuf::from(shellexpand::tilde(to).to_string())
-        } else {
-            root_dir.join(to)
-        };
+        let to_dest = resolve_destination(root_dir, to);
         if !is_glob(from) {
             paths.push((from.to_string(), to_dest.display().to_string()));
         } else {
             debug!("Detected from is glob: {}", from);
-            if !to_dest.exists() {
+            let overlay = Overlay::new(&self.filesystem, plan);
+            if !overlay.exists(&to_dest) {
                 // If we have been asked to copy a glob of files to a dir that does not exist, create the dir
-                if self.dry_run {
-                    info!("Would have created dir {}", to_dest.display());
-                } else {
-                    debug!("Creating dir {}", to_dest.display());
-                    std::fs::create_dir_all(&to_dest).context(format!("Error creating directory {}", to_dest.display()))?;
-                }
-            } else if !to_dest.is_dir() {
+                debug!("Creating dir {}", to_dest.display());
+                plan_create_dir_all(&self.filesystem, section, &to_dest, plan);
+            } else if !overlay.is_dir(&to_dest) {
                 return Err(anyhow!("Asked to copy into a path that is not a directory"));
             }
 
-            let full_glob = root_dir.join(from).display().to_string();
-            debug!("Detected from is glob {} | Will use {}", from, full_glob);
-            let glob_iter = glob::glob(&full_glob).context(format!("Error obtaining iterator from glob {}", full_glob))?;
-            for entry in glob_iter {
-                let entry = entry.context("Error obtaining glob entry")?;
-                let entry_without_prefix = entry
-                    .strip_prefix(root_dir)
-                    .context("Error stripping prefix from glob")?
-                    .to_path_buf();
-                let from_path = entry_without_prefix.display().to_string();
-                let from_filename = match entry.file_name() {
-                    Some(f) => match f.to_str() {
-                        Some(filename) => filename.to_string(),
-                        None => return Err(anyhow!("Cannot convert to str {:?}", f)),
-                    },
-                    None => return Err(anyhow!("Cannot obtain filename from {}", entry.display())),
-                };
-                let to_path = format!("{}/{}", to, from_filename);
-                paths.push((from_path, to_path));
-            }
+            paths.extend(expand_glob(&self.filesystem, root_dir, from, to)?);
         }
         Ok(paths)
     }
 
-    fn check_for_conflicts(&self, root_dir: &Path, from: &str, to: &str, delete_if_dir: bool) -> Result<(PathBuf, PathBuf)> {
+    /// Plans making room for `to`, applying the conflict strategy if it already exists.
+    ///
+    /// Returns None if `to` is already in the desired state, so there is nothing to do.
+    #[allow(clippy::too_many_arguments)]
+    fn check_for_conflicts(
+        &self,
+        root_dir: &Path,
+        section: &str,
+        from: &str,
+        to: &str,
+        delete_if_dir: bool,
+        desired: Desired,
+        strategy: ConflictStrategy,
+        plan: &mut Plan,
+    ) -> Result<Option<(PathBuf, PathBuf)>> {
         // Check if from file exists
         let from_path = root_dir.join(from);
-        let to_path = if to.contains('~') {
-            PathBuf::from(shellexpand::tilde(to).to_string())
-        } else {
-            root_dir.join(to)
-        };
+        let to_path = resolve_destination(root_dir, to);
         debug!("Checking if 'from' exists: {}", from_path.display());
 
-        if !from_path.exists() {
+        if !self.filesystem.exists(&from_path) {
             return Err(anyhow!("From does not exist: {}", from_path.display()));
         }
 
         // Check if to already exists
         debug!("Checking if 'to' exists: {}", to_path.display());
-        let mut to_already_exists = to_path.exists();
+        let overlay = Overlay::new(&self.filesystem, plan);
+        let mut to_already_exists = overlay.exists(&to_path);
         if !to_already_exists {
             debug!(
                 "Detected 'to' does not exist. Checking if is a broken symlink {}",
                 to_path.display()
             );
             // Check for broken symlink
-            if std::fs::symlink_metadata(&to_path).is_ok() {
+            if overlay.symlink_exists(&to_path) {
                 debug!("Detected 'to' is a broken symlink {}", to_path.display());
                 to_already_exists = true;
             }
         }
 
+        if to_already_exists && overlay.is_in_desired_state(&from_path, &to_path, &desired) {
+            let kind = match desired {
+                Desired::Symlink => EntryKind::Symlink,
+                Desired::Directory => EntryKind::Directory,
+                Desired::Copy => EntryKind::Copy,
+                Desired::Contents(_) => EntryKind::Template,
+            };
+            plan.push(
+                section,
+                Operation::Unchanged {
+                    kind,
+                    source: from_path,
+                    destination: to_path,
+                },
+            );
+            return Ok(None);
+        }
+
         if to_already_exists {
             debug!("'to' exists: {}", to_path.display());
 
             // To already exists. Check conflict strategy
-            match &self.conflict_strategy {
-                ConflictStrategy::Abort => {
+            let strategy = match strategy {
+                ConflictStrategy::Prompt => self.resolve_conflict(section, &from_path, &to_path, &desired)?,
+                strategy => strategy,
+            };
+            match strategy {
+                // The fallback of prompt is never prompt itself
+                ConflictStrategy::Abort | ConflictStrategy::Prompt => {
                     warn!("ConflictStrategy set to abort. Aborting");
                     return Err(anyhow!(
                         "'to' {} already exists and ConflictStrategy is set to abort",
                         to_path.display()
                     ));
                 }
+                ConflictStrategy::Skip => {
+                    info!("Skipping {} as it already exists", to_path.display());
+                    plan.push(
+                        section,
+                        Operation::Skip {
+                            source: from_path,
+                            destination: to_path,
+                        },
+                    );
+                    return Ok(None);
+                }
+                ConflictStrategy::Adopt => {
+                    debug!("ConflictStrategy set to adopt. Moving {} into the repo", to_path.display());
+                    check_adoptable(&overlay, &from_path, &to_path, &desired)?;
+                    plan.push(
+                        section,
+                        Operation::Adopt {
+                            path: to_path.clone(),
+                            source: from_path.clone(),
+                        },
+                    );
+                    return Ok(Some((from_path, to_path)));
+                }
+                ConflictStrategy::Backup => {
+                    debug!("ConflictStrategy set to backup. Moving {} into the backup store", to_path.display());
+                    let store = self
+                        .backup_store
+                        .as_ref()
+                        .ok_or_else(|| anyhow!("ConflictStrategy is set to backup, but there is no backup store"))?;
+                    let mut backup = store.path_for(&to_path);
+                    let mut counter = 0;
+                    while overlay.symlink_exists(&backup) {
+                        // The same path was already backed up by this run
+                        counter += 1;
+                        backup = PathBuf::from(format!("{}.{}", store.path_for(&to_path).display(), counter));
+                    }
+                    plan.push(
+                        section,
+                        Operation::StoreBackup {
+                            path: to_path.clone(),
+                            backup,
+                        },
+                    );
+                    return Ok(Some((from_path, to_path)));
+                }
                 ConflictStrategy::RenameOld => {
                     debug!("ConflictStrategy set to rename-old. Renaming old");
 
@@ -354,87 +763,27 @@ where
 
                         let to_path_bak = Path::new(&to_path_clone);
                         debug!("Checking if backup already exists");
-                        if !to_path_bak.exists() {
+                        if !overlay.exists(to_path_bak) {
                             break to_path_clone;
                         }
                         counter += 1;
                     };
 
-                    let backup_path = Path::new(&backup_path);
-                    if self.dry_run {
-                        info!("Would move [src={}] [dst={}]", to_path.display(), backup_path.display());
-                    } else {
-                        warn!("Moving [src={}] -> [dst={}]", to_path.display(), backup_path.display());
-                        std::fs::rename(&to_path, backup_path).context(format!(
-                            "Error renaming [src={}] -> [dst={}]",
-                            to_path.display(),
-                            backup_path.display()
-                        ))?;
-                    }
-
-                    return Ok((from_path, to_path));
+                    plan.push(
+                        section,
+                        Operation::Backup {
+                            path: to_path.clone(),
+                            backup: PathBuf::from(backup_path),
+                        },
+                    );
+                    return Ok(Some((from_path, to_path)));
                 }
                 ConflictStrategy::Overwrite => {
-                    if to_path.is_symlink() {
-                        if to_path.is_file() {
-                            if self.dry_run {
-                                info!("Would remove file symlink {}", to_path.display());
-                            } else {
-                                warn!("Removing file symlink {}", to_path.display());
-                                symlink::remove_symlink_file(&to_path)
-                                    .context(format!("Error removing file symlink {}", to_path.display()))?;
-                            }
-                        } else if to_path.is_dir() {
-                            if delete_if_dir {
-                                if self.dry_run {
-                                    info!("Would remove dir symlink {}", to_path.display());
-                                } else {
-                                    warn!("Removing dir symlink {}", to_path.display());
-                                    symlink::remove_symlink_dir(&to_path)
-                                        .context(format!("Error removing dir symlink {}", to_path.display()))?;
-                                }
-                            } else if self.dry_run {
-                                info!(
-                                    "Would not remove dir symlink as is specified in configuration {}",
-                                    to_path.display()
-                                );
-                            } else {
-                                debug!("Not removing dir symlink as is specified in configuration {}", to_path.display());
-                            }
-                        } else {
-                            // Probably a broken symlink if is neither a file nor a dir
-                            if self.dry_run {
-                                info!("Would remove broken symlink {}", to_path.display());
-                            } else {
-                                warn!("Removing broken symlink {}", to_path.display());
-                                symlink::remove_symlink_file(&to_path).context("Error removing broken symlink")?;
-                            }
-                        }
-                    } else if to_path.is_file() {
-                        if self.dry_run {
-                            info!("Would remove file {}", to_path.display());
-                        } else {
-                            warn!("Removing file {}", to_path.display());
-                            std::fs::remove_file(&to_path).context(format!("Error removing file {}", to_path.display()))?;
-                        }
-                    } else if to_path.is_dir() {
-                        if delete_if_dir {
-                            if self.dry_run {
-                                info!("Would remove dir {}", to_path.display());
-                            } else {
-                                warn!("Removing dir {}", to_path.display());
-                                std::fs::remove_dir_all(&to_path).context(format!("Error removing dir {}", to_path.display()))?;
-                            }
-                        } else if self.dry_run {
-                            info!("Would not remove dir as is specified in configuration {}", to_path.display());
-                        } else {
-                            debug!("Not removing dir as is specified in configuration {}", to_path.display());
-                        }
-                    } else if self.dry_run {
-                        info!("Would remove dir {}", to_path.display());
+                    let is_dir = overlay.is_dir(&to_path);
+                    if is_dir && !delete_if_dir {
+                        debug!("Not removing dir as is specified in configuration {}", to_path.display());
                     } else {
-                        warn!("Removing dir {}", to_path.display());
-                        std::fs::remove_dir_all(&to_path).context(format!("Error removing dir {}", to_path.display()))?;
+                        plan.push(section, Operation::Remove { path: to_path.clone() });
                     }
                 }
             }
@@ -442,79 +791,156 @@ where
             debug!("To {} does not exist", to_path.display());
             // Check if parent dir structure exists
             if let Some(parent) = to_path.parent() {
-                if !parent.exists() {
-                    if self.dry_run {
-                        info!("As parent dir does not exist, would have created {}", parent.display());
-                    } else {
-                        debug!("Creating parent dir structure {}", parent.display());
-                        std::fs::create_dir_all(parent).context(format!("Error creating parent dir structure {}", parent.display()))?;
-                    }
+                if !overlay.exists(parent) {
+                    debug!("Creating parent dir structure {}", parent.display());
+                    plan_create_dir_all(&self.filesystem, section, parent, plan);
                 }
             }
         }
 
-        Ok((from_path, to_path))
+        Ok(Some((from_path, to_path)))
     }
 
-    fn run(&self, root_dir: &Path, cmd: &str) -> Result<()> {
-        let current_dir = Path::new(root_dir);
-        let shell_args = self.shell.split(' ').collect::<Vec<&str>>();
-        if shell_args.is_empty() {
-            return Err(anyhow!("Cannot run commands with an empty shell definition"));
-        }
-
-        let mut command = Command::new(shell_args[0]);
-        for arg in shell_args.iter().skip(1) {
-            command.arg(arg);
+    /// Asks the prompter how to resolve a conflict, returning the strategy to apply.
+    fn resolve_conflict(&self, section: &str, from: &Path, to: &Path, desired: &Desired) -> Result<ConflictStrategy> {
+        if self.dry_run || !self.prompter.is_interactive() {
+            debug!(
+                "Nobody to ask about {}, using prompt_fallback {}",
+                to.display(),
+                self.prompt_fallback
+            );
+            return Ok(self.prompt_fallback);
         }
-        command.arg(cmd).current_dir(current_dir);
 
-        if self.dry_run {
-            info!(
-                "Would run [current_dir={}]: {:?} {:?}",
-                root_dir.display(),
-                command.get_program(),
-                command.get_args()
-            );
-        } else {
-            debug!("Command to be executed: {:?} {:?}", command.get_program(), command.get_args());
-            let mut exec = command.spawn().context("Error invoking subcommand")?;
-            let exit_status = exec.wait().context("Error waiting for subcommand to finish")?;
-            if let Some(code) = exit_status.code() {
-                debug!("Command exit status: {}", code);
-                if code != 0 {
-                    return Err(anyhow!(
-                        "Command exit status was not 0. Exit status: {} | Command: {:?} {:?}",
-                        exit_status,
-                        command.get_program(),
-                        command.get_args()
-                    ));
+        let answer = match self.answer_for_all.get() {
+            Some(answer) => answer,
+            None => {
+                let conflict = Conflict {
+                    section: section.to_string(),
+                    source: from.to_path_buf(),
+                    destination: to.to_path_buf(),
+                    existing: self.existing(to)?,
+                    diff: self.conflict_diff(from, to, desired),
+                };
+                let (answer, all) = self
+                    .prompter
+                    .ask(&conflict)
+                    .context(format!("Error asking how to resolve conflict in {}", to.display()))?;
+                if all {
+                    self.answer_for_all.set(Some(answer));
                 }
+                answer
             }
+        };
+        debug!("Resolving conflict in {} with {:?}", to.display(), answer);
+        Ok(match answer {
+            Answer::Overwrite => ConflictStrategy::Overwrite,
+            Answer::Backup => ConflictStrategy::Backup,
+            Answer::Skip => ConflictStrategy::Skip,
+        })
+    }
+
+    fn existing(&self, path: &Path) -> Result<Existing> {
+        let metadata = self
+            .filesystem
+            .symlink_metadata(path)
+            .context(format!("Error reading metadata of {}", path.display()))?;
+        Ok(if metadata.is_symlink() {
+            let target = self
+                .filesystem
+                .read_link(path)
+                .context(format!("Error reading symlink {}", path.display()))?;
+            Existing::Symlink(target)
+        } else if metadata.is_dir() {
+            Existing::Directory
+        } else {
+            Existing::File
+        })
+    }
+
+    /// Unified diff from `to` to what a copy or template would write into it, if both are text files.
+    fn conflict_diff(&self, from: &Path, to: &Path, desired: &Desired) -> Option<String> {
+        let incoming = match desired {
+            Desired::Copy if self.filesystem.is_file(from) => String::from_utf8(self.filesystem.read(from).ok()?).ok()?,
+            Desired::Contents(contents) => contents.to_string(),
+            _ => return None,
+        };
+        if !self.filesystem.symlink_metadata(to).ok()?.is_file() {
+            return None;
+        }
+        let current = String::from_utf8(self.filesystem.read(to).ok()?).ok()?;
+        let diff = TextDiff::from_lines(&current, &incoming)
+            .unified_diff()
+            .header(&to.display().to_string(), &from.display().to_string())
+            .to_string();
+        Some(diff)
+    }
 
-            info!("Executed command {}", cmd);
+    fn plan_run(&self, root_dir: &Path, section: &str, cmd: &str, plan: &mut Plan) -> Result<()> {
+        let shell_args = self.shell.split(' ').collect::<Vec<&str>>();
+        if shell_args.is_empty() {
+            return Err(anyhow!("Cannot run commands with an empty shell definition"));
         }
 
+        let mut args: Vec<String> = shell_args.iter().skip(1).map(|a| a.to_string()).collect();
+        args.push(cmd.to_string());
+        plan.push(
+            section,
+            Operation::Run {
+                command: cmd.to_string(),
+                program: shell_args[0].to_string(),
+                args,
+                current_dir: root_dir.to_path_buf(),
+            },
+        );
         Ok(())
     }
 
-    fn include(&self, root_dir: &Path, path: &str) -> Result<()> {
-        let yaml_path = root_dir.join(path);
-        if !yaml_path.exists() {
-            return Err(anyhow!("Could not file yaml to include in path {}", yaml_path.display()));
+    fn run(&self, program: &str, args: &[String], current_dir: &Path, cmd: &str) -> Result<()> {
+        let invocation = CommandInvocation {
+            program: program.to_string(),
+            args: args.to_vec(),
+            current_dir: current_dir.to_path_buf(),
+        };
+
+        debug!("Command to be executed: {:?} {:?}", invocation.program, invocation.args);
+        let output = self.command_runner.run(&invocation).context("Error invoking subcommand")?;
+        // Only runners that capture the output instead of letting the command write it into the terminal have any
+        for line in output.stdout.lines() {
+            info!("[cmd={}] [stdout] {}", cmd, line);
+        }
+        for line in output.stderr.lines() {
+            warn!("[cmd={}] [stderr] {}", cmd, line);
+        }
+        if let Some(code) = output.exit_code {
+            debug!("Command exit status: {}", code);
+            if code != 0 {
+                return Err(anyhow!(
+                    "Command exit status was not 0. Exit status: {} | Command: {:?} {:?}",
+                    code,
+                    invocation.program,
+                    invocation.args
+                ));
+            }
         }
 
-        let contents = std::fs::read_to_string(&yaml_path).context(format!("Error loading included file {}", yaml_path.display()))?;
-        let config = StateConfig::from_yaml(&contents).context(format!("Error parsing included file {}", yaml_path.display()))?;
+        info!("Executed command {}", cmd);
+        Ok(())
+    }
 
+    fn include(&self, root_dir: &Path, path: &str) -> Result<()> {
+        let (yaml_path, config) = self.load_include(root_dir, path)?;
         let included_root_dir = match yaml_path.parent() {
             Some(p) => p,
             None => root_dir,
         };
         debug!("Using root_dir: {}", included_root_dir.display());
 
-        for (section, directives) in config.states {
-            self.execute(included_root_dir, &section, &directives)
+        let order = config
+            .execution_order(None)
+            .context(format!("Error resolving section order of file {}", yaml_path.display()))?;
+        for name in order {
+            self.execute_section(included_root_dir, name, &config.states[name])
                 .context(format!("Error executing directives from file {}", yaml_path.display()))?;
         }
 
@@ -523,39 +949,564 @@ where
         Ok(())
     }
 
-    fn template(&self, root_dir: &Path, template: &str, dest: &str, vars: &Option<String>) -> Result<()> {
-        let (template, dest) = self
-            .check_for_conflicts(root_dir, template, dest, true)
-            .context("Error preparing files for templating")?;
+    /// Reads and parses the file included by an include directive, returning its path and its contents.
+    fn load_include(&self, root_dir: &Path, path: &str) -> Result<(PathBuf, StateConfig)> {
+        let yaml_path = root_dir.join(path);
+        if !self.filesystem.exists(&yaml_path) {
+            return Err(anyhow!("Could not file yaml to include in path {}", yaml_path.display()));
+        }
+
+        let contents = self
+            .filesystem
+            .read_to_string(&yaml_path)
+            .context(format!("Error loading included file {}", yaml_path.display()))?;
+        let config = StateConfig::from_yaml_with_strictness(&contents, self.strict)
+            .map_err(|e| e.with_file(&yaml_path))
+            .context(format!("Error parsing included file {}", yaml_path.display()))?;
+        Ok((yaml_path, config))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn plan_template(
+        &self,
+        root_dir: &Path,
+        section: &str,
+        template: &str,
+        dest: &str,
+        vars: &Option<String>,
+        strategy: ConflictStrategy,
+        plan: &mut Plan,
+    ) -> Result<()> {
+        let (template_path, rendered) = self.render_template(root_dir, template, vars)?;
+
+        let dest = match self
+            .check_for_conflicts(
+                root_dir,
+                section,
+                template,
+                dest,
+                true,
+                Desired::Contents(&rendered),
+                strategy,
+                plan,
+            )
+            .context("Error preparing files for templating")?
+        {
+            Some((_, dest)) => dest,
+            None => return Ok(()),
+        };
+
+        plan.push(
+            section,
+            Operation::WriteRendered {
+                template: template_path,
+                destination: dest,
+                contents: rendered,
+            },
+        );
+        Ok(())
+    }
 
-        let template_contents =
-            std::fs::read_to_string(&template).context(format!("Error reading template contents: {}", template.display()))?;
+    /// Renders `template` with the facts and `vars`, returning the path of the template and the rendered contents.
+    fn render_template(&self, root_dir: &Path, template: &str, vars: &Option<String>) -> Result<(PathBuf, String)> {
+        let template_path = root_dir.join(template);
+        if !self.filesystem.exists(&template_path) {
+            return Err(anyhow!("From does not exist: {}", template_path.display())).context("Error preparing files for templating");
+        }
+        let template_contents = self
+            .filesystem
+            .read_to_string(&template_path)
+            .context(format!("Error reading template contents: {}", template_path.display()))?;
         let mut tera = Tera::default();
         let mut context = TeraContext::new();
         // Add default variables
-        let os = self.os_detector.get_os().context("Error detecting current os")?;
-        context.insert("dotfilers_os", &os.to_string());
-        load_vars_into_context(root_dir, vars, &mut context).context("Error loading template vars")?;
+        for (name, value) in self.facts()?.template_variables() {
+            context.insert(name, &value);
+        }
+        load_vars_into_context(&self.filesystem, root_dir, vars, &mut context).context("Error loading template vars")?;
 
         let rendered = tera.render_str(&template_contents, &context).context("Error rendering template")?;
+        Ok((template_path, rendered))
+    }
 
-        if self.dry_run {
-            info!("Would have written into {} the following template: {}", dest.display(), rendered);
-        } else {
-            debug!("Writing template into {}", dest.display());
-            std::fs::write(&dest, rendered).context(format!("Error writing templated contents into {}", dest.display()))?;
-            info!("Rendered file {}", dest.display());
+    fn apply_operation(&self, section: &str, operation: &Operation) -> Result<()> {
+        match operation {
+            Operation::CreateDir { path } => {
+                debug!("Creating dir {}", path.display());
+                self.filesystem
+                    .create_dir(path)
+                    .context(format!("Error creating directory {}", path.display()))?;
+                self.journal_created(section, path)?;
+                self.record(section, EntryKind::Directory, None, path)?;
+                info!("Created dir {}", path.display());
+            }
+            Operation::Backup { path, backup } => {
+                warn!("Moving [src={}] -> [dst={}]", path.display(), backup.display());
+                self.filesystem.rename(path, backup).context(format!(
+                    "Error renaming [src={}] -> [dst={}]",
+                    path.display(),
+                    backup.display()
+                ))?;
+                self.journal(
+                    section,
+                    Change::Moved {
+                        from: path.clone(),
+                        to: backup.clone(),
+                    },
+                )?;
+                self.backups.borrow_mut().insert(path.clone(), backup.clone());
+            }
+            Operation::StoreBackup { path, backup } => {
+                warn!("Backing up [src={}] -> [dst={}]", path.display(), backup.display());
+                let store = self.backup_store()?;
+                // Dirs created inside the store are not journaled, as rolling back removes the run once it is empty
+                if let Some(parent) = backup.parent() {
+                    create_dir_all(&self.filesystem, parent)?;
+                }
+                self.filesystem.rename(path, backup).context(format!(
+                    "Error backing up [src={}] -> [dst={}]",
+                    path.display(),
+                    backup.display()
+                ))?;
+                self.journal(
+                    section,
+                    Change::Stored {
+                        from: path.clone(),
+                        to: backup.clone(),
+                    },
+                )?;
+                store.add(
+                    &self.filesystem,
+                    BackupEntry {
+                        original: path.clone(),
+                        backup: backup.clone(),
+                        section: section.to_string(),
+                        backed_up_at: chrono::Utc::now().to_rfc3339(),
+                    },
+                )?;
+                self.backups.borrow_mut().insert(path.clone(), backup.clone());
+            }
+            Operation::Remove { path } => self.remove(section, path)?,
+            Operation::Adopt { path, source } => {
+                warn!("Adopting {} into {}", path.display(), source.display());
+                if self.filesystem.symlink_metadata(source).is_ok() {
+                    self.remove(section, source)?;
+                }
+                self.filesystem.rename(path, source).context(format!(
+                    "Error moving [src={}] -> [dst={}]",
+                    path.display(),
+                    source.display()
+                ))?;
+                self.journal(
+                    section,
+                    Change::Moved {
+                        from: path.clone(),
+                        to: source.clone(),
+                    },
+                )?;
+            }
+            Operation::Symlink {
+                source,
+                destination,
+                directory,
+            } => {
+                self.filesystem.symlink(source, destination).context(format!(
+                    "Error symlinking {} {} -> {}",
+                    if *directory { "dir" } else { "file" },
+                    source.display(),
+                    destination.display()
+                ))?;
+                self.journal_created(section, destination)?;
+                self.record(section, EntryKind::Symlink, Some(source), destination)?;
+                info!("Symlinked {} -> {}", source.display(), destination.display());
+            }
+            Operation::Copy { source, destination } => {
+                debug!("Copying {} -> {}", source.display(), destination.display());
+                self.filesystem.copy(source, destination).context(format!(
+                    "Error copying file {} -> {}",
+                    source.display(),
+                    destination.display()
+                ))?;
+                self.journal_created(section, destination)?;
+                self.record(section, EntryKind::Copy, Some(source), destination)?;
+                info!("Copied file {} -> {}", source.display(), destination.display());
+            }
+            Operation::WriteRendered {
+                template,
+                destination,
+                contents,
+            } => {
+                debug!("Writing template into {}", destination.display());
+                self.filesystem
+                    .write(destination, contents.as_bytes())
+                    .context(format!("Error writing templated contents into {}", destination.display()))?;
+                self.journal_created(section, destination)?;
+                self.record(section, EntryKind::Template, Some(template), destination)?;
+                info!("Rendered file {}", destination.display());
+            }
+            Operation::Run {
+                command,
+                program,
+                args,
+                current_dir,
+            } => {
+                // Commands that fail may have changed things too, so they are journaled before running them
+                self.journal(section, Change::Ran(command.clone()))?;
+                self.run(program, args, current_dir, command)?
+            }
+            Operation::Unchanged { kind, source, destination } => {
+                info!("Unchanged {} -> {}", source.display(), destination.display());
+                self.record_unchanged(section, *kind, source, destination)?;
+            }
+            Operation::Skip { source, destination } => {
+                info!("Skipped {} -> {} as it already exists", source.display(), destination.display());
+            }
         }
+        Ok(())
+    }
 
+    /// Removes `path`. Inside a transaction, or when the run is journaled, it is only moved out of the way, so it can
+    /// be brought back.
+    fn remove(&self, section: &str, path: &Path) -> Result<()> {
+        let metadata = self
+            .filesystem
+            .symlink_metadata(path)
+            .context(format!("Error reading metadata of {}", path.display()))?;
+        let kind = if metadata.is_symlink() {
+            "symlink"
+        } else if metadata.is_dir() {
+            "dir"
+        } else {
+            "file"
+        };
+        warn!("Removing {} {}", kind, path.display());
+        if self.in_transaction() || self.run_journal.is_some() {
+            // It is only really removed once the transaction succeeds, or never when the run can be undone later
+            let stash = match &self.run_journal {
+                Some(journal) => {
+                    let stash = journal.stash_path(&self.filesystem, path);
+                    if let Some(parent) = stash.parent() {
+                        create_dir_all(&self.filesystem, parent)?;
+                    }
+                    stash
+                }
+                None => self.stash_path(path),
+            };
+            self.filesystem
+                .rename(path, &stash)
+                .context(format!("Error removing {} {}", kind, path.display()))?;
+            self.journal(
+                section,
+                Change::Removed {
+                    path: path.to_path_buf(),
+                    stash,
+                },
+            )?;
+        } else {
+            self.filesystem
+                .remove(path)
+                .context(format!("Error removing {} {}", kind, path.display()))?;
+        }
         Ok(())
     }
+
+    /// Hash of what is at `path`: the target of a symlink or the contents of a file. Dirs have none.
+    pub(crate) fn hash_of(&self, path: &Path) -> Result<Option<String>> {
+        let metadata = self
+            .filesystem
+            .symlink_metadata(path)
+            .context(format!("Error reading metadata of {}", path.display()))?;
+        if metadata.is_symlink() {
+            let target = self
+                .filesystem
+                .read_link(path)
+                .context(format!("Error reading symlink {}", path.display()))?;
+            Ok(Some(hash_bytes(target.to_string_lossy().as_bytes())))
+        } else if metadata.is_dir() {
+            Ok(None)
+        } else {
+            let contents = self
+                .filesystem
+                .read(path)
+                .context(format!("Error reading {} for hashing", path.display()))?;
+            Ok(Some(hash_bytes(&contents)))
+        }
+    }
+
+    /// Journals the creation of `path`, together with the hash of what was created.
+    fn journal_created(&self, section: &str, path: &Path) -> Result<()> {
+        let hash = self.hash_of(path)?;
+        self.journal(
+            section,
+            Change::Created {
+                path: path.to_path_buf(),
+                hash,
+            },
+        )
+    }
+
+    /// Records something that has just been deployed into the manifest, if there is one.
+    fn record(&self, section: &str, kind: EntryKind, source: Option<&Path>, destination: &Path) -> Result<()> {
+        let manifest = match &self.manifest {
+            Some(manifest) if !self.dry_run => manifest,
+            _ => return Ok(()),
+        };
+        let hash = self.hash_of(destination)?;
+        let mut entry = ManifestEntry::new(kind, section, source, destination, hash);
+        // When redeploying, the backup of the original file is kept rather than the one of the previous deployment
+        let previous = manifest.manifest().get(destination).cloned();
+        entry.backup = previous
+            .as_ref()
+            .and_then(|e| e.backup.clone())
+            .or_else(|| self.backups.borrow_mut().remove(destination));
+        self.journal(
+            section,
+            Change::Recorded {
+                destination: destination.to_path_buf(),
+                previous,
+            },
+        )?;
+        manifest
+            .record(&self.filesystem, entry)
+            .context(format!("Error updating manifest {}", manifest.path().display()))
+    }
+
+    /// Destinations that are already up to date are recorded too, unless the manifest already knows about them.
+    fn record_unchanged(&self, section: &str, kind: EntryKind, from: &Path, to: &Path) -> Result<()> {
+        let known = match &self.manifest {
+            Some(manifest) => manifest.manifest().get(to).is_some(),
+            None => return Ok(()),
+        };
+        match kind {
+            _ if known => Ok(()),
+            // Directories created by the user are not recorded, so they are never removed
+            EntryKind::Directory => Ok(()),
+            EntryKind::Copy if !self.filesystem.is_file(from) => Ok(()),
+            _ => self.record(section, kind, Some(from), to),
+        }
+    }
+}
+
+/// What a directive expects to find at its destination once it has been applied.
+pub(crate) enum Desired<'a> {
+    /// A symlink pointing to the source
+    Symlink,
+    /// A directory, whose contents are handled separately
+    Directory,
+    /// A copy of the source, with the same contents and permissions
+    Copy,
+    /// A file with exactly these contents
+    Contents(&'a str),
+}
+
+pub(crate) fn is_in_desired_state(fs: &dyn Filesystem, from: &Path, to: &Path, desired: &Desired) -> bool {
+    let to_metadata = match fs.symlink_metadata(to) {
+        Ok(metadata) => metadata,
+        Err(_) => return false,
+    };
+    match desired {
+        Desired::Symlink => {
+            if !to_metadata.is_symlink() {
+                return false;
+            }
+            if fs.read_link(to).map(|target| target == from).unwrap_or(false) {
+                return true;
+            }
+            matches!((fs.canonicalize(from), fs.canonicalize(to)), (Ok(a), Ok(b)) if a == b)
+        }
+        Desired::Directory => to_metadata.is_dir(),
+        Desired::Copy if fs.is_dir(from) => match from.file_name() {
+            // Directories are copied inside the destination
+            Some(name) => to_metadata.is_dir() && same_tree(fs, from, &to.join(name)),
+            None => false,
+        },
+        Desired::Copy => same_file(fs, from, to),
+        Desired::Contents(contents) => to_metadata.is_file() && fs.read(to).map(|current| current == contents.as_bytes()).unwrap_or(false),
+    }
+}
+
+/// Whether `to` is a regular file with the same contents and permissions as `from`.
+pub(crate) fn same_file(fs: &dyn Filesystem, from: &Path, to: &Path) -> bool {
+    let (from_metadata, to_metadata) = match (fs.metadata(from), fs.symlink_metadata(to)) {
+        (Ok(from_metadata), Ok(to_metadata)) => (from_metadata, to_metadata),
+        _ => return false,
+    };
+    if !to_metadata.is_file() || from_metadata.len != to_metadata.len || !same_permissions(&from_metadata, &to_metadata) {
+        return false;
+    }
+    match (fs.read(from), fs.read(to)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
 }
 
-fn is_glob(path: &str) -> bool {
+/// Whether every file below `from` has an identical copy in the same place below `to`.
+fn same_tree(fs: &dyn Filesystem, from: &Path, to: &Path) -> bool {
+    let entries = match fs.read_dir(from) {
+        Ok(entries) => entries,
+        Err(_) => return false,
+    };
+    for entry in entries {
+        let to_entry = match entry.file_name() {
+            Some(name) => to.join(name),
+            None => return false,
+        };
+        let same = if fs.is_dir(&entry) {
+            fs.is_dir(&to_entry) && same_tree(fs, &entry, &to_entry)
+        } else {
+            same_file(fs, &entry, &to_entry)
+        };
+        if !same {
+            return false;
+        }
+    }
+    true
+}
+
+fn same_permissions(a: &Metadata, b: &Metadata) -> bool {
+    a.mode == b.mode
+}
+
+/// Paths of everything below `dir`, relative to it. Directories come before their contents.
+fn tree_entries(fs: &dyn Filesystem, dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut entries = Vec::new();
+    let mut pending = vec![PathBuf::new()];
+    while let Some(relative) = pending.pop() {
+        let current = dir.join(&relative);
+        for entry in fs
+            .read_dir(&current)
+            .context(format!("Error getting dir contents of {}", current.display()))?
+        {
+            let path = relative.join(entry.file_name().unwrap_or_default());
+            if fs.is_dir(&entry) {
+                pending.push(path.clone());
+            }
+            entries.push(path);
+        }
+    }
+    Ok(entries)
+}
+
+/// Checks that what is in `to` can take the place of `from` in the repo.
+fn check_adoptable(overlay: &Overlay, from: &Path, to: &Path, desired: &Desired) -> Result<()> {
+    if matches!(desired, Desired::Contents(_)) {
+        return Err(anyhow!(
+            "Cannot adopt {} as templates are rendered, and their destination cannot replace them",
+            to.display()
+        ));
+    }
+    if overlay.is_symlink(to) {
+        return Err(anyhow!("Cannot adopt {} as it is a symlink", to.display()));
+    }
+    let from_is_dir = overlay.is_dir(from);
+    if from_is_dir && matches!(desired, Desired::Copy) {
+        return Err(anyhow!(
+            "Cannot adopt {} as directories are copied inside their destination",
+            to.display()
+        ));
+    }
+    if from_is_dir != overlay.is_dir(to) {
+        return Err(anyhow!(
+            "Cannot adopt {} into {} as only one of them is a directory",
+            to.display(),
+            from.display()
+        ));
+    }
+    Ok(())
+}
+
+/// Plans creating `path` and any missing parent, unless the plan already creates them.
+fn plan_create_dir_all(fs: &dyn Filesystem, section: &str, path: &Path, plan: &mut Plan) {
+    let missing: Vec<&Path> = path
+        .ancestors()
+        .take_while(|p| !p.as_os_str().is_empty() && !Overlay::new(fs, plan).exists(p))
+        .collect();
+    for dir in missing.into_iter().rev() {
+        plan.push(section, Operation::CreateDir { path: dir.to_path_buf() });
+    }
+}
+
+/// Where `to` points to, expanding `~` or making it relative to `root_dir`.
+fn resolve_destination(root_dir: &Path, to: &str) -> PathBuf {
+    if to.contains('~') {
+        PathBuf::from(shellexpand::tilde(to).to_string())
+    } else {
+        root_dir.join(to)
+    }
+}
+
+pub(crate) fn is_glob(path: &str) -> bool {
     path.contains('*')
 }
 
-fn load_vars_into_context(root_dir: &Path, vars: &Option<String>, context: &mut TeraContext) -> Result<()> {
+/// Returns the (from, to) pairs for every file matched by the `from` glob, without changing anything.
+pub(crate) fn expand_glob(fs: &dyn Filesystem, root_dir: &Path, from: &str, to: &str) -> Result<Vec<(String, String)>> {
+    let mut paths = vec![];
+    let full_glob = root_dir.join(from);
+    debug!("Detected from is glob {} | Will use {}", from, full_glob.display());
+    for entry in glob_matches(fs, &full_glob).context(format!("Error expanding glob {}", full_glob.display()))? {
+        let entry_without_prefix = entry
+            .strip_prefix(root_dir)
+            .context("Error stripping prefix from glob")?
+            .to_path_buf();
+        let from_path = entry_without_prefix.display().to_string();
+        let from_filename = match entry.file_name() {
+            Some(f) => match f.to_str() {
+                Some(filename) => filename.to_string(),
+                None => return Err(anyhow!("Cannot convert to str {:?}", f)),
+            },
+            None => return Err(anyhow!("Cannot obtain filename from {}", entry.display())),
+        };
+        let to_path = format!("{}/{}", to, from_filename);
+        paths.push((from_path, to_path));
+    }
+    Ok(paths)
+}
+
+/// Paths matching `pattern`, sorted the same way the glob crate sorts them. `**` matches any number of directories.
+fn glob_matches(fs: &dyn Filesystem, pattern: &Path) -> Result<Vec<PathBuf>> {
+    let mut matches = vec![PathBuf::new()];
+    for component in pattern.components() {
+        let part = component.as_os_str().to_string_lossy();
+        if part == "**" {
+            matches = matches.into_iter().flat_map(|m| dirs_below(fs, m)).collect();
+        } else if part.contains(['*', '?', '[']) {
+            let pattern = glob::Pattern::new(&part).context(format!("Invalid glob pattern {}", part))?;
+            let mut expanded = vec![];
+            for dir in matches.iter().filter(|m| fs.is_dir(m)) {
+                let mut entries: Vec<PathBuf> = fs
+                    .read_dir(dir)
+                    .context(format!("Error getting dir contents of {}", dir.display()))?
+                    .into_iter()
+                    .filter(|e| e.file_name().map(|n| pattern.matches(&n.to_string_lossy())).unwrap_or(false))
+                    .collect();
+                entries.sort();
+                expanded.extend(entries);
+            }
+            matches = expanded;
+        } else {
+            matches.iter_mut().for_each(|m| m.push(component));
+        }
+    }
+    matches.retain(|m| fs.symlink_metadata(m).is_ok());
+    Ok(matches)
+}
+
+/// `dir` followed by every directory below it, depth first.
+fn dirs_below(fs: &dyn Filesystem, dir: PathBuf) -> Vec<PathBuf> {
+    let mut children = match fs.read_dir(&dir) {
+        Ok(children) => children,
+        Err(_) => return vec![],
+    };
+    children.sort();
+    let mut dirs = vec![dir];
+    for child in children.into_iter().filter(|c| fs.is_dir(c)) {
+        dirs.extend(dirs_below(fs, child));
+    }
+    dirs
+}
+
+fn load_vars_into_context(fs: &dyn Filesystem, root_dir: &Path, vars: &Option<String>, context: &mut TeraContext) -> Result<()> {
     let vars = match vars {
         Some(v) => v,
         None => return Ok(()),
@@ -563,15 +1514,17 @@ fn load_vars_into_context(root_dir: &Path, vars: &Option<String>, context: &mut
 
     let vars_path = root_dir.join(vars);
 
-    if !vars_path.exists() {
+    if !fs.exists(&vars_path) {
         return Err(anyhow!("Could not find vars file {}", vars_path.display()));
     }
 
-    if !vars_path.is_file() {
+    if !fs.is_file(&vars_path) {
         return Err(anyhow!("Vars file is not a file {}", vars_path.display()));
     }
 
-    let vars_contents = std::fs::read_to_string(&vars_path).context(format!("Error reading vars file {}", vars_path.display()))?;
+    let vars_contents = fs
+        .read_to_string(&vars_path)
+        .context(format!("Error reading vars file {}", vars_path.display()))?;
     load_vars_into_context_from_str(&vars_contents, context);
 
     Ok(())
@@ -0,0 +1,195 @@
+use crate::config::did_you_mean;
+use crate::facts::{Facts, FACT_NAMES};
+use anyhow::{anyhow, Result};
+use evalexpr::{ContextWithMutableVariables, EvalexprError, HashMapContext, Node, Operator, Value};
+use tera::Value as TeraValue;
+
+const FACTS_PREFIX: &str = "dotfilers_";
+const ENV_FACT: &str = "env";
+
+/// Parses an `if:` expression, so syntax errors can be reported when loading the config.
+pub fn parse(expression: &str) -> std::result::Result<Node, String> {
+    let node = evalexpr::build_operator_tree(&normalize_quotes(expression)).map_err(|e| e.to_string())?;
+    check_node(&node)?;
+    check_variables(&node)?;
+    Ok(node)
+}
+
+/// Variables that are not facts would only fail when evaluating the expression, so they are rejected beforehand.
+fn check_variables(node: &Node) -> std::result::Result<(), String> {
+    for variable in node.iter_read_variable_identifiers() {
+        let name = variable.strip_prefix(FACTS_PREFIX).unwrap_or(variable);
+        let known = match name.split_once('.') {
+            Some((ENV_FACT, var)) => !var.is_empty(),
+            Some(_) => false,
+            None => name != ENV_FACT && FACT_NAMES.contains(&name),
+        };
+        if known {
+            continue;
+        }
+        return Err(match did_you_mean(name, &FACT_NAMES) {
+            Some(ENV_FACT) | None => format!("unknown variable '{}'", variable),
+            Some(suggestion) => format!("unknown variable '{}' (did you mean '{}'?)", variable, suggestion),
+        });
+    }
+    Ok(())
+}
+
+/// The engine builds trees with missing operands (such as `a &&`) and only complains when evaluating them, so the
+/// tree is checked beforehand.
+fn check_node(node: &Node) -> std::result::Result<(), String> {
+    let expected = match node.operator() {
+        Operator::Add
+        | Operator::Sub
+        | Operator::Mul
+        | Operator::Div
+        | Operator::Mod
+        | Operator::Exp
+        | Operator::Eq
+        | Operator::Neq
+        | Operator::Gt
+        | Operator::Lt
+        | Operator::Geq
+        | Operator::Leq
+        | Operator::And
+        | Operator::Or => Some(2),
+        Operator::Not | Operator::Neg | Operator::RootNode | Operator::FunctionIdentifier { .. } => Some(1),
+        Operator::Const { .. } | Operator::VariableIdentifierRead { .. } => Some(0),
+        Operator::Tuple | Operator::Chain => None,
+        other => return Err(format!("operator {} is not allowed in conditions", other)),
+    };
+    if let Some(expected) = expected {
+        if node.children().len() != expected {
+            return Err(match node.operator() {
+                Operator::RootNode => "found an empty expression".to_string(),
+                other => format!("operator {} is missing operands", other),
+            });
+        }
+    }
+    node.children().iter().try_for_each(check_node)
+}
+
+/// Evaluates an `if:` expression against the facts of the machine.
+///
+/// Facts are available both with their template name (`dotfilers_os`) and without the prefix (`os`), and environment
+/// variables can be accessed as `env.NAME`. Expressions that reference an unset environment variable do not hold.
+pub fn evaluate(expression: &str, facts: &Facts) -> Result<bool> {
+    let node = parse(expression).map_err(|e| anyhow!("Error parsing expression {}: {}", expression, e))?;
+    let context = build_context(facts)?;
+    match node.eval_boolean_with_context(&context) {
+        Err(EvalexprError::VariableIdentifierNotFound(name)) if is_env_variable(&name) => Ok(false),
+        result => result.map_err(|e| anyhow!("Error evaluating expression {}: {}", expression, e)),
+    }
+}
+
+fn is_env_variable(name: &str) -> bool {
+    let name = name.strip_prefix(FACTS_PREFIX).unwrap_or(name);
+    matches!(name.split_once('.'), Some((ENV_FACT, _)))
+}
+
+fn build_context(facts: &Facts) -> Result<HashMapContext> {
+    let mut context = HashMapContext::new();
+    let mut set = |name: String, value: Value| {
+        context
+            .set_value(name.clone(), value)
+            .map_err(|e| anyhow!("Error setting expression variable {}: {}", name, e))
+    };
+
+    for (name, value) in facts.template_variables() {
+        let short_name = name.trim_start_matches(FACTS_PREFIX).to_string();
+        match value {
+            TeraValue::Object(entries) => {
+                for (key, value) in entries {
+                    if let Some(value) = to_expression_value(&value) {
+                        set(format!("{}.{}", name, key), value.clone())?;
+                        set(format!("{}.{}", short_name, key), value)?;
+                    }
+                }
+            }
+            other => {
+                if let Some(value) = to_expression_value(&other) {
+                    set(name.clone(), value.clone())?;
+                    set(short_name, value)?;
+                }
+            }
+        }
+    }
+    Ok(context)
+}
+
+fn to_expression_value(value: &TeraValue) -> Option<Value> {
+    match value {
+        TeraValue::String(s) => Some(Value::String(s.clone())),
+        TeraValue::Bool(b) => Some(Value::Boolean(*b)),
+        TeraValue::Number(n) => match n.as_i64() {
+            Some(i) => Some(Value::Int(i)),
+            None => n.as_f64().map(Value::Float),
+        },
+        _ => None,
+    }
+}
+
+/// The expression engine only understands double quoted strings, but single quotes are much more convenient inside
+/// YAML, so they are turned into double quotes.
+fn normalize_quotes(expression: &str) -> String {
+    let mut normalized = String::with_capacity(expression.len());
+    let mut in_single = false;
+    let mut in_double = false;
+    let mut escaped = false;
+    for c in expression.chars() {
+        if escaped {
+            normalized.push(c);
+            escaped = false;
+            continue;
+        }
+        match c {
+            '\\' if in_single || in_double => {
+                normalized.push(c);
+                escaped = true;
+            }
+            '\'' if !in_double => {
+                in_single = !in_single;
+                normalized.push('"');
+            }
+            '"' if in_single => normalized.push_str("\\\""),
+            '"' => {
+                in_double = !in_double;
+                normalized.push(c);
+            }
+            _ => normalized.push(c),
+        }
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn normalize_single_quotes() {
+        assert_eq!(normalize_quotes("os == 'linux'"), "os == \"linux\"");
+        assert_eq!(normalize_quotes("a == \"it's\""), "a == \"it's\"");
+        assert_eq!(normalize_quotes("a == 'say \"hi\"'"), "a == \"say \\\"hi\\\"\"");
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(parse("os == 'linux' &&").is_err());
+        assert!(parse("(os == 'linux'").is_err());
+        assert!(parse("os = 'linux'").is_err());
+        assert!(parse("").is_err());
+        assert!(parse("os == 'linux' && env.WORK == '1'").is_ok());
+    }
+
+    #[test]
+    fn unknown_variables() {
+        assert_eq!(
+            parse("hostnme == 'work'").unwrap_err(),
+            "unknown variable 'hostnme' (did you mean 'hostname'?)"
+        );
+        assert_eq!(parse("env == 'work'").unwrap_err(), "unknown variable 'env'");
+        assert!(parse("os.name == 'linux'").unwrap_err().starts_with("unknown variable 'os.name'"));
+        assert!(parse("dotfilers_hostname == 'work' && dotfilers_env.WORK == '1' && cpu_count > 2").is_ok());
+    }
+}
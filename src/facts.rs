@@ -0,0 +1,188 @@
+use crate::detection::{OsDetector, OsInfo};
+use crate::filesystem::Filesystem;
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use tera::Value;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct XdgDirs {
+    pub config_home: PathBuf,
+    pub data_home: PathBuf,
+    pub cache_home: PathBuf,
+    pub state_home: PathBuf,
+}
+
+impl XdgDirs {
+    /// Resolves the XDG base directories, falling back to the defaults of the spec when the variables are not set.
+    pub fn from_env(home_dir: &std::path::Path, env: &BTreeMap<String, String>) -> Self {
+        let resolve = |var: &str, default: &str| match env.get(var) {
+            Some(value) if !value.is_empty() => PathBuf::from(value),
+            _ => home_dir.join(default),
+        };
+        Self {
+            config_home: resolve("XDG_CONFIG_HOME", ".config"),
+            data_home: resolve("XDG_DATA_HOME", ".local/share"),
+            cache_home: resolve("XDG_CACHE_HOME", ".cache"),
+            state_home: resolve("XDG_STATE_HOME", ".local/state"),
+        }
+    }
+}
+
+/// Names of the facts, as available in templates once prefixed with `dotfilers_`.
+pub(crate) const FACT_NAMES: [&str; 17] = [
+    "os",
+    "arch",
+    "distro",
+    "distro_version",
+    "is_wsl",
+    "is_container",
+    "hostname",
+    "username",
+    "home",
+    "xdg_config_home",
+    "xdg_data_home",
+    "xdg_cache_home",
+    "xdg_state_home",
+    "shell",
+    "kernel_version",
+    "cpu_count",
+    "env",
+];
+
+/// Information about the machine dotfilers is running on.
+///
+/// Facts are gathered once per run, and are available both in templates and in conditions.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Facts {
+    pub os: OsInfo,
+    pub hostname: String,
+    pub username: String,
+    pub home_dir: PathBuf,
+    pub xdg: XdgDirs,
+    pub shell: Option<String>,
+    pub kernel_version: Option<String>,
+    pub cpu_count: usize,
+    pub env: BTreeMap<String, String>,
+}
+
+impl Facts {
+    /// Variables exposed to templates, all of them prefixed with `dotfilers_`.
+    pub fn template_variables(&self) -> BTreeMap<String, Value> {
+        let mut vars = BTreeMap::new();
+        let mut insert = |name: &str, value: Value| {
+            vars.insert(format!("dotfilers_{}", name), value);
+        };
+        let path = |p: &PathBuf| Value::String(p.display().to_string());
+        let optional = |v: &Option<String>| Value::String(v.clone().unwrap_or_default());
+
+        insert("os", Value::String(self.os.os.to_string()));
+        insert("arch", Value::String(self.os.arch.clone()));
+        insert("distro", optional(&self.os.distro));
+        insert("distro_version", optional(&self.os.distro_version));
+        insert("is_wsl", Value::Bool(self.os.is_wsl));
+        insert("is_container", Value::Bool(self.os.is_container));
+        insert("hostname", Value::String(self.hostname.clone()));
+        insert("username", Value::String(self.username.clone()));
+        insert("home", path(&self.home_dir));
+        insert("xdg_config_home", path(&self.xdg.config_home));
+        insert("xdg_data_home", path(&self.xdg.data_home));
+        insert("xdg_cache_home", path(&self.xdg.cache_home));
+        insert("xdg_state_home", path(&self.xdg.state_home));
+        insert("shell", optional(&self.shell));
+        insert("kernel_version", optional(&self.kernel_version));
+        insert("cpu_count", Value::from(self.cpu_count));
+        insert(
+            "env",
+            Value::Object(self.env.iter().map(|(k, v)| (k.clone(), Value::String(v.clone()))).collect()),
+        );
+        vars
+    }
+
+    /// Looks for an executable named `command` in `fs`, in the PATH of the facts.
+    pub fn find_in_path(&self, fs: &dyn Filesystem, command: &str) -> Option<PathBuf> {
+        if command.contains(std::path::MAIN_SEPARATOR) {
+            let path = PathBuf::from(command);
+            return is_executable(fs, &path).then_some(path);
+        }
+        let path_var = self.env.get("PATH")?;
+        std::env::split_paths(path_var)
+            .map(|dir| dir.join(command))
+            .find(|candidate| is_executable(fs, candidate))
+    }
+}
+
+/// Whether `path` is a file that can be executed. Without unix permissions, every file can.
+fn is_executable(fs: &dyn Filesystem, path: &Path) -> bool {
+    match fs.metadata(path) {
+        Ok(metadata) => metadata.is_file() && (cfg!(not(unix)) || metadata.mode & 0o111 != 0),
+        Err(_) => false,
+    }
+}
+
+pub trait FactsProvider {
+    fn gather(&self) -> Result<Facts>;
+}
+
+#[derive(Default)]
+pub struct RealFactsProvider {
+    pub os_detector: OsDetector,
+}
+
+impl FactsProvider for RealFactsProvider {
+    fn gather(&self) -> Result<Facts> {
+        let os = self.os_detector.detect().context("Error detecting OS")?;
+        let env: BTreeMap<String, String> = std::env::vars().collect();
+
+        let hostname = hostname::get().context("Error obtaining hostname")?.to_string_lossy().to_string();
+        let username = ["USER", "LOGNAME", "USERNAME"]
+            .iter()
+            .find_map(|var| env.get(*var).cloned())
+            .unwrap_or_default();
+        let home_dir = match env.get("HOME").or_else(|| env.get("USERPROFILE")) {
+            Some(home) => PathBuf::from(home),
+            None => PathBuf::from(shellexpand::tilde("~").to_string()),
+        };
+        let xdg = XdgDirs::from_env(&home_dir, &env);
+        let cpu_count = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+
+        let facts = Facts {
+            os,
+            hostname,
+            username,
+            home_dir,
+            xdg,
+            shell: env.get("SHELL").cloned(),
+            kernel_version: self.os_detector.kernel_version(),
+            cpu_count,
+            env,
+        };
+        debug!(
+            "Gathered facts [hostname={}] [username={}] [home={}] [cpus={}]",
+            facts.hostname,
+            facts.username,
+            facts.home_dir.display(),
+            facts.cpu_count
+        );
+        Ok(facts)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn xdg_dirs_defaults_and_overrides() {
+        let home = PathBuf::from("/home/user");
+        let mut env = BTreeMap::new();
+        env.insert("XDG_CONFIG_HOME".to_string(), "/etc/user-config".to_string());
+        env.insert("XDG_CACHE_HOME".to_string(), "".to_string());
+
+        let xdg = XdgDirs::from_env(&home, &env);
+        assert_eq!(xdg.config_home, PathBuf::from("/etc/user-config"));
+        assert_eq!(xdg.data_home, PathBuf::from("/home/user/.local/share"));
+        assert_eq!(xdg.cache_home, PathBuf::from("/home/user/.cache"));
+        assert_eq!(xdg.state_home, PathBuf::from("/home/user/.local/state"));
+    }
+}
@@ -0,0 +1,515 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Component, Path, PathBuf};
+
+/// How many symlinks are followed when resolving a path before giving up, as the kernel does with `ELOOP`.
+const MAX_SYMLINK_HOPS: usize = 40;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum FileType {
+    File,
+    Dir,
+    Symlink,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Metadata {
+    pub file_type: FileType,
+    pub len: u64,
+    /// Permission bits. On platforms without unix permissions, read-only files are 0o444 and the rest 0o644.
+    pub mode: u32,
+}
+
+impl Metadata {
+    pub fn is_file(&self) -> bool {
+        self.file_type == FileType::File
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.file_type == FileType::Dir
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.file_type == FileType::Symlink
+    }
+}
+
+/// The file operations the executor performs, so it can work on something other than the real filesystem.
+pub trait Filesystem {
+    /// Metadata of `path`, following symlinks.
+    fn metadata(&self, path: &Path) -> Result<Metadata>;
+    /// Metadata of `path` itself, even if it is a symlink.
+    fn symlink_metadata(&self, path: &Path) -> Result<Metadata>;
+    fn read(&self, path: &Path) -> Result<Vec<u8>>;
+    /// Creates `path` if it does not exist, or replaces its contents.
+    fn write(&self, path: &Path, contents: &[u8]) -> Result<()>;
+    /// Adds `contents` at the end of `path`, creating it if it does not exist.
+    fn append(&self, path: &Path, contents: &[u8]) -> Result<()>;
+    /// Creates a directory, whose parent must already exist.
+    fn create_dir(&self, path: &Path) -> Result<()>;
+    fn rename(&self, from: &Path, to: &Path) -> Result<()>;
+    /// Removes a file or a symlink, or a directory together with everything inside it.
+    fn remove(&self, path: &Path) -> Result<()>;
+    /// Creates `link`, pointing to `target`.
+    fn symlink(&self, target: &Path, link: &Path) -> Result<()>;
+    fn read_link(&self, path: &Path) -> Result<PathBuf>;
+    /// Copies the contents and permissions of the file `from` into `to`.
+    fn copy(&self, from: &Path, to: &Path) -> Result<()>;
+    /// Paths of everything inside a directory, in no particular order.
+    fn read_dir(&self, path: &Path) -> Result<Vec<PathBuf>>;
+    fn canonicalize(&self, path: &Path) -> Result<PathBuf>;
+
+    fn exists(&self, path: &Path) -> bool {
+        self.metadata(path).is_ok()
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        self.metadata(path).map(|m| m.is_file()).unwrap_or(false)
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.metadata(path).map(|m| m.is_dir()).unwrap_or(false)
+    }
+
+    fn read_to_string(&self, path: &Path) -> Result<String> {
+        String::from_utf8(self.read(path)?).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+}
+
+/// The filesystem of the machine dotfilers runs on.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RealFilesystem;
+
+impl RealFilesystem {
+    fn convert(metadata: std::fs::Metadata) -> Metadata {
+        let file_type = if metadata.is_symlink() {
+            FileType::Symlink
+        } else if metadata.is_dir() {
+            FileType::Dir
+        } else {
+            FileType::File
+        };
+        Metadata {
+            file_type,
+            len: metadata.len(),
+            mode: Self::mode(&metadata),
+        }
+    }
+
+    #[cfg(unix)]
+    fn mode(metadata: &std::fs::Metadata) -> u32 {
+        use std::os::unix::fs::PermissionsExt;
+        metadata.permissions().mode() & 0o7777
+    }
+
+    #[cfg(not(unix))]
+    fn mode(metadata: &std::fs::Metadata) -> u32 {
+        if metadata.permissions().readonly() {
+            0o444
+        } else {
+            0o644
+        }
+    }
+}
+
+impl Filesystem for RealFilesystem {
+    fn metadata(&self, path: &Path) -> Result<Metadata> {
+        std::fs::metadata(path).map(Self::convert)
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> Result<Metadata> {
+        std::fs::symlink_metadata(path).map(Self::convert)
+    }
+
+    fn read(&self, path: &Path) -> Result<Vec<u8>> {
+        std::fs::read(path)
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> Result<()> {
+        std::fs::write(path, contents)
+    }
+
+    fn append(&self, path: &Path, contents: &[u8]) -> Result<()> {
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?
+            .write_all(contents)
+    }
+
+    fn create_dir(&self, path: &Path) -> Result<()> {
+        std::fs::create_dir(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        std::fs::rename(from, to)
+    }
+
+    fn remove(&self, path: &Path) -> Result<()> {
+        let metadata = std::fs::symlink_metadata(path)?;
+        if metadata.is_symlink() {
+            symlink::remove_symlink_auto(path)
+        } else if metadata.is_dir() {
+            std::fs::remove_dir_all(path)
+        } else {
+            std::fs::remove_file(path)
+        }
+    }
+
+    fn symlink(&self, target: &Path, link: &Path) -> Result<()> {
+        if target.is_dir() {
+            symlink::symlink_dir(target, link)
+        } else {
+            symlink::symlink_file(target, link)
+        }
+    }
+
+    fn read_link(&self, path: &Path) -> Result<PathBuf> {
+        std::fs::read_link(path)
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> Result<()> {
+        std::fs::copy(from, to).map(|_| ())
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<Vec<PathBuf>> {
+        std::fs::read_dir(path)?.map(|entry| entry.map(|e| e.path())).collect()
+    }
+
+    fn canonicalize(&self, path: &Path) -> Result<PathBuf> {
+        path.canonicalize()
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum Node {
+    File { contents: Vec<u8>, mode: u32 },
+    Dir { mode: u32 },
+    Symlink(PathBuf),
+}
+
+/// A filesystem that only lives in memory, for tests and for deploying into virtual roots.
+///
+/// Paths are expected to be absolute. The root directory always exists.
+#[derive(Debug, Default)]
+pub struct MemoryFilesystem {
+    nodes: RefCell<BTreeMap<PathBuf, Node>>,
+}
+
+impl MemoryFilesystem {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates `path` and every missing parent.
+    pub fn create_dir_all<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        for ancestor in path.ancestors().collect::<Vec<_>>().into_iter().rev() {
+            if !self.exists(ancestor) {
+                self.create_dir(ancestor)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes a file, creating every missing parent.
+    pub fn add_file<P: AsRef<Path>, C: AsRef<[u8]>>(&self, path: P, contents: C) -> Result<()> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            self.create_dir_all(parent)?;
+        }
+        self.write(path, contents.as_ref())
+    }
+
+    pub fn set_mode<P: AsRef<Path>>(&self, path: P, mode: u32) -> Result<()> {
+        let resolved = self.resolve(path.as_ref(), true)?;
+        match self.nodes.borrow_mut().get_mut(&resolved) {
+            Some(Node::File { mode: current, .. }) | Some(Node::Dir { mode: current }) => {
+                *current = mode;
+                Ok(())
+            }
+            _ => Err(not_found(path.as_ref())),
+        }
+    }
+
+    fn node(&self, resolved: &Path) -> Option<Node> {
+        if resolved.parent().is_none() {
+            return Some(Node::Dir { mode: 0o755 });
+        }
+        self.nodes.borrow().get(resolved).cloned()
+    }
+
+    /// Resolves every symlink in the parents of `path`, and also `path` itself if `follow` is set.
+    fn resolve(&self, path: &Path, follow: bool) -> Result<PathBuf> {
+        let mut resolved = PathBuf::new();
+        // Each pending entry is a single component, owned so that symlink targets can be spliced in
+        let mut pending: Vec<PathBuf> = components(path);
+        let mut hops = 0;
+        while let Some(part) = pending.pop() {
+            let component = match part.components().next() {
+                Some(component) => component,
+                None => continue,
+            };
+            match component {
+                Component::Prefix(_) | Component::RootDir => resolved.push(component),
+                Component::CurDir => {}
+                Component::ParentDir => {
+                    resolved.pop();
+                }
+                Component::Normal(name) => {
+                    let candidate = resolved.join(name);
+                    let is_last = pending.is_empty();
+                    match self.node(&candidate) {
+                        Some(Node::Symlink(target)) if follow || !is_last => {
+                            hops += 1;
+                            if hops > MAX_SYMLINK_HOPS {
+                                return Err(Error::other(format!("Too many symlinks in {}", path.display())));
+                            }
+                            pending.extend(components(&target));
+                        }
+                        Some(Node::Dir { .. }) => resolved = candidate,
+                        _ if is_last => resolved = candidate,
+                        Some(_) => return Err(Error::other(format!("Not a directory: {}", candidate.display()))),
+                        None => return Err(not_found(&candidate)),
+                    }
+                }
+            }
+        }
+        Ok(resolved)
+    }
+
+    fn parent_must_be_dir(&self, resolved: &Path) -> Result<()> {
+        match resolved.parent().map(|p| self.node(p)) {
+            Some(Some(Node::Dir { .. })) | None => Ok(()),
+            Some(Some(_)) => Err(Error::other(format!("Not a directory: {}", resolved.display()))),
+            Some(None) => Err(not_found(resolved.parent().unwrap_or(resolved))),
+        }
+    }
+
+    fn existing(&self, path: &Path, follow: bool) -> Result<(PathBuf, Node)> {
+        let resolved = self.resolve(path, follow)?;
+        match self.node(&resolved) {
+            Some(node) => Ok((resolved, node)),
+            None => Err(not_found(path)),
+        }
+    }
+}
+
+/// The components of `path` in reverse order, ready to be popped.
+fn components(path: &Path) -> Vec<PathBuf> {
+    path.components().rev().map(|c| PathBuf::from(c.as_os_str())).collect()
+}
+
+fn not_found(path: &Path) -> Error {
+    Error::new(ErrorKind::NotFound, format!("No such file or directory: {}", path.display()))
+}
+
+fn metadata_of(node: &Node) -> Metadata {
+    match node {
+        Node::File { contents, mode } => Metadata {
+            file_type: FileType::File,
+            len: contents.len() as u64,
+            mode: *mode,
+        },
+        Node::Dir { mode } => Metadata {
+            file_type: FileType::Dir,
+            len: 0,
+            mode: *mode,
+        },
+        Node::Symlink(target) => Metadata {
+            file_type: FileType::Symlink,
+            len: target.as_os_str().len() as u64,
+            mode: 0o777,
+        },
+    }
+}
+
+impl Filesystem for MemoryFilesystem {
+    fn metadata(&self, path: &Path) -> Result<Metadata> {
+        self.existing(path, true).map(|(_, node)| metadata_of(&node))
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> Result<Metadata> {
+        self.existing(path, false).map(|(_, node)| metadata_of(&node))
+    }
+
+    fn read(&self, path: &Path) -> Result<Vec<u8>> {
+        match self.existing(path, true)? {
+            (_, Node::File { contents, .. }) => Ok(contents),
+            _ => Err(Error::other(format!("Is a directory: {}", path.display()))),
+        }
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> Result<()> {
+        let resolved = self.resolve(path, true)?;
+        self.parent_must_be_dir(&resolved)?;
+        let mode = match self.node(&resolved) {
+            Some(Node::File { mode, .. }) => mode,
+            Some(_) => return Err(Error::other(format!("Is a directory: {}", path.display()))),
+            None => 0o644,
+        };
+        self.nodes.borrow_mut().insert(
+            resolved,
+            Node::File {
+                contents: contents.to_vec(),
+                mode,
+            },
+        );
+        Ok(())
+    }
+
+    fn append(&self, path: &Path, contents: &[u8]) -> Result<()> {
+        let mut current = match self.symlink_metadata(path) {
+            Ok(_) => self.read(path)?,
+            Err(_) => Vec::new(),
+        };
+        current.extend_from_slice(contents);
+        self.write(path, &current)
+    }
+
+    fn create_dir(&self, path: &Path) -> Result<()> {
+        let resolved = self.resolve(path, false)?;
+        if self.node(&resolved).is_some() {
+            return Err(Error::new(ErrorKind::AlreadyExists, format!("File exists: {}", path.display())));
+        }
+        self.parent_must_be_dir(&resolved)?;
+        self.nodes.borrow_mut().insert(resolved, Node::Dir { mode: 0o755 });
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        let (from, _) = self.existing(from, false)?;
+        let to = self.resolve(to, false)?;
+        self.parent_must_be_dir(&to)?;
+        if to.starts_with(&from) && to != from {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Cannot move {} inside itself", from.display()),
+            ));
+        }
+        if let Some(Node::Dir { .. }) = self.node(&to) {
+            if !self.read_dir(&to)?.is_empty() {
+                return Err(Error::other(format!("Directory not empty: {}", to.display())));
+            }
+        }
+        let mut nodes = self.nodes.borrow_mut();
+        nodes.retain(|path, _| !path.starts_with(&to));
+        let moved: Vec<(PathBuf, Node)> = nodes
+            .iter()
+            .filter(|(path, _)| path.starts_with(&from))
+            .map(|(path, node)| (path.clone(), node.clone()))
+            .collect();
+        for (path, node) in moved {
+            nodes.remove(&path);
+            let relative = path.strip_prefix(&from).unwrap_or(Path::new(""));
+            let new_path = if relative.as_os_str().is_empty() {
+                to.clone()
+            } else {
+                to.join(relative)
+            };
+            nodes.insert(new_path, node);
+        }
+        Ok(())
+    }
+
+    fn remove(&self, path: &Path) -> Result<()> {
+        let (resolved, _) = self.existing(path, false)?;
+        if resolved.parent().is_none() {
+            return Err(Error::new(ErrorKind::PermissionDenied, "Cannot remove the root directory"));
+        }
+        self.nodes.borrow_mut().retain(|path, _| !path.starts_with(&resolved));
+        Ok(())
+    }
+
+    fn symlink(&self, target: &Path, link: &Path) -> Result<()> {
+        let resolved = self.resolve(link, false)?;
+        if self.node(&resolved).is_some() {
+            return Err(Error::new(ErrorKind::AlreadyExists, format!("File exists: {}", link.display())));
+        }
+        self.parent_must_be_dir(&resolved)?;
+        self.nodes.borrow_mut().insert(resolved, Node::Symlink(target.to_path_buf()));
+        Ok(())
+    }
+
+    fn read_link(&self, path: &Path) -> Result<PathBuf> {
+        match self.existing(path, false)? {
+            (_, Node::Symlink(target)) => Ok(target),
+            _ => Err(Error::new(ErrorKind::InvalidInput, format!("Not a symlink: {}", path.display()))),
+        }
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> Result<()> {
+        let (contents, mode) = match self.existing(from, true)? {
+            (_, Node::File { contents, mode }) => (contents, mode),
+            _ => return Err(Error::new(ErrorKind::InvalidInput, format!("Not a file: {}", from.display()))),
+        };
+        self.write(to, &contents)?;
+        self.set_mode(to, mode)
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<Vec<PathBuf>> {
+        let resolved = match self.existing(path, true)? {
+            (resolved, Node::Dir { .. }) => resolved,
+            _ => return Err(Error::other(format!("Not a directory: {}", path.display()))),
+        };
+        Ok(self
+            .nodes
+            .borrow()
+            .keys()
+            .filter(|p| p.parent() == Some(resolved.as_path()))
+            .map(|p| path.join(p.file_name().unwrap_or_default()))
+            .collect())
+    }
+
+    fn canonicalize(&self, path: &Path) -> Result<PathBuf> {
+        self.existing(path, true).map(|(resolved, _)| resolved)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn memory_filesystem_follows_symlinks() {
+        let fs = MemoryFilesystem::new();
+        fs.add_file("/dotfiles/nvim/init.lua", "vim.opt.number = true").unwrap();
+        fs.create_dir_all("/home/user/.config").unwrap();
+        fs.symlink(Path::new("/dotfiles/nvim"), Path::new("/home/user/.config/nvim"))
+            .unwrap();
+        fs.symlink(Path::new("../missing"), Path::new("/home/user/broken")).unwrap();
+
+        let linked = Path::new("/home/user/.config/nvim/init.lua");
+        assert_eq!(fs.read_to_string(linked).unwrap(), "vim.opt.number = true");
+        assert!(fs.symlink_metadata(Path::new("/home/user/.config/nvim")).unwrap().is_symlink());
+        assert!(fs.is_dir(Path::new("/home/user/.config/nvim")));
+        assert_eq!(fs.canonicalize(linked).unwrap(), PathBuf::from("/dotfiles/nvim/init.lua"));
+        assert!(!fs.exists(Path::new("/home/user/broken")));
+        assert!(fs.symlink_metadata(Path::new("/home/user/broken")).is_ok());
+        assert_eq!(
+            fs.read_dir(Path::new("/home/user/.config/nvim")).unwrap(),
+            vec![PathBuf::from("/home/user/.config/nvim/init.lua")]
+        );
+    }
+
+    #[test]
+    fn memory_filesystem_moves_and_removes_trees() {
+        let fs = MemoryFilesystem::new();
+        fs.add_file("/a/b/c", "contents").unwrap();
+        fs.set_mode("/a/b/c", 0o600).unwrap();
+
+        fs.rename(Path::new("/a"), Path::new("/a.bak")).unwrap();
+        assert!(!fs.exists(Path::new("/a")));
+        assert_eq!(fs.metadata(Path::new("/a.bak/b/c")).unwrap().mode, 0o600);
+
+        fs.copy(Path::new("/a.bak/b/c"), Path::new("/copied")).unwrap();
+        assert_eq!(fs.metadata(Path::new("/copied")).unwrap().mode, 0o600);
+
+        fs.remove(Path::new("/a.bak")).unwrap();
+        assert!(!fs.exists(Path::new("/a.bak/b/c")));
+        assert_eq!(fs.read_dir(Path::new("/")).unwrap(), vec![PathBuf::from("/copied")]);
+        assert_eq!(fs.create_dir(Path::new("/x/y")).unwrap_err().kind(), ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,334 @@
+use crate::backups::{below, create_dir_all, run_id};
+use crate::command::CommandRunner;
+use crate::executor::Executor;
+use crate::facts::FactsProvider;
+use crate::filesystem::Filesystem;
+use crate::prompt::Prompter;
+use crate::transaction::Change;
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Utc};
+use std::path::{Path, PathBuf};
+
+const HISTORY_DIR_NAME: &str = "dotfilers";
+const HISTORY_SUBDIR_NAME: &str = "runs";
+const JOURNAL_FILE_NAME: &str = "journal.jsonl";
+const UNDONE_FILE_NAME: &str = "undone";
+const REMOVED_DIR_NAME: &str = "removed";
+/// How many runs are kept unless configured otherwise.
+pub const DEFAULT_KEPT_RUNS: usize = 20;
+
+/// A change applied by a run, as it is written in its journal.
+#[derive(Debug, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct JournalRecord {
+    pub section: String,
+    /// RFC 3339 timestamp of the moment the change was applied
+    pub at: String,
+    pub change: Change,
+}
+
+/// What a past run did, as shown by `history`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RunSummary {
+    pub id: String,
+    /// RFC 3339 timestamp of its first change
+    pub started_at: String,
+    /// RFC 3339 timestamp of its last change
+    pub finished_at: String,
+    /// Sections that changed something, in the order they did
+    pub sections: Vec<String>,
+    /// Changes applied to the machine. Manifest updates are not counted.
+    pub changes: usize,
+    /// RFC 3339 timestamp of the moment it was undone or rolled back, if it was
+    pub undone_at: Option<String>,
+}
+
+/// Directory where every run journals the changes it applies, one JSON record per line in
+/// `<root>/<id>/journal.jsonl`. Journals are only ever appended to.
+///
+/// Whatever a run removes is kept in `<root>/<id>/removed`, so the run can be undone.
+///
+/// Only the last `kept` runs are kept: the older ones are removed once a new run changes something.
+#[derive(Debug, Clone)]
+pub struct RunJournal {
+    root: PathBuf,
+    run: String,
+    kept: usize,
+}
+
+impl RunJournal {
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        Self::started_at(root, Utc::now())
+    }
+
+    /// A journal for a run that started at `started_at`.
+    pub fn started_at<P: AsRef<Path>>(root: P, started_at: DateTime<Utc>) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+            run: run_id(started_at),
+            kept: DEFAULT_KEPT_RUNS,
+        }
+    }
+
+    /// Keeps the last `kept` runs, counting the current one, instead of the default.
+    pub fn with_kept_runs(self, kept: usize) -> Self {
+        Self { kept, ..self }
+    }
+
+    /// Default location of the journals: `$XDG_STATE_HOME/dotfilers/runs`.
+    pub fn default_path(state_home: &Path) -> PathBuf {
+        state_home.join(HISTORY_DIR_NAME).join(HISTORY_SUBDIR_NAME)
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Id of the current run.
+    pub fn run(&self) -> &str {
+        &self.run
+    }
+
+    /// Every run that changed something, oldest first.
+    pub fn runs(&self, fs: &dyn Filesystem) -> Result<Vec<RunSummary>> {
+        self.run_ids(fs)?.iter().map(|id| self.summary(fs, id)).collect()
+    }
+
+    /// Ids of every run that changed something, oldest first.
+    fn run_ids(&self, fs: &dyn Filesystem) -> Result<Vec<String>> {
+        if !fs.exists(&self.root) {
+            return Ok(Vec::new());
+        }
+        let mut ids = Vec::new();
+        for dir in fs
+            .read_dir(&self.root)
+            .context(format!("Error listing runs in {}", self.root.display()))?
+        {
+            match dir.file_name().and_then(|name| name.to_str()) {
+                Some(id) if fs.is_file(&dir.join(JOURNAL_FILE_NAME)) => ids.push(id.to_string()),
+                _ => debug!("Ignoring {} as it is not a run", dir.display()),
+            }
+        }
+        ids.sort();
+        Ok(ids)
+    }
+
+    /// Removes the oldest runs, together with whatever they removed, so that only the last `kept` ones
+    /// are left once the current run is journaled. Returns the ids of the removed runs.
+    fn prune(&self, fs: &dyn Filesystem) -> Result<Vec<String>> {
+        let previous: Vec<String> = self.run_ids(fs)?.into_iter().filter(|id| *id != self.run).collect();
+        let excess = (previous.len() + 1).saturating_sub(self.kept);
+        let pruned: Vec<String> = previous.into_iter().take(excess).collect();
+        for id in &pruned {
+            info!("Removing run {} as only the last {} runs are kept", id, self.kept);
+            let dir = self.root.join(id);
+            fs.remove(&dir).context(format!("Error removing run {}", dir.display()))?;
+        }
+        Ok(pruned)
+    }
+
+    pub fn summary(&self, fs: &dyn Filesystem, run: &str) -> Result<RunSummary> {
+        let records = self.records(fs, run)?;
+        let mut sections: Vec<String> = Vec::new();
+        for record in &records {
+            if !sections.contains(&record.section) {
+                sections.push(record.section.clone());
+            }
+        }
+        let undone_path = self.root.join(run).join(UNDONE_FILE_NAME);
+        let undone_at = match fs.exists(&undone_path) {
+            true => Some(
+                fs.read_to_string(&undone_path)
+                    .context(format!("Error reading {}", undone_path.display()))?
+                    .trim()
+                    .to_string(),
+            ),
+            false => None,
+        };
+        Ok(RunSummary {
+            id: run.to_string(),
+            started_at: records.first().map(|r| r.at.clone()).unwrap_or_default(),
+            finished_at: records.last().map(|r| r.at.clone()).unwrap_or_default(),
+            sections,
+            changes: records.iter().filter(|r| !matches!(r.change, Change::Recorded { .. })).count(),
+            undone_at,
+        })
+    }
+
+    /// Every change applied by `run`, in the order they were applied.
+    pub fn records(&self, fs: &dyn Filesystem, run: &str) -> Result<Vec<JournalRecord>> {
+        let path = self.root.join(run).join(JOURNAL_FILE_NAME);
+        if !fs.exists(&path) {
+            return Err(anyhow!("Could not find run {}", run));
+        }
+        let contents = fs
+            .read_to_string(&path)
+            .context(format!("Error reading journal {}", path.display()))?;
+        contents
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(index, line)| {
+                serde_json::from_str(line).context(format!("Error parsing line {} of journal {}", index + 1, path.display()))
+            })
+            .collect()
+    }
+
+    /// Journals a change applied by the current run. Its first change prunes the oldest runs.
+    pub(crate) fn append(&self, fs: &dyn Filesystem, section: &str, change: Change) -> Result<()> {
+        let dir = self.root.join(&self.run);
+        let path = dir.join(JOURNAL_FILE_NAME);
+        if !fs.exists(&path) {
+            self.prune(fs)?;
+        }
+        create_dir_all(fs, &dir)?;
+        let record = JournalRecord {
+            section: section.to_string(),
+            at: Utc::now().to_rfc3339(),
+            change,
+        };
+        let mut line = serde_json::to_string(&record).context("Error serializing journal record")?;
+        line.push('\n');
+        fs.append(&path, line.as_bytes())
+            .context(format!("Error writing journal {}", path.display()))
+    }
+
+    /// Where the current run keeps `path` once it removes it.
+    pub(crate) fn stash_path(&self, fs: &dyn Filesystem, path: &Path) -> PathBuf {
+        let stash = below(&self.root.join(&self.run).join(REMOVED_DIR_NAME), path);
+        let mut candidate = stash.clone();
+        let mut counter = 1;
+        while fs.symlink_metadata(&candidate).is_ok() {
+            candidate = PathBuf::from(format!("{}.{}", stash.display(), counter));
+            counter += 1;
+        }
+        candidate
+    }
+
+    /// Marks `run` as undone, so it is not undone twice. Runs that did not change anything are left alone.
+    pub(crate) fn mark_undone(&self, fs: &dyn Filesystem, run: &str) -> Result<()> {
+        let dir = self.root.join(run);
+        if !fs.exists(&dir.join(JOURNAL_FILE_NAME)) {
+            return Ok(());
+        }
+        let path = dir.join(UNDONE_FILE_NAME);
+        fs.write(&path, Utc::now().to_rfc3339().as_bytes())
+            .context(format!("Error writing {}", path.display()))
+    }
+}
+
+impl<T, F, C, I> Executor<T, F, C, I>
+where
+    T: FactsProvider,
+    F: Filesystem,
+    C: CommandRunner,
+    I: Prompter,
+{
+    /// Undoes every change applied by `run`, or by the last run that has not been undone yet, in reverse order.
+    /// Commands that were run cannot be undone. Returns a summary of what was done.
+    pub fn undo_run(&self, run: Option<&str>) -> Result<String> {
+        let journal = self.run_journal()?;
+        let runs = journal.runs(&self.filesystem)?;
+        let summary = match run {
+            Some(run) => runs
+                .iter()
+                .find(|r| r.id == run)
+                .ok_or_else(|| anyhow!("Could not find run {}", run))?,
+            None => runs
+                .iter()
+                .rev()
+                .find(|r| r.undone_at.is_none())
+                .ok_or_else(|| anyhow!("There is no run left to undo"))?,
+        };
+        if let Some(undone_at) = &summary.undone_at {
+            return Err(anyhow!("Run {} was already undone at {}", summary.id, undone_at));
+        }
+        let later: Vec<&str> = runs
+            .iter()
+            .filter(|r| r.id > summary.id && r.undone_at.is_none())
+            .map(|r| r.id.as_str())
+            .collect();
+        if !later.is_empty() {
+            warn!("These later runs have not been undone and may depend on it: {}", later.join(", "));
+        }
+
+        let mut changes = Vec::new();
+        for record in journal.records(&self.filesystem, &summary.id)? {
+            match &record.change {
+                // Whatever the run created may have been removed by hand since then
+                Change::Created { path, .. } if self.filesystem.symlink_metadata(path).is_err() => {
+                    warn!("{} does not exist anymore, so there is nothing to undo", path.display());
+                }
+                _ => changes.push(record.change),
+            }
+        }
+
+        if self.dry_run {
+            for change in changes.iter().rev() {
+                info!("Would undo {}", change);
+            }
+            let count = changes.iter().filter(|c| !matches!(c, Change::Recorded { .. })).count();
+            return Ok(format!("{} change(s) would be undone", count));
+        }
+        info!("Undoing run {}", summary.id);
+        let outcome = self.rollback(changes).context(format!("Error undoing run {}", summary.id))?;
+        journal.mark_undone(&self.filesystem, &summary.id)?;
+        Ok(outcome)
+    }
+
+    pub(crate) fn run_journal(&self) -> Result<&RunJournal> {
+        self.run_journal.as_ref().ok_or_else(|| anyhow!("There is no run journal"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::filesystem::MemoryFilesystem;
+    use chrono::TimeZone;
+
+    #[test]
+    fn journal_is_appended_to() {
+        let fs = MemoryFilesystem::new();
+        let journal = RunJournal::started_at("/state/dotfilers/runs", Utc.with_ymd_and_hms(2024, 3, 1, 10, 30, 0).unwrap());
+        journal
+            .append(
+                &fs,
+                "shell",
+                Change::Created {
+                    path: PathBuf::from("/home/user/.zshrc"),
+                    hash: None,
+                },
+            )
+            .unwrap();
+        journal
+            .append(
+                &fs,
+                "git",
+                Change::Recorded {
+                    destination: PathBuf::from("/home/user/.gitconfig"),
+                    previous: None,
+                },
+            )
+            .unwrap();
+        journal.append(&fs, "git", Change::Ran("git config".to_string())).unwrap();
+
+        let records = journal.records(&fs, "2024-03-01T10-30-00.000Z").unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(
+            records[0].change,
+            Change::Created {
+                path: PathBuf::from("/home/user/.zshrc"),
+                hash: None,
+            }
+        );
+        let summary = journal.summary(&fs, journal.run()).unwrap();
+        assert_eq!(summary.sections, vec!["shell".to_string(), "git".to_string()]);
+        assert_eq!(summary.changes, 2);
+        assert_eq!(summary.undone_at, None);
+
+        assert_eq!(
+            journal.stash_path(&fs, Path::new("/home/user/.zshrc")),
+            PathBuf::from("/state/dotfilers/runs/2024-03-01T10-30-00.000Z/removed/home/user/.zshrc")
+        );
+    }
+}
@@ -1,11 +1,41 @@
 #[macro_use]
 extern crate tracing;
 
+pub mod backups;
+pub mod command;
+mod conditions;
 pub mod config;
+pub mod detection;
 pub mod executor;
+mod expression;
+pub mod facts;
+pub mod filesystem;
+pub mod history;
+pub mod location;
+pub mod manifest;
+mod overlay;
+pub mod plan;
+pub mod prompt;
+pub mod status;
+mod transaction;
+mod undeploy;
+pub mod validation;
 
+pub use backups::*;
+pub use command::*;
 pub use config::*;
+pub use detection::*;
 pub use executor::*;
+pub use facts::*;
+pub use filesystem::*;
+pub use history::*;
+pub use location::{ConfigError, SourceLocation};
+pub use manifest::*;
+pub use plan::*;
+pub use prompt::*;
+pub use status::*;
+pub use transaction::Change;
+pub use validation::*;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -13,4 +43,67 @@ pub type Result<T> = std::result::Result<T, Error>;
 pub enum Error {
     #[error("config error: {0}")]
     Config(String),
+    /// The config is not valid YAML.
+    #[error("syntax error: {0}")]
+    Syntax(ConfigError),
+    /// The config is valid YAML, but its contents are not a valid config.
+    #[error("config error: {0}")]
+    Invalid(ConfigError),
+}
+
+impl Error {
+    /// Error caused by the node found at `path` inside the document being parsed.
+    pub(crate) fn at(path: Vec<location::YamlPathSegment>, message: String) -> Self {
+        Self::Invalid(ConfigError::at(path, message))
+    }
+
+    pub(crate) fn from_yaml(err: serde_yaml::Error, contents: &str, context: &str) -> Self {
+        let message = format!("{}: {}", context, err);
+        match err.location() {
+            Some(location) => Self::Syntax(ConfigError::at_position(contents, location.line(), location.column(), message)),
+            None => Self::Config(message),
+        }
+    }
+
+    /// Adds context to the error, and moves it below `path` inside the document.
+    pub(crate) fn nested(self, path: &[location::YamlPathSegment], context: &str) -> Self {
+        match self {
+            Self::Config(message) if path.is_empty() => Self::Config(format!("{}: {}", context, message)),
+            Self::Config(message) => Self::at(path.to_vec(), format!("{}: {}", context, message)),
+            Self::Syntax(mut e) => {
+                e.message = format!("{}: {}", context, e.message);
+                Self::Syntax(e)
+            }
+            Self::Invalid(mut e) => {
+                e.path = path.iter().cloned().chain(e.path).collect();
+                e.message = format!("{}: {}", context, e.message);
+                Self::Invalid(e)
+            }
+        }
+    }
+
+    /// Resolves the line and column of the error inside `contents`.
+    pub(crate) fn locate(self, contents: &str) -> Self {
+        match self {
+            Self::Invalid(e) => Self::Invalid(e.locate(contents)),
+            other => other,
+        }
+    }
+
+    /// Records the file the error was found in.
+    pub fn with_file<P: AsRef<std::path::Path>>(self, file: P) -> Self {
+        match self {
+            Self::Syntax(e) => Self::Syntax(e.with_file(file.as_ref())),
+            Self::Invalid(e) => Self::Invalid(e.with_file(file.as_ref())),
+            other => other,
+        }
+    }
+
+    /// The location details of the error, if it comes from a config document.
+    pub fn config_error(&self) -> Option<&ConfigError> {
+        match self {
+            Self::Syntax(e) | Self::Invalid(e) => Some(e),
+            Self::Config(_) => None,
+        }
+    }
 }
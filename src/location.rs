@@ -0,0 +1,309 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use yaml_rust::parser::{Event, MarkedEventReceiver, Parser};
+use yaml_rust::scanner::Marker;
+
+/// A step in the path from the root of a YAML document to one of its nodes.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum YamlPathSegment {
+    Key(String),
+    Index(usize),
+}
+
+impl From<&str> for YamlPathSegment {
+    fn from(key: &str) -> Self {
+        Self::Key(key.to_string())
+    }
+}
+
+impl From<usize> for YamlPathSegment {
+    fn from(index: usize) -> Self {
+        Self::Index(index)
+    }
+}
+
+/// Where in a config file an error was found.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SourceLocation {
+    pub file: Option<PathBuf>,
+    /// 1-based line number
+    pub line: usize,
+    /// 1-based column number
+    pub column: usize,
+    /// The offending line, preceded by the previous one when there is one
+    pub snippet: Vec<(usize, String)>,
+}
+
+impl SourceLocation {
+    fn new(contents: &str, line: usize, column: usize) -> Self {
+        let first_line = if line > 1 { line - 1 } else { line };
+        let mut snippet: Vec<(usize, String)> = contents
+            .lines()
+            .enumerate()
+            .map(|(idx, l)| (idx + 1, l.to_string()))
+            .skip(first_line - 1)
+            .take(line - first_line + 1)
+            .filter(|(number, l)| *number == line || !l.trim().is_empty())
+            .collect();
+        // Errors at the end of the document point past the last line
+        if snippet.last().map(|(number, _)| *number) != Some(line) {
+            snippet.push((line, String::new()));
+        }
+        Self {
+            file: None,
+            line,
+            column,
+            snippet,
+        }
+    }
+}
+
+/// A config error, together with the location of the node that caused it when it is known.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ConfigError {
+    pub message: String,
+    pub location: Option<SourceLocation>,
+    pub(crate) path: Vec<YamlPathSegment>,
+}
+
+impl ConfigError {
+    pub(crate) fn at(path: Vec<YamlPathSegment>, message: String) -> Self {
+        Self {
+            message,
+            location: None,
+            path,
+        }
+    }
+
+    pub(crate) fn at_position(contents: &str, line: usize, column: usize, message: String) -> Self {
+        Self {
+            message,
+            location: Some(SourceLocation::new(contents, line, column)),
+            path: Vec::new(),
+        }
+    }
+
+    /// Resolves the location of the error inside `contents`, the document it was found in.
+    pub(crate) fn locate(mut self, contents: &str) -> Self {
+        if self.location.is_some() {
+            return self;
+        }
+        let source_map = SourceMap::build(contents);
+        // The exact node may not exist (such as a missing key), so fall back to the closest ancestor
+        let mut path = self.path.as_slice();
+        loop {
+            if let Some(marker) = source_map.positions.get(path) {
+                self.location = Some(SourceLocation::new(contents, marker.line(), marker.col() + 1));
+                break;
+            }
+            match path.split_last() {
+                Some((_, parent)) if !parent.is_empty() => path = parent,
+                _ => break,
+            }
+        }
+        self
+    }
+
+    pub(crate) fn with_file(mut self, file: &Path) -> Self {
+        if let Some(location) = self.location.as_mut() {
+            location.file = Some(file.to_path_buf());
+        }
+        self
+    }
+
+    /// Renders the error the way rustc does, pointing at the offending line with a caret.
+    pub fn render(&self) -> String {
+        let location = match &self.location {
+            Some(location) => location,
+            None => return format!("error: {}", self.message),
+        };
+        let width = location.snippet.iter().map(|(n, _)| n.to_string().len()).max().unwrap_or(1);
+        let padding = " ".repeat(width);
+        let file = match &location.file {
+            Some(file) => file.display().to_string(),
+            None => "<config>".to_string(),
+        };
+
+        let mut rendered = format!("error: {}\n", self.message);
+        rendered.push_str(&format!("{}--> {}:{}:{}\n", padding, file, location.line, location.column));
+        rendered.push_str(&format!("{} |\n", padding));
+        for (number, line) in &location.snippet {
+            rendered.push_str(&format!("{:>width$} | {}\n", number, line, width = width));
+            if *number == location.line {
+                let token_len = line
+                    .chars()
+                    .skip(location.column.saturating_sub(1))
+                    .take_while(|c| !c.is_whitespace() && *c != ':')
+                    .count()
+                    .max(1);
+                rendered.push_str(&format!(
+                    "{} | {}{}\n",
+                    padding,
+                    " ".repeat(location.column.saturating_sub(1)),
+                    "^".repeat(token_len)
+                ));
+            }
+        }
+        rendered
+    }
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.location {
+            Some(SourceLocation {
+                file: Some(file),
+                line,
+                column,
+                ..
+            }) => write!(f, "{}:{}:{}: {}", file.display(), line, column, self.message),
+            Some(SourceLocation { line, column, .. }) => write!(f, "line {} column {}: {}", line, column, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+enum Frame {
+    Mapping { path: Vec<YamlPathSegment>, key: Option<String> },
+    Sequence { path: Vec<YamlPathSegment>, index: usize },
+}
+
+/// Positions of every node of a YAML document. For mapping entries, the position of the key is stored.
+#[derive(Default)]
+struct SourceMap {
+    positions: HashMap<Vec<YamlPathSegment>, Marker>,
+    stack: Vec<Frame>,
+}
+
+impl SourceMap {
+    fn build(contents: &str) -> Self {
+        let mut source_map = Self::default();
+        let mut parser = Parser::new(contents.chars());
+        // Errors are already reported by serde_yaml, so an incomplete map is good enough here
+        let _ = parser.load(&mut source_map, false);
+        source_map
+    }
+
+    /// Returns the path of the node that starts now, or None if the node is a mapping key.
+    ///
+    /// The parser marks block mappings after their first key, so they are not recorded here. They get the position of
+    /// their first key instead.
+    fn node_path(&mut self, mark: Marker, key_value: Option<&str>, record: bool) -> Option<Vec<YamlPathSegment>> {
+        match self.stack.last_mut() {
+            None => Some(Vec::new()),
+            Some(Frame::Sequence { path, index }) => {
+                let mut node_path = path.clone();
+                node_path.push(YamlPathSegment::Index(*index));
+                *index += 1;
+                if record {
+                    self.positions.entry(node_path.clone()).or_insert(mark);
+                }
+                Some(node_path)
+            }
+            Some(Frame::Mapping { path, key }) => match key.take() {
+                Some(key) => {
+                    let mut node_path = path.clone();
+                    node_path.push(YamlPathSegment::Key(key));
+                    Some(node_path)
+                }
+                None => {
+                    let key_value = key_value.unwrap_or_default().to_string();
+                    self.positions.entry(path.clone()).or_insert(mark);
+                    let mut key_path = path.clone();
+                    key_path.push(YamlPathSegment::Key(key_value.clone()));
+                    self.positions.entry(key_path).or_insert(mark);
+                    *key = Some(key_value);
+                    None
+                }
+            },
+        }
+    }
+}
+
+impl MarkedEventReceiver for SourceMap {
+    fn on_event(&mut self, ev: Event, mark: Marker) {
+        match ev {
+            Event::Scalar(value, ..) => {
+                self.node_path(mark, Some(&value), true);
+            }
+            Event::Alias(_) => {
+                self.node_path(mark, None, true);
+            }
+            Event::MappingStart(_) => {
+                let path = self.node_path(mark, None, false).unwrap_or_default();
+                self.stack.push(Frame::Mapping { path, key: None });
+            }
+            Event::SequenceStart(_) => {
+                let path = self.node_path(mark, None, true).unwrap_or_default();
+                self.stack.push(Frame::Sequence { path, index: 0 });
+            }
+            Event::MappingEnd | Event::SequenceEnd => {
+                self.stack.pop();
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CONTENTS: &str = r#"
+.dotfilers:
+  log_level: debug
+
+ssh:
+  - run: echo
+  - template: ssh/config.tpl
+    vars: ssh/vars
+git:
+  steps:
+    - run: echo
+"#;
+
+    fn path(segments: &[YamlPathSegment]) -> Vec<YamlPathSegment> {
+        segments.to_vec()
+    }
+
+    #[test]
+    fn source_map_positions() {
+        let map = SourceMap::build(CONTENTS);
+        let position = |p: Vec<YamlPathSegment>| map.positions.get(&p).map(|m| (m.line(), m.col() + 1));
+
+        assert_eq!(position(path(&["ssh".into()])), Some((5, 1)));
+        assert_eq!(position(path(&[".dotfilers".into(), "log_level".into()])), Some((3, 3)));
+        assert_eq!(position(path(&["ssh".into(), 1.into()])), Some((7, 5)));
+        assert_eq!(position(path(&["ssh".into(), 1.into(), "vars".into()])), Some((8, 5)));
+        assert_eq!(position(path(&["git".into(), "steps".into(), 0.into()])), Some((11, 7)));
+    }
+
+    #[test]
+    fn locate_falls_back_to_parent() {
+        let error = ConfigError::at(path(&["ssh".into(), 1.into(), "missing".into()]), "boom".to_string()).locate(CONTENTS);
+        let location = error.location.expect("Should have been located");
+        assert_eq!((location.line, location.column), (7, 5));
+        assert_eq!(
+            location.snippet,
+            vec![(6, "  - run: echo".to_string()), (7, "  - template: ssh/config.tpl".to_string())]
+        );
+    }
+
+    #[test]
+    fn render_with_caret() {
+        let error = ConfigError::at(path(&["ssh".into(), 1.into(), "vars".into()]), "unknown key 'vars'".to_string())
+            .locate(CONTENTS)
+            .with_file(Path::new("dotfilers.yaml"));
+
+        let expected = r#"error: unknown key 'vars'
+ --> dotfilers.yaml:8:5
+  |
+7 |   - template: ssh/config.tpl
+8 |     vars: ssh/vars
+  |     ^^^^
+"#;
+        assert_eq!(error.render(), expected);
+        assert_eq!(error.to_string(), "dotfilers.yaml:8:5: unknown key 'vars'");
+    }
+}
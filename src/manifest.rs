@@ -0,0 +1,219 @@
+use crate::backups::create_dir_all;
+use crate::filesystem::Filesystem;
+use anyhow::{anyhow, Context, Result};
+use sha2::{Digest, Sha256};
+use std::cell::{Ref, RefCell};
+use std::path::{Path, PathBuf};
+
+const MANIFEST_VERSION: u32 = 1;
+const MANIFEST_DIR_NAME: &str = "dotfilers";
+const MANIFEST_FILE_NAME: &str = "state.json";
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryKind {
+    Symlink,
+    Copy,
+    Template,
+    Directory,
+}
+
+/// Something dotfilers put on the machine.
+#[derive(Debug, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ManifestEntry {
+    pub kind: EntryKind,
+    /// File the destination was created from. Directories do not have one.
+    pub source: Option<PathBuf>,
+    pub destination: PathBuf,
+    /// SHA-256 of the deployed contents. For symlinks, it is the hash of the path they point to.
+    pub hash: Option<String>,
+    pub section: String,
+    /// RFC 3339 timestamp of the moment the entry was deployed
+    pub deployed_at: String,
+    /// Where whatever was in `destination` before deploying it was moved to
+    #[serde(default)]
+    pub backup: Option<PathBuf>,
+}
+
+impl ManifestEntry {
+    pub fn new(kind: EntryKind, section: &str, source: Option<&Path>, destination: &Path, hash: Option<String>) -> Self {
+        Self {
+            kind,
+            source: source.map(Path::to_path_buf),
+            destination: destination.to_path_buf(),
+            hash,
+            section: section.to_string(),
+            deployed_at: chrono::Utc::now().to_rfc3339(),
+            backup: None,
+        }
+    }
+}
+
+/// Everything dotfilers has deployed on this machine, across runs.
+#[derive(Debug, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    /// Entries in the order they were deployed. There is at most one entry per destination.
+    pub entries: Vec<ManifestEntry>,
+}
+
+impl Default for Manifest {
+    fn default() -> Self {
+        Self {
+            version: MANIFEST_VERSION,
+            entries: Vec::new(),
+        }
+    }
+}
+
+impl Manifest {
+    /// Loads the manifest stored in `path`, or an empty one if it does not exist yet.
+    pub fn load<P: AsRef<Path>>(fs: &dyn Filesystem, path: P) -> Result<Self> {
+        let path = path.as_ref();
+        if !fs.exists(path) {
+            debug!("Manifest {} does not exist yet", path.display());
+            return Ok(Self::default());
+        }
+        let contents = fs
+            .read_to_string(path)
+            .context(format!("Error reading manifest {}", path.display()))?;
+        let manifest: Self = serde_json::from_str(&contents).context(format!("Error parsing manifest {}", path.display()))?;
+        if manifest.version != MANIFEST_VERSION {
+            return Err(anyhow!(
+                "Unsupported manifest version {} in {} (expected {})",
+                manifest.version,
+                path.display(),
+                MANIFEST_VERSION
+            ));
+        }
+        Ok(manifest)
+    }
+
+    /// Writes the manifest into `path` atomically, so it is never left half written.
+    pub fn save<P: AsRef<Path>>(&self, fs: &dyn Filesystem, path: P) -> Result<()> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            create_dir_all(fs, parent).context(format!("Error creating manifest dir {}", parent.display()))?;
+        }
+        let contents = serde_json::to_string_pretty(self).context("Error serializing manifest")?;
+        let tmp_path = path.with_extension(format!("tmp.{}", std::process::id()));
+        fs.write(&tmp_path, contents.as_bytes())
+            .context(format!("Error writing manifest {}", tmp_path.display()))?;
+        fs.rename(&tmp_path, path)
+            .context(format!("Error moving manifest into {}", path.display()))?;
+        Ok(())
+    }
+
+    pub fn get<P: AsRef<Path>>(&self, destination: P) -> Option<&ManifestEntry> {
+        self.entries.iter().find(|e| e.destination == destination.as_ref())
+    }
+
+    /// Adds an entry, replacing the previous one for the same destination.
+    pub fn insert(&mut self, entry: ManifestEntry) {
+        self.entries.retain(|e| e.destination != entry.destination);
+        self.entries.push(entry);
+    }
+
+    pub fn remove<P: AsRef<Path>>(&mut self, destination: P) -> Option<ManifestEntry> {
+        let position = self.entries.iter().position(|e| e.destination == destination.as_ref())?;
+        Some(self.entries.remove(position))
+    }
+}
+
+/// A manifest that is persisted every time it changes, so it stays accurate even if a run fails halfway.
+pub struct ManifestStore {
+    path: PathBuf,
+    manifest: RefCell<Manifest>,
+}
+
+impl ManifestStore {
+    pub fn open<P: AsRef<Path>>(fs: &dyn Filesystem, path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let manifest = Manifest::load(fs, &path)?;
+        Ok(Self {
+            path,
+            manifest: RefCell::new(manifest),
+        })
+    }
+
+    /// Default location of the manifest: `$XDG_STATE_HOME/dotfilers/state.json`.
+    pub fn default_path(state_home: &Path) -> PathBuf {
+        state_home.join(MANIFEST_DIR_NAME).join(MANIFEST_FILE_NAME)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn manifest(&self) -> Ref<'_, Manifest> {
+        self.manifest.borrow()
+    }
+
+    pub fn record(&self, fs: &dyn Filesystem, entry: ManifestEntry) -> Result<()> {
+        debug!("Recording {:?} {} in manifest", entry.kind, entry.destination.display());
+        self.manifest.borrow_mut().insert(entry);
+        self.manifest.borrow().save(fs, &self.path)
+    }
+
+    pub fn forget<P: AsRef<Path>>(&self, fs: &dyn Filesystem, destination: P) -> Result<Option<ManifestEntry>> {
+        let removed = self.manifest.borrow_mut().remove(destination);
+        if removed.is_some() {
+            self.manifest.borrow().save(fs, &self.path)?;
+        }
+        Ok(removed)
+    }
+}
+
+pub fn hash_bytes(contents: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(contents))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::filesystem::MemoryFilesystem;
+
+    fn entry(destination: &str, section: &str) -> ManifestEntry {
+        ManifestEntry::new(
+            EntryKind::Copy,
+            section,
+            Some(Path::new("/repo/file")),
+            Path::new(destination),
+            Some(hash_bytes(b"contents")),
+        )
+    }
+
+    #[test]
+    fn insert_replaces_same_destination() {
+        let mut manifest = Manifest::default();
+        manifest.insert(entry("/home/a", "first"));
+        manifest.insert(entry("/home/b", "first"));
+        manifest.insert(entry("/home/a", "second"));
+
+        assert_eq!(manifest.entries.len(), 2);
+        assert_eq!(manifest.entries[0].destination, PathBuf::from("/home/b"));
+        assert_eq!(manifest.get("/home/a").map(|e| e.section.as_str()), Some("second"));
+        assert!(manifest.remove("/home/a").is_some());
+        assert!(manifest.get("/home/a").is_none());
+    }
+
+    #[test]
+    fn hashes_are_sha256() {
+        assert_eq!(
+            hash_bytes(b"contents"),
+            "d1b2a59fbea7e20077af9f91b27e95e865061b270be03ff539ab3b73587882e8"
+        );
+    }
+
+    #[test]
+    fn save_and_load() {
+        let fs = MemoryFilesystem::new();
+        let path = ManifestStore::default_path(Path::new("/state"));
+        let store = ManifestStore::open(&fs, &path).expect("Should open a missing manifest");
+        store.record(&fs, entry("/home/a", "first")).expect("Should record");
+
+        let loaded = Manifest::load(&fs, &path).expect("Should load");
+        assert_eq!(loaded, *store.manifest());
+        assert_eq!(fs.read_dir(path.parent().unwrap()).unwrap(), vec![path.clone()]);
+    }
+}
@@ -0,0 +1,197 @@
+use crate::executor::{is_in_desired_state, Desired};
+use crate::filesystem::Filesystem;
+use crate::plan::{Operation, Plan};
+use std::path::{Path, PathBuf};
+
+/// How many planned symlinks are followed before giving up, as the kernel does with `ELOOP`.
+const MAX_SYMLINK_HOPS: usize = 40;
+
+/// What a plan leaves in a path it changes.
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum Planned<'a> {
+    Missing,
+    Dir,
+    Symlink(&'a Path),
+    /// A copy of the given file
+    Copy(&'a Path),
+    Contents(&'a str),
+}
+
+enum Resolved<'a> {
+    Planned(Planned<'a>),
+    /// The plan does not change the path, so it is whatever is on disk in the given path
+    Disk(PathBuf),
+}
+
+/// Read-only view of a filesystem as it will be once every operation of a plan has been applied.
+///
+/// Planning goes through it rather than the disk, so each directive sees the state earlier ones would have left even
+/// when nothing has been applied yet. Commands run by the plan are not taken into account.
+pub(crate) struct Overlay<'a> {
+    fs: &'a dyn Filesystem,
+    plan: &'a Plan,
+}
+
+impl<'a> Overlay<'a> {
+    pub(crate) fn new(fs: &'a dyn Filesystem, plan: &'a Plan) -> Self {
+        Self { fs, plan }
+    }
+
+    /// Whether `path` exists, following symlinks.
+    pub(crate) fn exists(&self, path: &Path) -> bool {
+        match self.resolve(path) {
+            Resolved::Disk(path) => self.fs.exists(&path),
+            Resolved::Planned(Planned::Missing) => false,
+            Resolved::Planned(Planned::Symlink(target)) => self.exists(target),
+            Resolved::Planned(_) => true,
+        }
+    }
+
+    /// Whether `path` exists, without following symlinks, so broken symlinks exist too.
+    pub(crate) fn symlink_exists(&self, path: &Path) -> bool {
+        match self.resolve(path) {
+            Resolved::Disk(path) => self.fs.symlink_metadata(&path).is_ok(),
+            Resolved::Planned(Planned::Missing) => false,
+            Resolved::Planned(_) => true,
+        }
+    }
+
+    /// Whether `path` is a directory, following symlinks.
+    pub(crate) fn is_dir(&self, path: &Path) -> bool {
+        match self.resolve(path) {
+            Resolved::Disk(path) => self.fs.is_dir(&path),
+            Resolved::Planned(Planned::Dir) => true,
+            Resolved::Planned(Planned::Symlink(target)) => self.is_dir(target),
+            Resolved::Planned(_) => false,
+        }
+    }
+
+    /// Whether `path` is a symlink, without following it.
+    pub(crate) fn is_symlink(&self, path: &Path) -> bool {
+        match self.resolve(path) {
+            Resolved::Disk(path) => self.fs.symlink_metadata(&path).map(|m| m.is_symlink()).unwrap_or(false),
+            Resolved::Planned(Planned::Symlink(_)) => true,
+            Resolved::Planned(_) => false,
+        }
+    }
+
+    pub(crate) fn is_in_desired_state(&self, from: &Path, to: &Path, desired: &Desired) -> bool {
+        let planned = match self.resolve(to) {
+            Resolved::Disk(to) => return is_in_desired_state(self.fs, from, &to, desired),
+            Resolved::Planned(planned) => planned,
+        };
+        match (planned, desired) {
+            (Planned::Symlink(target), Desired::Symlink) => target == from,
+            (Planned::Dir, Desired::Directory) => true,
+            (Planned::Copy(source), Desired::Copy) => source == from,
+            (Planned::Copy(source), Desired::Contents(contents)) => {
+                self.fs.read(source).map(|current| current == contents.as_bytes()).unwrap_or(false)
+            }
+            (Planned::Contents(contents), Desired::Copy) => {
+                self.fs.is_file(from) && self.fs.read(from).map(|source| source == contents.as_bytes()).unwrap_or(false)
+            }
+            (Planned::Contents(planned), Desired::Contents(contents)) => &planned == contents,
+            _ => false,
+        }
+    }
+
+    fn resolve(&self, path: &Path) -> Resolved<'a> {
+        self.resolve_before(path, self.plan.operations.len(), 0)
+    }
+
+    /// Finds out what is in `path` after applying the first `end` operations of the plan.
+    fn resolve_before(&self, path: &Path, end: usize, hops: usize) -> Resolved<'a> {
+        if hops > MAX_SYMLINK_HOPS {
+            return Resolved::Disk(path.to_path_buf());
+        }
+        for (index, planned) in self.plan.operations[..end].iter().enumerate().rev() {
+            let (changed, node) = match &planned.operation {
+                Operation::CreateDir { path } => (path.as_path(), Planned::Dir),
+                Operation::Backup { path: moved, backup }
+                | Operation::StoreBackup { path: moved, backup }
+                | Operation::Adopt {
+                    path: moved,
+                    source: backup,
+                } => {
+                    // Whatever was in the original path before moving it is now in the backup
+                    if let Ok(rest) = path.strip_prefix(backup) {
+                        return self.resolve_before(&join(moved, rest), index, hops);
+                    }
+                    (moved.as_path(), Planned::Missing)
+                }
+                Operation::Remove { path } => (path.as_path(), Planned::Missing),
+                Operation::Symlink { source, destination, .. } => match path.strip_prefix(destination) {
+                    Ok(rest) if rest.as_os_str().is_empty() => return Resolved::Planned(Planned::Symlink(source)),
+                    // Paths below a symlink are found in the place it points to
+                    Ok(rest) => return self.resolve_before(&source.join(rest), self.plan.operations.len(), hops + 1),
+                    Err(_) => continue,
+                },
+                Operation::Copy { source, destination } => (destination.as_path(), Planned::Copy(source)),
+                Operation::WriteRendered { destination, contents, .. } => (destination.as_path(), Planned::Contents(contents)),
+                Operation::Run { .. } | Operation::Unchanged { .. } | Operation::Skip { .. } => continue,
+            };
+            if changed == path {
+                return Resolved::Planned(node);
+            }
+            if path.starts_with(changed) {
+                // Whatever was below it has been removed, or it is a new empty directory
+                return Resolved::Planned(Planned::Missing);
+            }
+        }
+        Resolved::Disk(path.to_path_buf())
+    }
+}
+
+fn join(base: &Path, rest: &Path) -> PathBuf {
+    if rest.as_os_str().is_empty() {
+        base.to_path_buf()
+    } else {
+        base.join(rest)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::filesystem::MemoryFilesystem;
+
+    #[test]
+    fn sees_planned_changes() {
+        let fs = MemoryFilesystem::new();
+        let root = PathBuf::from("/root");
+        let mut plan = Plan::default();
+        plan.push("test", Operation::CreateDir { path: root.join("dir") });
+        plan.push(
+            "test",
+            Operation::WriteRendered {
+                template: root.join("tpl"),
+                destination: root.join("dir/file"),
+                contents: "contents".to_string(),
+            },
+        );
+        plan.push(
+            "test",
+            Operation::Backup {
+                path: root.join("dir"),
+                backup: root.join("dir.bak"),
+            },
+        );
+        plan.push(
+            "test",
+            Operation::Symlink {
+                source: root.join("dir.bak"),
+                destination: root.join("dir"),
+                directory: true,
+            },
+        );
+
+        let overlay = Overlay::new(&fs, &plan);
+        assert!(overlay.is_dir(&root.join("dir.bak")));
+        assert!(overlay.is_dir(&root.join("dir")));
+        assert!(overlay.exists(&root.join("dir/file")));
+        assert!(!overlay.exists(&root.join("dir/other")));
+        assert!(overlay.is_in_desired_state(&root.join("tpl"), &root.join("dir.bak/file"), &Desired::Contents("contents")));
+        assert!(overlay.is_in_desired_state(&root.join("dir.bak"), &root.join("dir"), &Desired::Symlink));
+        assert!(!overlay.exists(&root.join("does_not_exist")));
+    }
+}
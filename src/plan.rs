@@ -0,0 +1,168 @@
+use crate::manifest::EntryKind;
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+
+/// A single change to be made on the machine.
+#[derive(Debug, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Operation {
+    /// Create a directory, whose parent is expected to exist
+    CreateDir { path: PathBuf },
+    /// Move whatever is in `path` out of the way
+    Backup { path: PathBuf, backup: PathBuf },
+    /// Move whatever is in `path` into the backup store, creating the dirs `backup` needs
+    StoreBackup { path: PathBuf, backup: PathBuf },
+    /// Remove a file, symlink or directory (with all its contents)
+    Remove { path: PathBuf },
+    /// Move whatever is in `path` into the repo, replacing `source`
+    Adopt { path: PathBuf, source: PathBuf },
+    Symlink {
+        source: PathBuf,
+        destination: PathBuf,
+        /// Whether `source` is a directory
+        directory: bool,
+    },
+    /// Copy a single file
+    Copy { source: PathBuf, destination: PathBuf },
+    /// Write the result of rendering `template`
+    WriteRendered {
+        template: PathBuf,
+        destination: PathBuf,
+        contents: String,
+    },
+    Run {
+        command: String,
+        program: String,
+        args: Vec<String>,
+        current_dir: PathBuf,
+    },
+    /// `destination` is already in the desired state, so nothing has to be done
+    Unchanged {
+        kind: EntryKind,
+        source: PathBuf,
+        destination: PathBuf,
+    },
+    /// `destination` already exists and is left as it is, instead of deploying `source` into it
+    Skip { source: PathBuf, destination: PathBuf },
+}
+
+impl Display for Operation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::CreateDir { path } => write!(f, "create dir {}", path.display()),
+            Self::Backup { path, backup } => write!(f, "move [src={}] [dst={}]", path.display(), backup.display()),
+            Self::StoreBackup { path, backup } => write!(f, "back up [src={}] [dst={}]", path.display(), backup.display()),
+            Self::Remove { path } => write!(f, "remove {}", path.display()),
+            Self::Adopt { path, source } => write!(f, "adopt {} into {}", path.display(), source.display()),
+            Self::Symlink {
+                source,
+                destination,
+                directory,
+            } => {
+                let kind = if *directory { "dir" } else { "file" };
+                write!(f, "symlink {} {} -> {}", kind, source.display(), destination.display())
+            }
+            Self::Copy { source, destination } => write!(f, "copy file {} -> {}", source.display(), destination.display()),
+            // The contents are only part of the serialized plan, as they can be arbitrarily long
+            Self::WriteRendered {
+                template,
+                destination,
+                contents,
+            } => write!(
+                f,
+                "write {} rendered from {} ({} bytes)",
+                destination.display(),
+                template.display(),
+                contents.len()
+            ),
+            Self::Run {
+                program,
+                args,
+                current_dir,
+                ..
+            } => write!(f, "run [current_dir={}]: {:?} {:?}", current_dir.display(), program, args),
+            Self::Unchanged { source, destination, .. } => write!(f, "leave {} -> {} unchanged", source.display(), destination.display()),
+            Self::Skip { source, destination } => write!(f, "skip {} -> {} as it already exists", source.display(), destination.display()),
+        }
+    }
+}
+
+/// An operation, together with the section it comes from.
+#[derive(Debug, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct PlannedOperation {
+    pub section: String,
+    #[serde(flatten)]
+    pub operation: Operation,
+}
+
+/// Every operation needed to bring the machine to the state described by the config, in the order they have to be
+/// applied.
+#[derive(Debug, Clone, Default, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Plan {
+    pub operations: Vec<PlannedOperation>,
+}
+
+impl Plan {
+    pub fn push(&mut self, section: &str, operation: Operation) {
+        self.operations.push(PlannedOperation {
+            section: section.to_string(),
+            operation,
+        });
+    }
+
+    pub fn extend(&mut self, other: Plan) {
+        self.operations.extend(other.operations);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn serializes_operations_with_their_section() {
+        let mut plan = Plan::default();
+        plan.push(
+            "zsh",
+            Operation::Symlink {
+                source: PathBuf::from("/dotfiles/zshrc"),
+                destination: PathBuf::from("/home/user/.zshrc"),
+                directory: false,
+            },
+        );
+
+        let json = serde_json::to_value(&plan).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "operations": [{
+                    "section": "zsh",
+                    "op": "symlink",
+                    "source": "/dotfiles/zshrc",
+                    "destination": "/home/user/.zshrc",
+                    "directory": false,
+                }]
+            })
+        );
+        assert_eq!(serde_json::from_value::<Plan>(json).unwrap(), plan);
+    }
+
+    #[test]
+    fn rendered_contents_are_only_serialized() {
+        let operation = Operation::WriteRendered {
+            template: PathBuf::from("/dotfiles/config.tpl"),
+            destination: PathBuf::from("/home/user/.config/app/config"),
+            contents: "secret = hunter2\n".to_string(),
+        };
+
+        assert_eq!(
+            operation.to_string(),
+            "write /home/user/.config/app/config rendered from /dotfiles/config.tpl (17 bytes)"
+        );
+        assert_eq!(serde_json::to_value(&operation).unwrap()["contents"], "secret = hunter2\n");
+    }
+}
@@ -0,0 +1,169 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+use std::io::{BufRead, ErrorKind, IsTerminal, Result, Write};
+use std::path::PathBuf;
+
+/// A destination that already exists and is not in the desired state, found with the `prompt` conflict strategy.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Conflict {
+    pub section: String,
+    pub source: PathBuf,
+    pub destination: PathBuf,
+    /// What is found at the destination
+    pub existing: Existing,
+    /// Unified diff from the destination to the incoming contents. Only available for copies and templates of text files.
+    pub diff: Option<String>,
+}
+
+impl Display for Conflict {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[section={}] {} already exists as ", self.section, self.destination.display())?;
+        match &self.existing {
+            Existing::File => write!(f, "a file"),
+            Existing::Directory => write!(f, "a directory"),
+            Existing::Symlink(target) => write!(f, "a symlink to {}", target.display()),
+        }
+    }
+}
+
+/// What is found at a conflicting destination.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Existing {
+    File,
+    Directory,
+    /// A symlink, with its target
+    Symlink(PathBuf),
+}
+
+/// How the user wants a conflict to be resolved.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Answer {
+    /// Remove what exists, as the overwrite strategy does
+    Overwrite,
+    /// Move what exists into the backup store, as the backup strategy does
+    Backup,
+    /// Leave what exists and carry on
+    Skip,
+}
+
+/// Asks the user how to resolve conflicts, so it can be scripted instead of reading from the terminal.
+pub trait Prompter {
+    /// Whether there is anybody to ask. Otherwise, the `prompt_fallback` strategy is used.
+    fn is_interactive(&self) -> bool;
+
+    /// Asks how to resolve `conflict`. The returned flag is set if the answer applies to every conflict that follows.
+    fn ask(&self, conflict: &Conflict) -> Result<(Answer, bool)>;
+}
+
+/// Asks through stderr and reads the answers from stdin.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TerminalPrompter;
+
+impl Prompter for TerminalPrompter {
+    fn is_interactive(&self) -> bool {
+        std::io::stdin().is_terminal()
+    }
+
+    fn ask(&self, conflict: &Conflict) -> Result<(Answer, bool)> {
+        let mut stderr = std::io::stderr();
+        writeln!(stderr, "{}", conflict)?;
+        if let Some(diff) = &conflict.diff {
+            writeln!(stderr, "{}", diff)?;
+        }
+        let stdin = std::io::stdin();
+        loop {
+            write!(stderr, "[o]verwrite, [b]ackup, [s]kip (capitals apply to all)? ")?;
+            stderr.flush()?;
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line)? == 0 {
+                return Err(std::io::Error::new(ErrorKind::UnexpectedEof, "stdin was closed"));
+            }
+            match parse_answer(line.trim()) {
+                Some(answer) => return Ok(answer),
+                None => writeln!(stderr, "Invalid answer: {}", line.trim())?,
+            }
+        }
+    }
+}
+
+fn parse_answer(answer: &str) -> Option<(Answer, bool)> {
+    match answer {
+        "o" => Some((Answer::Overwrite, false)),
+        "b" => Some((Answer::Backup, false)),
+        "s" => Some((Answer::Skip, false)),
+        "O" => Some((Answer::Overwrite, true)),
+        "B" => Some((Answer::Backup, true)),
+        "S" => Some((Answer::Skip, true)),
+        _ => None,
+    }
+}
+
+/// Records every conflict instead of asking, and answers with scripted answers.
+#[derive(Debug)]
+pub struct ScriptedPrompter {
+    interactive: bool,
+    conflicts: RefCell<Vec<Conflict>>,
+    answers: RefCell<VecDeque<(Answer, bool)>>,
+}
+
+impl ScriptedPrompter {
+    pub fn new() -> Self {
+        Self {
+            interactive: true,
+            conflicts: RefCell::new(Vec::new()),
+            answers: RefCell::new(VecDeque::new()),
+        }
+    }
+
+    /// A prompter with nobody to ask, as when stdin is not a terminal.
+    pub fn non_interactive() -> Self {
+        Self {
+            interactive: false,
+            ..Self::new()
+        }
+    }
+
+    /// Queues the answer to the next conflict. Asking once the queue is empty fails.
+    pub fn push_answer(&self, answer: Answer, all: bool) {
+        self.answers.borrow_mut().push_back((answer, all));
+    }
+
+    /// Every conflict asked about so far, in order.
+    pub fn conflicts(&self) -> Vec<Conflict> {
+        self.conflicts.borrow().clone()
+    }
+}
+
+impl Default for ScriptedPrompter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Prompter for ScriptedPrompter {
+    fn is_interactive(&self) -> bool {
+        self.interactive
+    }
+
+    fn ask(&self, conflict: &Conflict) -> Result<(Answer, bool)> {
+        self.conflicts.borrow_mut().push(conflict.clone());
+        self.answers
+            .borrow_mut()
+            .pop_front()
+            .ok_or_else(|| std::io::Error::new(ErrorKind::UnexpectedEof, "no answers left"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_answers() {
+        assert_eq!(parse_answer("o"), Some((Answer::Overwrite, false)));
+        assert_eq!(parse_answer("B"), Some((Answer::Backup, true)));
+        assert_eq!(parse_answer("s"), Some((Answer::Skip, false)));
+        assert_eq!(parse_answer("yes"), None);
+    }
+}
@@ -0,0 +1,163 @@
+use crate::command::CommandRunner;
+use crate::config::{ConflictStrategy, StateConfig};
+use crate::executor::{same_file, Executor};
+use crate::facts::FactsProvider;
+use crate::filesystem::Filesystem;
+use crate::manifest::{hash_bytes, EntryKind};
+use crate::plan::Operation;
+use crate::prompt::Prompter;
+use anyhow::Result;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+
+/// How a destination compares to what the config would deploy into it.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DestinationState {
+    /// Nothing exists in the destination
+    Missing,
+    /// The destination is exactly what would be deployed
+    Correct,
+    /// A symlink is expected, but it points somewhere else
+    LinkPointsElsewhere,
+    /// A symlink is expected, but there is a regular file
+    FileInsteadOfLink,
+    /// The destination is a symlink pointing to something that does not exist
+    BrokenLink,
+    /// A copied file whose contents were changed after being deployed
+    ModifiedLocally,
+    /// A copy or rendered template whose source changed after being deployed
+    OutOfDate,
+    /// Something else is in the way, such as a directory where a file is expected
+    Conflict,
+}
+
+impl DestinationState {
+    pub fn is_drift(&self) -> bool {
+        self != &Self::Correct
+    }
+}
+
+impl Display for DestinationState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::Missing => "missing",
+            Self::Correct => "ok",
+            Self::LinkPointsElsewhere => "link points elsewhere",
+            Self::FileInsteadOfLink => "file instead of link",
+            Self::BrokenLink => "broken link",
+            Self::ModifiedLocally => "modified locally",
+            Self::OutOfDate => "out of date",
+            Self::Conflict => "conflict",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// The state of a single destination the config deploys into.
+#[derive(Debug, Clone, Eq, PartialEq, serde::Serialize)]
+pub struct DestinationStatus {
+    pub section: String,
+    pub kind: EntryKind,
+    pub source: PathBuf,
+    pub destination: PathBuf,
+    pub state: DestinationState,
+}
+
+impl<T, F, C, I> Executor<T, F, C, I>
+where
+    T: FactsProvider,
+    F: Filesystem,
+    C: CommandRunner,
+    I: Prompter,
+{
+    /// Compares what the `requested` sections (or all of them, if None) would deploy against the filesystem, without
+    /// changing anything. Sections and directives whose conditions do not hold on this machine are not checked.
+    ///
+    /// Destinations are resolved by the same planner that deploys them, resolving every conflict by overwriting it, so
+    /// that nothing is asked or aborted. Directories are left out, as whatever has to be deployed inside them is reported.
+    pub fn status<P: AsRef<Path>>(&self, root_dir: P, config: &StateConfig, requested: Option<&[&str]>) -> Result<Vec<DestinationStatus>> {
+        let plan = self.plan_with_strategy(root_dir.as_ref(), config, requested, ConflictStrategy::Overwrite)?;
+        let statuses = plan
+            .operations
+            .into_iter()
+            .filter_map(|planned| {
+                let (kind, source, destination, state) = match planned.operation {
+                    Operation::Unchanged {
+                        kind: EntryKind::Directory,
+                        ..
+                    } => return None,
+                    Operation::Unchanged { kind, source, destination } => (kind, source, destination, DestinationState::Correct),
+                    Operation::Symlink { source, destination, .. } => {
+                        let state = self.link_state(&destination);
+                        (EntryKind::Symlink, source, destination, state)
+                    }
+                    Operation::Copy { source, destination } => {
+                        let state = self.copy_state(&source, &destination);
+                        (EntryKind::Copy, source, destination, state)
+                    }
+                    Operation::WriteRendered { template, destination, .. } => {
+                        let state = self.changed_file_state(&destination, true);
+                        (EntryKind::Template, template, destination, state)
+                    }
+                    _ => return None,
+                };
+                Some(DestinationStatus {
+                    section: planned.section,
+                    kind,
+                    source,
+                    destination,
+                    state,
+                })
+            })
+            .collect();
+        Ok(statuses)
+    }
+
+    /// State of a destination the planner would link, which is not a link to its source.
+    fn link_state(&self, to: &Path) -> DestinationState {
+        match self.filesystem.symlink_metadata(to) {
+            Err(_) => DestinationState::Missing,
+            Ok(metadata) if metadata.is_symlink() && !self.filesystem.exists(to) => DestinationState::BrokenLink,
+            Ok(metadata) if metadata.is_symlink() => DestinationState::LinkPointsElsewhere,
+            Ok(metadata) if metadata.is_file() => DestinationState::FileInsteadOfLink,
+            Ok(_) => DestinationState::Conflict,
+        }
+    }
+
+    /// State of a destination the planner would copy into. Files of a copied directory may be up to date even if the
+    /// directory as a whole is not.
+    fn copy_state(&self, from: &Path, to: &Path) -> DestinationState {
+        match self.filesystem.symlink_metadata(to) {
+            Ok(_) if same_file(&self.filesystem, from, to) => DestinationState::Correct,
+            _ => self.changed_file_state(to, false),
+        }
+    }
+
+    /// Tells apart destinations that were modified by hand from the ones whose source changed, using the manifest.
+    /// Without a manifest, copies are assumed to be modified by hand, and templates to be out of date.
+    fn changed_file_state(&self, to: &Path, rendered: bool) -> DestinationState {
+        let metadata = match self.filesystem.symlink_metadata(to) {
+            Ok(metadata) => metadata,
+            Err(_) => return DestinationState::Missing,
+        };
+        if metadata.is_symlink() && !self.filesystem.exists(to) {
+            return DestinationState::BrokenLink;
+        }
+        if !metadata.is_file() {
+            return DestinationState::Conflict;
+        }
+        let deployed_hash = self
+            .manifest
+            .as_ref()
+            .and_then(|manifest| manifest.manifest().get(to).and_then(|e| e.hash.clone()));
+        match deployed_hash {
+            Some(deployed) if self.filesystem.read(to).ok().map(|c| hash_bytes(&c)).as_ref() == Some(&deployed) => {
+                DestinationState::OutOfDate
+            }
+            Some(_) => DestinationState::ModifiedLocally,
+            None if rendered => DestinationState::OutOfDate,
+            None => DestinationState::ModifiedLocally,
+        }
+    }
+}
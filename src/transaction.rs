@@ -0,0 +1,214 @@
+use crate::command::CommandRunner;
+use crate::executor::Executor;
+use crate::facts::FactsProvider;
+use crate::filesystem::Filesystem;
+use crate::manifest::ManifestEntry;
+use crate::prompt::Prompter;
+use anyhow::{anyhow, Context, Result};
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+
+/// A change applied to the machine, with what is needed to undo it.
+#[derive(Debug, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Change {
+    /// A dir, symlink or file that did not exist before, with the hash of the target of the symlink or the contents of
+    /// the file
+    Created { path: PathBuf, hash: Option<String> },
+    /// Something moved out of the way, such as a backup made by the rename-old strategy
+    Moved { from: PathBuf, to: PathBuf },
+    /// Something moved into the backup store
+    Stored { from: PathBuf, to: PathBuf },
+    /// Something that was removed. It is kept in `stash` until the transaction is committed, or for good when the run
+    /// is journaled.
+    Removed { path: PathBuf, stash: PathBuf },
+    /// An update of the manifest, with the entry it replaced
+    Recorded {
+        destination: PathBuf,
+        previous: Option<ManifestEntry>,
+    },
+    /// A command, which cannot be undone
+    Ran(String),
+}
+
+impl Display for Change {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Change::Created { path, .. } => write!(f, "creation of {}", path.display()),
+            Change::Moved { from, to } | Change::Stored { from, to } => {
+                write!(f, "move [src={}] -> [dst={}]", from.display(), to.display())
+            }
+            Change::Removed { path, .. } => write!(f, "removal of {}", path.display()),
+            Change::Recorded { destination, .. } => write!(f, "manifest update of {}", destination.display()),
+            Change::Ran(command) => write!(f, "command {}", command),
+        }
+    }
+}
+
+impl<T, F, C, I> Executor<T, F, C, I>
+where
+    T: FactsProvider,
+    F: Filesystem,
+    C: CommandRunner,
+    I: Prompter,
+{
+    /// Runs `f` as a transaction: every change it applies is journaled, and if it fails, they are all undone in
+    /// reverse order, so the machine is left as it was before. Commands that were run cannot be undone.
+    ///
+    /// The returned error includes both the original error and the outcome of the rollback. Transactions started
+    /// inside `f` are part of this one.
+    pub fn transaction<R>(&self, f: impl FnOnce(&Self) -> Result<R>) -> Result<R> {
+        if self.journal.borrow().is_some() || self.dry_run {
+            return f(self);
+        }
+        *self.journal.borrow_mut() = Some(Vec::new());
+        let result = f(self);
+        let changes = self.journal.borrow_mut().take().unwrap_or_default();
+        match result {
+            Ok(value) => {
+                self.commit(&changes);
+                Ok(value)
+            }
+            Err(e) => Err(match self.rollback(changes) {
+                Ok(summary) => {
+                    if let Some(journal) = &self.run_journal {
+                        if let Err(mark_error) = journal.mark_undone(&self.filesystem, journal.run()) {
+                            warn!("Could not mark run {} as undone: {:#}", journal.run(), mark_error);
+                        }
+                    }
+                    e.context(format!("Run failed and {}", summary))
+                }
+                Err(rollback_error) => e.context(format!(
+                    "Run failed and rolling back its changes failed too, so the machine may be left half-configured: {:#}",
+                    rollback_error
+                )),
+            }),
+        }
+    }
+
+    /// Whether changes are being journaled, so removed files have to be kept until the end of the transaction.
+    pub(crate) fn in_transaction(&self) -> bool {
+        self.journal.borrow().is_some()
+    }
+
+    /// Journals a change applied by `section`, both in the current transaction and in the run journal.
+    pub(crate) fn journal(&self, section: &str, change: Change) -> Result<()> {
+        if let Some(run_journal) = &self.run_journal {
+            run_journal.append(&self.filesystem, section, change.clone())?;
+        }
+        if let Some(journal) = self.journal.borrow_mut().as_mut() {
+            journal.push(change);
+        }
+        Ok(())
+    }
+
+    /// Path next to `path` where it can be kept while the transaction is in progress.
+    pub(crate) fn stash_path(&self, path: &Path) -> PathBuf {
+        let mut counter = 0;
+        loop {
+            let stash = PathBuf::from(format!("{}.dotfilers-removed{}", path.display(), counter));
+            if self.filesystem.symlink_metadata(&stash).is_err() {
+                return stash;
+            }
+            counter += 1;
+        }
+    }
+
+    /// Gets rid of whatever was kept to be able to undo the transaction, unless the run journal keeps it.
+    fn commit(&self, changes: &[Change]) {
+        if self.run_journal.is_some() {
+            return;
+        }
+        for change in changes {
+            if let Change::Removed { stash, .. } = change {
+                debug!("Removing {}", stash.display());
+                if let Err(e) = self.filesystem.remove(stash) {
+                    warn!("Could not remove {}: {}", stash.display(), e);
+                }
+            }
+        }
+    }
+
+    /// Undoes every change, even if undoing some of them fails. Returns a summary of what was done.
+    pub(crate) fn rollback(&self, changes: Vec<Change>) -> Result<String> {
+        warn!("Rolling back {} change(s)", changes.len());
+        let mut undone = 0;
+        let mut commands = Vec::new();
+        let mut failures = Vec::new();
+        for change in changes.into_iter().rev() {
+            match change {
+                Change::Ran(command) => {
+                    warn!("Cannot undo command {}", command);
+                    commands.push(command);
+                }
+                change => match self.undo(&change) {
+                    // Manifest updates are not counted, as they are not changes to the machine
+                    Ok(()) if matches!(change, Change::Recorded { .. }) => {}
+                    Ok(()) => undone += 1,
+                    Err(e) => {
+                        error!("Error rolling back {:?}: {:#}", change, e);
+                        failures.push(format!("{:#}", e));
+                    }
+                },
+            }
+        }
+
+        if !failures.is_empty() {
+            return Err(anyhow!("{} change(s) could not be undone: {}", failures.len(), failures.join("; ")));
+        }
+        let mut summary = format!("{} change(s) were rolled back", undone);
+        if !commands.is_empty() {
+            summary.push_str(&format!(". These commands cannot be undone: {}", commands.join(", ")));
+        }
+        info!("{}", summary);
+        Ok(summary)
+    }
+
+    fn undo(&self, change: &Change) -> Result<()> {
+        match change {
+            Change::Created { path, hash } => {
+                // Whatever was changed or added since then is not thrown away with it
+                let metadata = self
+                    .filesystem
+                    .symlink_metadata(path)
+                    .context(format!("Error reading metadata of {}", path.display()))?;
+                if metadata.is_dir() {
+                    if !self.filesystem.read_dir(path)?.is_empty() {
+                        return Err(anyhow!("Refusing to remove {} as it is not empty", path.display()));
+                    }
+                } else if self.hash_of(path)? != *hash {
+                    return Err(anyhow!("Refusing to remove {} as it changed since it was created", path.display()));
+                }
+                debug!("Rollback: removing {}", path.display());
+                self.filesystem.remove(path).context(format!("Error removing {}", path.display()))
+            }
+            Change::Moved { from, to } | Change::Removed { path: from, stash: to } => {
+                debug!("Rollback: moving [src={}] -> [dst={}]", to.display(), from.display());
+                self.filesystem
+                    .rename(to, from)
+                    .context(format!("Error moving [src={}] -> [dst={}]", to.display(), from.display()))
+            }
+            Change::Stored { from, to } => {
+                debug!("Rollback: moving [src={}] -> [dst={}]", to.display(), from.display());
+                self.filesystem
+                    .rename(to, from)
+                    .context(format!("Error moving [src={}] -> [dst={}]", to.display(), from.display()))?;
+                self.backup_store()?
+                    .forget(&self.filesystem, to)
+                    .context(format!("Error forgetting backup {}", to.display()))
+            }
+            Change::Recorded { destination, previous } => {
+                let manifest = match &self.manifest {
+                    Some(manifest) => manifest,
+                    None => return Ok(()),
+                };
+                match previous {
+                    Some(previous) => manifest.record(&self.filesystem, previous.clone()),
+                    None => manifest.forget(&self.filesystem, destination).map(|_| ()),
+                }
+                .context(format!("Error restoring manifest entry of {}", destination.display()))
+            }
+            Change::Ran(_) => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,175 @@
+use crate::command::CommandRunner;
+use crate::config::did_you_mean;
+use crate::executor::Executor;
+use crate::facts::FactsProvider;
+use crate::filesystem::Filesystem;
+use crate::manifest::{hash_bytes, EntryKind, ManifestEntry};
+use crate::prompt::Prompter;
+use anyhow::{anyhow, Context, Result};
+use std::path::Path;
+
+impl<T, F, C, I> Executor<T, F, C, I>
+where
+    T: FactsProvider,
+    F: Filesystem,
+    C: CommandRunner,
+    I: Prompter,
+{
+    /// Removes everything the manifest says was deployed for `sections` (or for every section, if empty), restoring
+    /// the backups made when deploying them.
+    ///
+    /// Files that were modified after being deployed are only removed if `force` is set. Otherwise, nothing is removed.
+    /// Sections that did not deploy anything are rejected, together with a suggestion in case of a typo.
+    pub fn undeploy(&self, sections: &[&str], force: bool) -> Result<()> {
+        let manifest = self
+            .manifest
+            .as_ref()
+            .ok_or_else(|| anyhow!("Cannot undeploy without a manifest"))?;
+        let mut deployed: Vec<String> = manifest.manifest().entries.iter().map(|e| e.section.clone()).collect();
+        deployed.sort_unstable();
+        deployed.dedup();
+        let deployed: Vec<&str> = deployed.iter().map(String::as_str).collect();
+        if let Some(unknown) = sections.iter().find(|s| !deployed.contains(s)) {
+            return Err(match did_you_mean(unknown, &deployed) {
+                Some(suggestion) => anyhow!("Nothing was deployed for section {} (did you mean {}?)", unknown, suggestion),
+                None => anyhow!("Nothing was deployed for section {}", unknown),
+            });
+        }
+        let mut entries: Vec<ManifestEntry> = manifest
+            .manifest()
+            .entries
+            .iter()
+            .filter(|e| sections.is_empty() || sections.contains(&e.section.as_str()))
+            .cloned()
+            .collect();
+        // Undo in reverse order, so the contents of a directory are removed before the directory itself
+        entries.reverse();
+
+        let modified: Vec<&ManifestEntry> = entries.iter().filter(|e| is_modified(&self.filesystem, e)).collect();
+        if !modified.is_empty() {
+            let destinations = modified
+                .iter()
+                .map(|e| e.destination.display().to_string())
+                .collect::<Vec<String>>()
+                .join(", ");
+            if !force {
+                return Err(anyhow!(
+                    "Refusing to undeploy files that changed since they were deployed: {}. Use force to remove them anyway",
+                    destinations
+                ));
+            }
+            warn!("Removing files that changed since they were deployed: {}", destinations);
+        }
+
+        for entry in &entries {
+            self.undeploy_entry(entry).context(format!(
+                "Error undeploying [section={}] {}",
+                entry.section,
+                entry.destination.display()
+            ))?;
+            if !self.dry_run {
+                manifest
+                    .forget(&self.filesystem, &entry.destination)
+                    .context("Error updating manifest")?;
+            }
+        }
+        Ok(())
+    }
+
+    fn undeploy_entry(&self, entry: &ManifestEntry) -> Result<()> {
+        let destination = &entry.destination;
+        let exists = self.filesystem.symlink_metadata(destination).is_ok();
+        match entry.kind {
+            _ if !exists => debug!("{} does not exist anymore", destination.display()),
+            EntryKind::Directory => {
+                let is_empty = self
+                    .filesystem
+                    .read_dir(destination)
+                    .context(format!("Error getting dir contents of {}", destination.display()))?
+                    .is_empty();
+                if !is_empty {
+                    info!("Keeping dir {} as it is not empty", destination.display());
+                    return Ok(());
+                }
+                if self.dry_run {
+                    info!("Would remove dir {}", destination.display());
+                } else {
+                    self.filesystem
+                        .remove(destination)
+                        .context(format!("Error removing dir {}", destination.display()))?;
+                    info!("Removed dir {}", destination.display());
+                }
+            }
+            EntryKind::Symlink | EntryKind::Copy | EntryKind::Template => {
+                let metadata = self.filesystem.symlink_metadata(destination)?;
+                if metadata.is_dir() {
+                    return Err(anyhow!("{} has been replaced by a directory", destination.display()));
+                }
+                if self.dry_run {
+                    info!("Would remove {}", destination.display());
+                } else {
+                    let kind = if metadata.is_symlink() { "symlink" } else { "file" };
+                    self.filesystem
+                        .remove(destination)
+                        .context(format!("Error removing {} {}", kind, destination.display()))?;
+                    info!("Removed {} {}", kind, destination.display());
+                }
+            }
+        }
+
+        if let Some(backup) = &entry.backup {
+            self.restore_backup(backup, destination)?;
+        }
+        Ok(())
+    }
+
+    fn restore_backup(&self, backup: &Path, destination: &Path) -> Result<()> {
+        if self.filesystem.symlink_metadata(backup).is_err() {
+            warn!("Backup {} of {} does not exist anymore", backup.display(), destination.display());
+            return Ok(());
+        }
+        if self.dry_run {
+            info!("Would restore backup [src={}] -> [dst={}]", backup.display(), destination.display());
+            return Ok(());
+        }
+        if self.filesystem.symlink_metadata(destination).is_ok() {
+            return Err(anyhow!(
+                "Cannot restore backup {} as {} still exists",
+                backup.display(),
+                destination.display()
+            ));
+        }
+        self.filesystem.rename(backup, destination).context(format!(
+            "Error restoring backup [src={}] -> [dst={}]",
+            backup.display(),
+            destination.display()
+        ))?;
+        if let Some(store) = &self.backup_store {
+            store.forget(&self.filesystem, backup)?;
+        }
+        info!("Restored backup [src={}] -> [dst={}]", backup.display(), destination.display());
+        Ok(())
+    }
+}
+
+/// Whether a deployed file changed since it was deployed. Files that do not exist anymore are not modified.
+fn is_modified(fs: &dyn Filesystem, entry: &ManifestEntry) -> bool {
+    let destination = &entry.destination;
+    let metadata = match fs.symlink_metadata(destination) {
+        Ok(metadata) => metadata,
+        Err(_) => return false,
+    };
+    let current_hash = match entry.kind {
+        EntryKind::Directory => return !metadata.is_dir(),
+        EntryKind::Symlink if metadata.is_symlink() => match fs.read_link(destination) {
+            Ok(target) => hash_bytes(target.to_string_lossy().as_bytes()),
+            Err(_) => return true,
+        },
+        EntryKind::Copy | EntryKind::Template if metadata.is_file() => match fs.read(destination) {
+            Ok(contents) => hash_bytes(&contents),
+            Err(_) => return true,
+        },
+        _ => return true,
+    };
+    entry.hash.as_ref() != Some(&current_hash)
+}
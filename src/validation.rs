@@ -0,0 +1,182 @@
+use crate::command::CommandRunner;
+use crate::config::{Directive, StateConfig};
+use crate::executor::{expand_glob, is_glob, Executor};
+use crate::facts::FactsProvider;
+use crate::filesystem::Filesystem;
+use crate::prompt::Prompter;
+use anyhow::{anyhow, Context, Result};
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use tera::Tera;
+
+/// A problem found while validating a config.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Problem {
+    /// Config file the problem was found in
+    pub file: PathBuf,
+    pub section: Option<String>,
+    pub index: Option<usize>,
+    pub message: String,
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.file.display())?;
+        if let Some(section) = &self.section {
+            write!(f, " [section={}]", section)?;
+        }
+        if let Some(index) = &self.index {
+            write!(f, " [index={}]", index)?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+impl<T, F, C, I> Executor<T, F, C, I>
+where
+    T: FactsProvider,
+    F: Filesystem,
+    C: CommandRunner,
+    I: Prompter,
+{
+    /// Statically checks a config and every file it includes, without executing any directive.
+    ///
+    /// `file` is the path `config` was loaded from, and is only used to report where problems were found.
+    /// All directives are checked, regardless of their conditions.
+    pub fn validate<P: AsRef<Path>, Q: AsRef<Path>>(&self, root_dir: P, file: Q, config: &StateConfig) -> Vec<Problem> {
+        let file = file.as_ref();
+        let mut problems = Vec::new();
+        if let Err(e) = self.validate_shell() {
+            problems.push(Problem {
+                file: file.to_path_buf(),
+                section: None,
+                index: None,
+                message: format!("{:#}", e),
+            });
+        }
+
+        let mut include_stack = vec![file.to_path_buf()];
+        self.validate_state(root_dir.as_ref(), file, config, &mut include_stack, &mut problems);
+        problems
+    }
+
+    fn validate_shell(&self) -> Result<()> {
+        let program = match self.shell.split(' ').next() {
+            Some(program) if !program.is_empty() => program,
+            _ => return Err(anyhow!("Shell definition is empty")),
+        };
+        match self.facts()?.find_in_path(&self.filesystem, program) {
+            Some(path) => {
+                debug!("Found shell {}", path.display());
+                Ok(())
+            }
+            None => Err(anyhow!("Could not find shell {}", program)),
+        }
+    }
+
+    fn validate_state(
+        &self,
+        root_dir: &Path,
+        file: &Path,
+        config: &StateConfig,
+        include_stack: &mut Vec<PathBuf>,
+        problems: &mut Vec<Problem>,
+    ) {
+        for (name, section) in &config.states {
+            for (index, step) in section.steps.iter().enumerate() {
+                debug!("Validating [file={}] [section={}] [index={}]", file.display(), name, index);
+                let errors: Vec<anyhow::Error> = match &step.directive {
+                    Directive::Link { from, to, .. } | Directive::Copy { from, to } => {
+                        validate_source(&self.filesystem, root_dir, from, to).err().into_iter().collect()
+                    }
+                    Directive::Template { template, vars, .. } => validate_template(&self.filesystem, root_dir, template, vars),
+                    Directive::Include(path) => self
+                        .validate_include(root_dir, path, include_stack, problems)
+                        .err()
+                        .into_iter()
+                        .collect(),
+                    Directive::Run(_) => Vec::new(),
+                };
+                for e in errors {
+                    problems.push(Problem {
+                        file: file.to_path_buf(),
+                        section: Some(name.to_string()),
+                        index: Some(index),
+                        message: format!("{:#}", e),
+                    });
+                }
+            }
+        }
+    }
+
+    fn validate_include(&self, root_dir: &Path, path: &str, include_stack: &mut Vec<PathBuf>, problems: &mut Vec<Problem>) -> Result<()> {
+        let yaml_path = root_dir.join(path);
+        if !self.filesystem.is_file(&yaml_path) {
+            return Err(anyhow!("Could not find file to include {}", yaml_path.display()));
+        }
+        let canonical = self
+            .filesystem
+            .canonicalize(&yaml_path)
+            .context(format!("Error resolving path {}", yaml_path.display()))?;
+        if include_stack
+            .iter()
+            .any(|p| self.filesystem.canonicalize(p).ok().as_ref() == Some(&canonical))
+        {
+            return Err(anyhow!("File {} includes itself", yaml_path.display()));
+        }
+
+        let contents = self
+            .filesystem
+            .read_to_string(&yaml_path)
+            .context(format!("Error loading included file {}", yaml_path.display()))?;
+        let config = StateConfig::from_yaml_with_strictness(&contents, self.strict)
+            .map_err(|e| e.with_file(&yaml_path))
+            .context(format!("Error parsing included file {}", yaml_path.display()))?;
+
+        let included_root_dir = yaml_path.parent().unwrap_or(root_dir);
+        include_stack.push(yaml_path.clone());
+        self.validate_state(included_root_dir, &yaml_path, &config, include_stack, problems);
+        include_stack.pop();
+        Ok(())
+    }
+}
+
+fn validate_source(fs: &dyn Filesystem, root_dir: &Path, from: &str, to: &str) -> Result<()> {
+    if is_glob(from) {
+        if expand_glob(fs, root_dir, from, to)?.is_empty() {
+            return Err(anyhow!("Glob {} does not match any file", root_dir.join(from).display()));
+        }
+    } else if !fs.exists(&root_dir.join(from)) {
+        return Err(anyhow!("From does not exist: {}", root_dir.join(from).display()));
+    }
+    Ok(())
+}
+
+/// Checks the template and its vars file, returning every problem found in them.
+fn validate_template(fs: &dyn Filesystem, root_dir: &Path, template: &str, vars: &Option<String>) -> Vec<anyhow::Error> {
+    let mut errors = Vec::new();
+    let template_path = root_dir.join(template);
+    if !fs.is_file(&template_path) {
+        errors.push(anyhow!("Could not find template {}", template_path.display()));
+    } else {
+        let parsed = fs
+            .read_to_string(&template_path)
+            .context(format!("Error reading template contents: {}", template_path.display()))
+            .and_then(|contents| {
+                Tera::default()
+                    .add_raw_template(template, &contents)
+                    .context(format!("Error parsing template {}", template_path.display()))
+            });
+        if let Err(e) = parsed {
+            errors.push(e);
+        }
+    }
+
+    if let Some(vars) = vars {
+        let vars_path = root_dir.join(vars);
+        if !fs.is_file(&vars_path) {
+            errors.push(anyhow!("Could not find vars file {}", vars_path.display()));
+        }
+    }
+    errors
+}
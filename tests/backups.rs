@@ -0,0 +1,179 @@
+use crate::test_tools::*;
+use chrono::{Duration, TimeZone, Utc};
+use dotfilers::{BackupStore, CommandOutput, Config, ConflictStrategy, Filesystem, MemoryFilesystem, RecordingCommandRunner, Retention};
+use std::path::{Path, PathBuf};
+
+const STORE: &str = "/home/tester/.local/share/dotfilers/backups";
+
+const CONFIG: &str = r#"
+shell:
+  - link_from: zshrc
+    link_to: /home/tester/.zshrc
+  - copy_from: gitconfig
+    copy_to: /home/tester/.config/git/config
+"#;
+
+fn dotfiles() -> MemoryFilesystem {
+    let fs = MemoryFilesystem::new();
+    fs.add_file("/dotfiles/zshrc", "export EDITOR=vim").unwrap();
+    fs.add_file("/dotfiles/gitconfig", "[user]").unwrap();
+    fs.add_file("/home/tester/.zshrc", "old zshrc").unwrap();
+    fs.add_file("/home/tester/.config/git/config", "old gitconfig").unwrap();
+    fs
+}
+
+fn store_at(year: i32) -> BackupStore {
+    BackupStore::started_at(STORE, Utc.with_ymd_and_hms(year, 1, 1, 0, 0, 0).unwrap())
+}
+
+#[test]
+fn displaced_files_are_moved_into_the_store() {
+    let mut executor = memory_executor(dotfiles(), ConflictStrategy::Backup);
+    executor.backup_store = Some(store_at(2024));
+    deploy(&executor, CONFIG, None);
+
+    let fs = &executor.filesystem;
+    let run_dir = PathBuf::from(STORE).join("2024-01-01T00-00-00.000Z");
+    assert!(fs.symlink_metadata(Path::new("/home/tester/.zshrc")).unwrap().is_symlink());
+    assert_eq!(fs.read_to_string(Path::new("/home/tester/.config/git/config")).unwrap(), "[user]");
+    assert_eq!(fs.read_to_string(&run_dir.join("home/tester/.zshrc")).unwrap(), "old zshrc");
+    assert_eq!(
+        fs.read_to_string(&run_dir.join("home/tester/.config/git/config")).unwrap(),
+        "old gitconfig"
+    );
+    // Nothing is left next to the originals
+    assert_eq!(
+        fs.read_dir(Path::new("/home/tester")).unwrap(),
+        vec![
+            PathBuf::from("/home/tester/.config"),
+            PathBuf::from("/home/tester/.local"),
+            PathBuf::from("/home/tester/.zshrc")
+        ]
+    );
+
+    let runs = executor.backup_store.as_ref().unwrap().runs(fs).unwrap();
+    assert_eq!(runs.len(), 1);
+    assert_eq!(runs[0].id, "2024-01-01T00-00-00.000Z");
+    assert_eq!(runs[0].started_at, "2024-01-01T00:00:00+00:00");
+    let originals: Vec<&Path> = runs[0].entries.iter().map(|e| e.original.as_path()).collect();
+    assert_eq!(
+        originals,
+        vec![Path::new("/home/tester/.zshrc"), Path::new("/home/tester/.config/git/config")]
+    );
+    assert!(runs[0].entries.iter().all(|e| e.section == "shell"));
+}
+
+#[test]
+fn restore_moves_backups_back() {
+    let mut executor = memory_executor(dotfiles(), ConflictStrategy::Backup);
+    executor.backup_store = Some(store_at(2024));
+    deploy(&executor, CONFIG, None);
+    let run = "2024-01-01T00-00-00.000Z";
+
+    let error = executor
+        .restore_backups(run, &[], false)
+        .expect_err("Should not replace deployed files");
+    assert!(format!("{:#}", error).contains("Refusing to restore backups"), "{:#}", error);
+
+    executor.dry_run = true;
+    executor.restore_backups(run, &[], true).expect("Should be able to restore");
+    assert!(executor
+        .filesystem
+        .symlink_metadata(Path::new("/home/tester/.zshrc"))
+        .unwrap()
+        .is_symlink());
+
+    executor.dry_run = false;
+    executor
+        .restore_backups(run, &[PathBuf::from("/home/tester/.zshrc")], true)
+        .expect("Should be able to restore");
+    let fs = &executor.filesystem;
+    assert_eq!(fs.read_to_string(Path::new("/home/tester/.zshrc")).unwrap(), "old zshrc");
+    let runs = executor.backup_store.as_ref().unwrap().runs(fs).unwrap();
+    assert_eq!(runs[0].entries.len(), 1);
+
+    executor.restore_backups(run, &[], true).expect("Should be able to restore");
+    let fs = &executor.filesystem;
+    assert_eq!(
+        fs.read_to_string(Path::new("/home/tester/.config/git/config")).unwrap(),
+        "old gitconfig"
+    );
+    // Runs are removed once everything has been restored
+    assert!(executor.backup_store.as_ref().unwrap().runs(fs).unwrap().is_empty());
+    assert!(fs.read_dir(Path::new(STORE)).unwrap().is_empty());
+}
+
+#[test]
+fn undeploy_restores_from_the_store() {
+    let mut executor = memory_executor(dotfiles(), ConflictStrategy::Backup);
+    executor.backup_store = Some(store_at(2024));
+    open_manifest(&mut executor);
+    deploy(&executor, CONFIG, None);
+
+    executor.undeploy(&[], false).expect("Should be able to undeploy");
+
+    let fs = &executor.filesystem;
+    assert_eq!(fs.read_to_string(Path::new("/home/tester/.zshrc")).unwrap(), "old zshrc");
+    assert!(executor.backup_store.as_ref().unwrap().runs(fs).unwrap().is_empty());
+}
+
+#[test]
+fn prune_by_count_and_age() {
+    let mut executor = memory_executor(dotfiles(), ConflictStrategy::Backup);
+    let recent = Utc::now() - Duration::days(1);
+    for started_at in [
+        Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap(),
+        Utc.with_ymd_and_hms(2021, 1, 1, 0, 0, 0).unwrap(),
+        recent,
+    ] {
+        // Every run finds a hand-edited file in the way
+        executor
+            .filesystem
+            .add_file("/home/tester/.config/git/config", "edited gitconfig")
+            .unwrap();
+        executor.backup_store = Some(BackupStore::started_at(STORE, started_at));
+        deploy(&executor, CONFIG, None);
+    }
+    let store = executor.backup_store.clone().unwrap();
+    assert_eq!(store.runs(&executor.filesystem).unwrap().len(), 3);
+
+    let pruned = executor
+        .prune_backups(Retention {
+            keep: Some(2),
+            max_age: None,
+        })
+        .unwrap();
+    assert_eq!(pruned, vec!["2020-01-01T00-00-00.000Z".to_string()]);
+
+    let pruned = executor
+        .prune_backups(Retention {
+            keep: None,
+            max_age: Some(Duration::days(30)),
+        })
+        .unwrap();
+    assert_eq!(pruned, vec!["2021-01-01T00-00-00.000Z".to_string()]);
+    let runs = store.runs(&executor.filesystem).unwrap();
+    assert_eq!(runs.len(), 1);
+    assert_eq!(runs[0].id, store.run());
+}
+
+#[test]
+fn rollback_empties_the_store() {
+    let runner = RecordingCommandRunner::new();
+    runner.push_output(CommandOutput::exit_code(1));
+    let mut executor = memory_executor(dotfiles(), ConflictStrategy::Backup).with_command_runner(runner);
+    executor.backup_store = Some(store_at(2024));
+    let config = Config::from_yaml(&format!("{}  - run: ./install.sh\n", CONFIG)).unwrap();
+
+    executor
+        .transaction(|executor| executor.execute_section("/dotfiles", "shell", &config.state_config.states["shell"]))
+        .expect_err("Should fail");
+
+    let fs = &executor.filesystem;
+    assert_eq!(fs.read_to_string(Path::new("/home/tester/.zshrc")).unwrap(), "old zshrc");
+    assert_eq!(
+        fs.read_to_string(Path::new("/home/tester/.config/git/config")).unwrap(),
+        "old gitconfig"
+    );
+    assert!(executor.backup_store.as_ref().unwrap().runs(fs).unwrap().is_empty());
+}
@@ -0,0 +1,112 @@
+use crate::test_tools::*;
+use dotfilers::{Condition, Config, ConflictStrategy, Directive, DirectiveStep, Filesystem, MemoryFilesystem};
+use std::path::Path;
+
+fn copy_step(condition: Condition, from: &str, to: &str) -> DirectiveStep {
+    DirectiveStep {
+        condition,
+        on_error: None,
+        conflict_strategy: None,
+        directive: Directive::Copy {
+            from: from.to_string(),
+            to: to.to_string(),
+        },
+    }
+}
+
+#[test]
+fn only_matching_directives_are_executed() {
+    let fs = MemoryFilesystem::new();
+    fs.add_file("/dotfiles/source", "contents").unwrap();
+    fs.add_file("/dotfiles/marker", "").unwrap();
+
+    let executor = memory_executor(fs, ConflictStrategy::Overwrite);
+    executor
+        .execute(
+            "/dotfiles",
+            "test",
+            &[
+                copy_step(
+                    Condition::All(vec![
+                        Condition::IfHostname("work-*".to_string()),
+                        Condition::IfFileExists("marker".to_string()),
+                        Condition::IfContainer,
+                    ]),
+                    "source",
+                    "matched",
+                ),
+                copy_step(
+                    Condition::Any(vec![
+                        Condition::IfUser("root".to_string()),
+                        Condition::IfFileExists("missing".to_string()),
+                    ]),
+                    "source",
+                    "any_not_matched",
+                ),
+                copy_step(
+                    Condition::Not(Box::new(Condition::IfEnvEquals {
+                        name: "WORK".to_string(),
+                        value: "1".to_string(),
+                    })),
+                    "source",
+                    "not_not_matched",
+                ),
+            ],
+        )
+        .expect("Should be able to execute");
+
+    let fs = &executor.filesystem;
+    assert!(fs.exists(Path::new("/dotfiles/matched")));
+    assert!(!fs.exists(Path::new("/dotfiles/any_not_matched")));
+    assert!(!fs.exists(Path::new("/dotfiles/not_not_matched")));
+}
+
+#[test]
+fn expressions_that_cannot_be_evaluated_fail() {
+    let fs = MemoryFilesystem::new();
+    fs.add_file("/dotfiles/source", "contents").unwrap();
+    let config = Config::from_yaml("test:\n  - if: os > 2\n    copy_from: source\n    copy_to: dest\n").unwrap();
+
+    let executor = memory_executor(fs, ConflictStrategy::Overwrite);
+    let error = executor
+        .execute_section("/dotfiles", "test", &config.state_config.states["test"])
+        .expect_err("Should fail");
+
+    let message = format!("{:#}", error);
+    assert!(message.contains("[section=test]"), "{}", message);
+    assert!(message.contains("os > 2"), "{}", message);
+    assert!(!executor.filesystem.exists(Path::new("/dotfiles/dest")));
+}
+
+#[test]
+#[cfg(unix)]
+fn command_condition_uses_path() {
+    let fs = MemoryFilesystem::new();
+    fs.add_file("/dotfiles/source", "contents").unwrap();
+    fs.add_file("/opt/tools/bin/mytool", "#!/bin/sh\n").unwrap();
+    fs.set_mode("/opt/tools/bin/mytool", 0o755).unwrap();
+    fs.add_file("/opt/tools/bin/notexecutable", "").unwrap();
+    let mut facts = fake_facts();
+    facts.env.insert("PATH".to_string(), "/usr/bin:/opt/tools/bin".to_string());
+
+    let executor = memory_executor(fs, ConflictStrategy::Overwrite).with_facts_provider(FakeFactsProvider(facts));
+    executor
+        .execute(
+            "/dotfiles",
+            "test",
+            &[
+                copy_step(Condition::IfCommand("mytool".to_string()), "source", "with_tool"),
+                copy_step(Condition::IfCommand("notexecutable".to_string()), "source", "without_tool"),
+                copy_step(
+                    Condition::IfCommand("/opt/tools/bin/mytool".to_string()),
+                    "source",
+                    "with_full_path",
+                ),
+            ],
+        )
+        .expect("Should be able to execute");
+
+    assert!(executor.filesystem.exists(Path::new("/dotfiles/with_tool")));
+    assert!(!executor.filesystem.exists(Path::new("/dotfiles/without_tool")));
+    assert!(executor.filesystem.exists(Path::new("/dotfiles/with_full_path")));
+}
@@ -0,0 +1,252 @@
+use crate::test_tools::*;
+use dotfilers::{
+    CommandOutput, Config, ConflictStrategy, Directive, DirectiveStep, Filesystem, LinkDirectoryBehaviour, MemoryFilesystem, Operation,
+    RecordingCommandRunner,
+};
+use std::path::{Path, PathBuf};
+
+fn link(from: &Path, to: &Path, directory_behaviour: LinkDirectoryBehaviour) -> DirectiveStep {
+    step(Directive::Link {
+        from: from.display().to_string(),
+        to: to.display().to_string(),
+        directory_behaviour,
+    })
+}
+
+fn repo_and_dest() -> MemoryFilesystem {
+    let fs = MemoryFilesystem::new();
+    fs.add_file("/dotfiles/repo_file", "from the repo").unwrap();
+    fs.add_file("/dotfiles/dest_file", "hand edited").unwrap();
+    fs
+}
+
+#[test]
+fn skip_leaves_destination_alone() {
+    let config = Config::from_yaml("test:\n  - link_from: repo_file\n    link_to: /dotfiles/dest_file\n").unwrap();
+
+    let executor = memory_executor(repo_and_dest(), ConflictStrategy::Skip);
+    let plan = executor.plan("/dotfiles", &config.state_config, None).unwrap();
+    assert_eq!(
+        plan.operations[0].operation,
+        Operation::Skip {
+            source: PathBuf::from("/dotfiles/repo_file"),
+            destination: PathBuf::from("/dotfiles/dest_file"),
+        }
+    );
+
+    executor
+        .execute_section("/dotfiles", "test", &config.state_config.states["test"])
+        .expect("Should be able to execute");
+    let fs = &executor.filesystem;
+    assert!(!fs.symlink_metadata(Path::new("/dotfiles/dest_file")).unwrap().is_symlink());
+    assert_eq!(fs.read_to_string(Path::new("/dotfiles/dest_file")).unwrap(), "hand edited");
+}
+
+#[test]
+fn skip_with_create_dir_does_not_recurse() {
+    let fs = MemoryFilesystem::new();
+    fs.add_file("/dotfiles/original/afile", "contents").unwrap();
+    fs.add_file("/dotfiles/dest", "not a dir").unwrap();
+
+    let executor = memory_executor(fs, ConflictStrategy::Skip);
+    executor
+        .execute(
+            "/dotfiles",
+            "test",
+            &[link(
+                Path::new("/dotfiles/original"),
+                Path::new("/dotfiles/dest"),
+                LinkDirectoryBehaviour::CreateDirectory,
+            )],
+        )
+        .expect("Should be able to execute");
+
+    assert_eq!(
+        executor.filesystem.read_to_string(Path::new("/dotfiles/dest")).unwrap(),
+        "not a dir"
+    );
+}
+
+#[test]
+fn adopt_moves_destination_into_the_repo() {
+    let executor = memory_executor(repo_and_dest(), ConflictStrategy::Adopt);
+    executor
+        .execute(
+            "/dotfiles",
+            "test",
+            &[link(
+                Path::new("/dotfiles/repo_file"),
+                Path::new("/dotfiles/dest_file"),
+                LinkDirectoryBehaviour::default(),
+            )],
+        )
+        .expect("Should be able to execute");
+
+    let fs = &executor.filesystem;
+    assert!(fs.symlink_metadata(Path::new("/dotfiles/dest_file")).unwrap().is_symlink());
+    assert_eq!(
+        fs.read_link(Path::new("/dotfiles/dest_file")).unwrap(),
+        PathBuf::from("/dotfiles/repo_file")
+    );
+    assert_eq!(fs.read_to_string(Path::new("/dotfiles/repo_file")).unwrap(), "hand edited");
+}
+
+#[test]
+fn adopt_directories() {
+    let fs = MemoryFilesystem::new();
+    fs.add_file("/dotfiles/original/from_repo", "from the repo").unwrap();
+    fs.add_file("/dotfiles/dest/hand_made", "hand edited").unwrap();
+
+    let executor = memory_executor(fs, ConflictStrategy::Adopt);
+    executor
+        .execute(
+            "/dotfiles",
+            "test",
+            &[link(
+                Path::new("/dotfiles/original"),
+                Path::new("/dotfiles/dest"),
+                LinkDirectoryBehaviour::LinkDirectory,
+            )],
+        )
+        .expect("Should be able to execute");
+
+    let fs = &executor.filesystem;
+    assert!(fs.symlink_metadata(Path::new("/dotfiles/dest")).unwrap().is_symlink());
+    assert_eq!(
+        fs.read_dir(Path::new("/dotfiles/original")).unwrap(),
+        vec![PathBuf::from("/dotfiles/original/hand_made")]
+    );
+}
+
+#[test]
+fn adopt_copies() {
+    let executor = memory_executor(repo_and_dest(), ConflictStrategy::Adopt);
+    executor
+        .execute(
+            "/dotfiles",
+            "test",
+            &[step(Directive::Copy {
+                from: "repo_file".to_string(),
+                to: "/dotfiles/dest_file".to_string(),
+            })],
+        )
+        .expect("Should be able to execute");
+
+    let fs = &executor.filesystem;
+    assert!(!fs.symlink_metadata(Path::new("/dotfiles/dest_file")).unwrap().is_symlink());
+    assert_eq!(fs.read_to_string(Path::new("/dotfiles/dest_file")).unwrap(), "hand edited");
+    assert_eq!(fs.read_to_string(Path::new("/dotfiles/repo_file")).unwrap(), "hand edited");
+}
+
+#[test]
+fn adopt_refuses_templates_and_symlinks() {
+    let fs = repo_and_dest();
+    fs.add_file("/dotfiles/template.tpl", "rendered").unwrap();
+    fs.symlink(Path::new("/dotfiles/elsewhere"), Path::new("/dotfiles/dest_link"))
+        .unwrap();
+
+    let executor = memory_executor(fs, ConflictStrategy::Adopt);
+    let error = executor
+        .execute(
+            "/dotfiles",
+            "test",
+            &[step(Directive::Template {
+                template: "template.tpl".to_string(),
+                dest: "/dotfiles/dest_file".to_string(),
+                vars: None,
+            })],
+        )
+        .expect_err("Should not adopt templates");
+    assert!(format!("{:#}", error).contains("templates are rendered"), "{:#}", error);
+
+    let error = executor
+        .execute(
+            "/dotfiles",
+            "test",
+            &[link(
+                Path::new("/dotfiles/repo_file"),
+                Path::new("/dotfiles/dest_link"),
+                LinkDirectoryBehaviour::default(),
+            )],
+        )
+        .expect_err("Should not adopt symlinks");
+    assert!(format!("{:#}", error).contains("as it is a symlink"), "{:#}", error);
+
+    let fs = &executor.filesystem;
+    assert_eq!(fs.read_to_string(Path::new("/dotfiles/dest_file")).unwrap(), "hand edited");
+    assert_eq!(fs.read_to_string(Path::new("/dotfiles/repo_file")).unwrap(), "from the repo");
+}
+
+#[test]
+fn adopt_is_rolled_back() {
+    let fs = MemoryFilesystem::new();
+    fs.add_file("/dotfiles/zshrc", "from the repo").unwrap();
+    fs.add_file("/home/tester/.zshrc", "hand edited").unwrap();
+    let runner = RecordingCommandRunner::new();
+    runner.push_output(CommandOutput::exit_code(1));
+    let executor = memory_executor(fs, ConflictStrategy::Adopt).with_command_runner(runner);
+
+    let steps = [
+        link(
+            Path::new("zshrc"),
+            Path::new("/home/tester/.zshrc"),
+            LinkDirectoryBehaviour::default(),
+        ),
+        step(Directive::Run("./install.sh".to_string())),
+    ];
+    executor
+        .transaction(|executor| executor.execute("/dotfiles", "shell", &steps))
+        .expect_err("Should fail");
+
+    let fs = &executor.filesystem;
+    assert_eq!(fs.read_to_string(Path::new("/dotfiles/zshrc")).unwrap(), "from the repo");
+    assert_eq!(fs.read_to_string(Path::new("/home/tester/.zshrc")).unwrap(), "hand edited");
+    assert_eq!(fs.read_dir(Path::new("/dotfiles")).unwrap(), vec![PathBuf::from("/dotfiles/zshrc")]);
+}
+
+#[test]
+fn most_specific_strategy_wins() {
+    let config = Config::from_yaml(
+        r#"
+.dotfilers:
+  conflict_strategy: rename-old
+caches:
+  conflict_strategy: overwrite
+  steps:
+    - copy_from: cache
+      copy_to: /home/tester/.cache/tool
+    - link_from: ssh_config
+      link_to: /home/tester/.ssh/config
+      conflict_strategy: abort
+zsh:
+  - link_from: zshrc
+    link_to: /home/tester/.zshrc
+"#,
+    )
+    .unwrap();
+    let fs = MemoryFilesystem::new();
+    fs.add_file("/dotfiles/cache", "new cache").unwrap();
+    fs.add_file("/dotfiles/ssh_config", "Host *").unwrap();
+    fs.add_file("/dotfiles/zshrc", "export EDITOR=vim").unwrap();
+    fs.add_file("/home/tester/.cache/tool", "old cache").unwrap();
+    fs.add_file("/home/tester/.ssh/config", "hand edited").unwrap();
+    fs.add_file("/home/tester/.zshrc", "old zshrc").unwrap();
+    let executor = memory_executor(fs, config.program.conflict_strategy);
+
+    executor
+        .execute_section("/dotfiles", "zsh", &config.state_config.states["zsh"])
+        .expect("Should be able to execute");
+    let error = executor
+        .execute_section("/dotfiles", "caches", &config.state_config.states["caches"])
+        .expect_err("Should abort on ssh");
+
+    assert!(format!("{:#}", error).contains("ConflictStrategy is set to abort"), "{:#}", error);
+    let fs = &executor.filesystem;
+    assert_eq!(fs.read_to_string(Path::new("/home/tester/.zshrc.bak")).unwrap(), "old zshrc");
+    assert_eq!(fs.read_to_string(Path::new("/home/tester/.cache/tool")).unwrap(), "new cache");
+    assert_eq!(
+        fs.read_dir(Path::new("/home/tester/.cache")).unwrap(),
+        vec![PathBuf::from("/home/tester/.cache/tool")]
+    );
+    assert_eq!(fs.read_to_string(Path::new("/home/tester/.ssh/config")).unwrap(), "hand edited");
+}
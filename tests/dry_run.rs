@@ -0,0 +1,120 @@
+use crate::test_tools::*;
+use dotfilers::{Config, ConflictStrategy, EntryKind, Filesystem, MemoryFilesystem, Operation};
+use std::path::{Path, PathBuf};
+
+fn dry_run(fs: MemoryFilesystem, yaml: &str) -> Vec<Operation> {
+    let mut executor = memory_executor(fs, ConflictStrategy::RenameOld);
+    executor.dry_run = true;
+    deploy(&executor, yaml, None);
+
+    // A dry run does exactly what planning everything at once does
+    let config = Config::from_yaml(yaml).expect("Should be able to parse the config");
+    let plan = executor
+        .plan("/dotfiles", &config.state_config, None)
+        .expect("Should be able to plan");
+    assert_eq!(*executor.dry_run_plan(), plan);
+    assert!(!executor.filesystem.exists(Path::new("/dotfiles/out")));
+    plan.operations.into_iter().map(|o| o.operation).collect()
+}
+
+#[test]
+fn glob_into_dir_that_does_not_exist_yet() {
+    let fs = MemoryFilesystem::new();
+    fs.add_file("/dotfiles/a.txt", "a").unwrap();
+    fs.add_file("/dotfiles/b.txt", "b").unwrap();
+    let pb = PathBuf::from("/dotfiles");
+
+    let operations = dry_run(
+        fs,
+        r#"
+texts:
+  - link_from: "*.txt"
+    link_to: out/texts
+  - copy_from: a.txt
+    copy_to: out/texts/copied
+"#,
+    );
+    assert_eq!(
+        operations,
+        vec![
+            Operation::CreateDir { path: pb.join("out") },
+            Operation::CreateDir {
+                path: pb.join("out/texts")
+            },
+            Operation::Symlink {
+                source: pb.join("a.txt"),
+                destination: pb.join("out/texts/a.txt"),
+                directory: false,
+            },
+            Operation::Symlink {
+                source: pb.join("b.txt"),
+                destination: pb.join("out/texts/b.txt"),
+                directory: false,
+            },
+            Operation::Copy {
+                source: pb.join("a.txt"),
+                destination: pb.join("out/texts/copied"),
+            },
+        ]
+    );
+}
+
+#[test]
+fn later_directives_see_earlier_ones() {
+    let fs = MemoryFilesystem::new();
+    fs.add_file("/dotfiles/dir/config", "copied").unwrap();
+    fs.add_file("/dotfiles/config.tpl", "Host {{ dotfilers_hostname }}").unwrap();
+    let pb = PathBuf::from("/dotfiles");
+
+    let operations = dry_run(
+        fs,
+        r#"
+first:
+  - copy_from: dir
+    copy_to: out
+second:
+  depends_on: [first]
+  steps:
+    - template: config.tpl
+      template_to: out/dir/config
+    - template: config.tpl
+      template_to: out/dir/nested/config
+    - template: config.tpl
+      template_to: out/dir/config
+"#,
+    );
+    assert_eq!(
+        operations,
+        vec![
+            Operation::CreateDir { path: pb.join("out") },
+            Operation::CreateDir { path: pb.join("out/dir") },
+            Operation::Copy {
+                source: pb.join("dir/config"),
+                destination: pb.join("out/dir/config"),
+            },
+            // The copied file is in the way of the template
+            Operation::Backup {
+                path: pb.join("out/dir/config"),
+                backup: pb.join("out/dir/config.bak"),
+            },
+            Operation::WriteRendered {
+                template: pb.join("config.tpl"),
+                destination: pb.join("out/dir/config"),
+                contents: "Host work-laptop".to_string(),
+            },
+            Operation::CreateDir {
+                path: pb.join("out/dir/nested")
+            },
+            Operation::WriteRendered {
+                template: pb.join("config.tpl"),
+                destination: pb.join("out/dir/nested/config"),
+                contents: "Host work-laptop".to_string(),
+            },
+            Operation::Unchanged {
+                kind: EntryKind::Template,
+                source: pb.join("config.tpl"),
+                destination: pb.join("out/dir/config"),
+            },
+        ]
+    );
+}
@@ -0,0 +1,91 @@
+use crate::test_tools::*;
+use dotfilers::{Config, ConflictStrategy, DestinationState, Filesystem, MemoryFilesystem};
+use std::path::{Path, PathBuf};
+
+const CONFIG: &str = r#"
+shell:
+  - link_from: zshrc
+    link_to: /home/tester/.zshrc
+  - link_from: "scripts/*.sh"
+    link_to: /home/tester/bin
+git:
+  - template: gitconfig.tpl
+    template_to: /home/tester/.gitconfig
+    template_vars: git.vars
+  - copy_from: ssh
+    copy_to: /home/tester/.config
+"#;
+
+fn dotfiles() -> MemoryFilesystem {
+    let fs = MemoryFilesystem::new();
+    fs.add_file("/dotfiles/zshrc", "export EDITOR=vim").unwrap();
+    fs.add_file("/dotfiles/scripts/a.sh", "echo a").unwrap();
+    fs.add_file("/dotfiles/scripts/b.sh", "echo b").unwrap();
+    fs.add_file("/dotfiles/scripts/notes.md", "not a script").unwrap();
+    fs.add_file(
+        "/dotfiles/gitconfig.tpl",
+        "[user]\n  name = {{ name }}\n  host = {{ dotfilers_hostname }}",
+    )
+    .unwrap();
+    fs.add_file("/dotfiles/git.vars", "name=Tester").unwrap();
+    fs.add_file("/dotfiles/ssh/config", "Host *").unwrap();
+    fs.set_mode("/dotfiles/ssh/config", 0o600).unwrap();
+    fs.create_dir_all("/home/tester").unwrap();
+    fs
+}
+
+#[test]
+fn deploys_into_memory() {
+    let executor = memory_executor(dotfiles(), ConflictStrategy::Abort);
+    deploy(&executor, CONFIG, None);
+    let fs = executor.filesystem;
+
+    assert_eq!(
+        fs.read_link(Path::new("/home/tester/.zshrc")).unwrap(),
+        PathBuf::from("/dotfiles/zshrc")
+    );
+    assert_eq!(
+        fs.read_dir(Path::new("/home/tester/bin")).unwrap(),
+        vec![PathBuf::from("/home/tester/bin/a.sh"), PathBuf::from("/home/tester/bin/b.sh")]
+    );
+    assert_eq!(fs.read_to_string(Path::new("/home/tester/bin/b.sh")).unwrap(), "echo b");
+    assert_eq!(
+        fs.read_to_string(Path::new("/home/tester/.gitconfig")).unwrap(),
+        "[user]\n  name = Tester\n  host = work-laptop"
+    );
+    let copied = Path::new("/home/tester/.config/ssh/config");
+    assert_eq!(fs.read_to_string(copied).unwrap(), "Host *");
+    assert_eq!(fs.metadata(copied).unwrap().mode, 0o600);
+
+    // Nothing is left to do once everything is deployed
+    let config = Config::from_yaml(CONFIG).unwrap();
+    let executor = memory_executor(fs, ConflictStrategy::Abort);
+    let statuses = executor.status("/dotfiles", &config.state_config, None).unwrap();
+    assert_eq!(statuses.len(), 5);
+    assert!(statuses.iter().all(|s| s.state == DestinationState::Correct));
+}
+
+#[test]
+fn renames_conflicting_files_in_memory() {
+    let fs = dotfiles();
+    fs.add_file("/home/tester/.zshrc", "old zshrc").unwrap();
+    fs.add_file("/home/tester/.zshrc.bak", "older zshrc").unwrap();
+
+    let executor = memory_executor(fs, ConflictStrategy::RenameOld);
+    deploy(&executor, CONFIG, None);
+    let fs = &executor.filesystem;
+
+    assert!(fs.symlink_metadata(Path::new("/home/tester/.zshrc")).unwrap().is_symlink());
+    assert_eq!(fs.read_to_string(Path::new("/home/tester/.zshrc.bak")).unwrap(), "older zshrc");
+    assert_eq!(fs.read_to_string(Path::new("/home/tester/.zshrc.bak1")).unwrap(), "old zshrc");
+}
+
+#[test]
+fn dry_run_in_memory_changes_nothing() {
+    let mut executor = memory_executor(dotfiles(), ConflictStrategy::Abort);
+    executor.dry_run = true;
+    deploy(&executor, CONFIG, None);
+
+    assert!(!executor.dry_run_plan().is_empty());
+    assert!(executor.filesystem.read_dir(Path::new("/home/tester")).unwrap().is_empty());
+}
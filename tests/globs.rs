@@ -1,118 +1,62 @@
 use crate::test_tools::*;
-use dotfilers::{Condition, ConflictStrategy, Directive, DirectiveStep, Executor, LinkDirectoryBehaviour};
+use dotfilers::{ConflictStrategy, Directive, Filesystem, LinkDirectoryBehaviour, MemoryFilesystem};
+use std::path::{Path, PathBuf};
+
+fn dotfiles() -> MemoryFilesystem {
+    let fs = MemoryFilesystem::new();
+    fs.add_file("/dotfiles/from/a.txt", "a contents").unwrap();
+    fs.add_file("/dotfiles/from/b.txt", "b contents").unwrap();
+    fs.add_file("/dotfiles/from/c.dat", "c contents").unwrap();
+    fs.create_dir_all("/dotfiles/to").unwrap();
+    fs
+}
 
 #[test]
 fn copy_globs_work() {
-    run_with_temp_dir(|pb| {
-        let a_txt = format!("{}.txt", random_string(10));
-        let b_txt = format!("{}.txt", random_string(10));
-        let c_file = format!("{}.dat", random_string(10));
-
-        let a_contents = random_string(10);
-        let b_contents = random_string(10);
-        let c_contents = random_string(10);
-
-        let from_dir_name = random_string(10);
-        let to_dir_name = random_string(10);
-
-        let from_dir_path = pb.join(&from_dir_name);
-        let to_dir_path = pb.join(&to_dir_name);
-        std::fs::create_dir(&from_dir_path).expect("Error creating from dir");
-        std::fs::create_dir(&to_dir_path).expect("Error creating to dir");
-
-        write_file(&pb, &format!("{}/{}", from_dir_name, &a_txt), &a_contents);
-        write_file(&pb, &format!("{}/{}", from_dir_name, &b_txt), &b_contents);
-        write_file(&pb, &format!("{}/{}", from_dir_name, &c_file), &c_contents);
-
-        let executor = Executor::new("", ConflictStrategy::Overwrite);
-        executor
-            .execute(
-                &pb,
-                "test",
-                &[DirectiveStep {
-                    condition: Condition::Always,
-                    directive: Directive::Copy {
-                        from: format!("{}/*.txt", &from_dir_name),
-                        to: to_dir_name,
-                    },
-                }],
-            )
-            .expect("Should be able to execute");
-
-        let mut count = 0;
-        for f in std::fs::read_dir(&to_dir_path).unwrap() {
-            f.unwrap();
-            count += 1;
-        }
-
-        assert_eq!(count, 2);
-
-        let a_read_contents = std::fs::read_to_string(to_dir_path.join(&a_txt)).unwrap();
-        assert_eq!(a_read_contents, a_contents);
-
-        let b_read_contents = std::fs::read_to_string(to_dir_path.join(&b_txt)).unwrap();
-        assert_eq!(b_read_contents, b_contents);
-
-        Ok(())
-    });
+    let executor = memory_executor(dotfiles(), ConflictStrategy::Overwrite);
+    executor
+        .execute(
+            "/dotfiles",
+            "test",
+            &[step(Directive::Copy {
+                from: "from/*.txt".to_string(),
+                to: "to".to_string(),
+            })],
+        )
+        .expect("Should be able to execute");
+
+    let fs = &executor.filesystem;
+    assert_eq!(
+        fs.read_dir(Path::new("/dotfiles/to")).unwrap(),
+        vec![PathBuf::from("/dotfiles/to/a.txt"), PathBuf::from("/dotfiles/to/b.txt")]
+    );
+    assert_eq!(fs.read_to_string(Path::new("/dotfiles/to/a.txt")).unwrap(), "a contents");
+    assert_eq!(fs.read_to_string(Path::new("/dotfiles/to/b.txt")).unwrap(), "b contents");
 }
 
 #[test]
 fn symlink_globs_work() {
-    run_with_temp_dir(|pb| {
-        let a_txt = format!("{}.txt", random_string(10));
-        let b_txt = format!("{}.txt", random_string(10));
-        let c_file = format!("{}.dat", random_string(10));
-
-        let a_contents = random_string(10);
-        let b_contents = random_string(10);
-        let c_contents = random_string(10);
-
-        let from_dir_name = random_string(10);
-        let to_dir_name = random_string(10);
-
-        let from_dir_path = pb.join(&from_dir_name);
-        let to_dir_path = pb.join(&to_dir_name);
-        std::fs::create_dir(&from_dir_path).expect("Error creating from dir");
-        std::fs::create_dir(&to_dir_path).expect("Error creating to dir");
-
-        write_file(&pb, &format!("{}/{}", from_dir_name, &a_txt), &a_contents);
-        write_file(&pb, &format!("{}/{}", from_dir_name, &b_txt), &b_contents);
-        write_file(&pb, &format!("{}/{}", from_dir_name, &c_file), &c_contents);
-
-        let executor = Executor::new("", ConflictStrategy::Overwrite);
-        executor
-            .execute(
-                &pb,
-                "test",
-                &[DirectiveStep {
-                    condition: Condition::Always,
-                    directive: Directive::Link {
-                        from: format!("{}/*.txt", &from_dir_name),
-                        to: to_dir_name,
-                        directory_behaviour: LinkDirectoryBehaviour::default(),
-                    },
-                }],
-            )
-            .expect("Should be able to execute");
-
-        let mut count = 0;
-        for f in std::fs::read_dir(&to_dir_path).unwrap() {
-            f.unwrap();
-            count += 1;
-        }
-
-        assert_eq!(count, 2);
-
-        let a_read_contents = std::fs::read_to_string(to_dir_path.join(&a_txt)).unwrap();
-        assert_eq!(a_read_contents, a_contents);
-
-        let b_read_contents = std::fs::read_to_string(to_dir_path.join(&b_txt)).unwrap();
-        assert_eq!(b_read_contents, b_contents);
-
-        assert!(to_dir_path.join(&a_txt).is_symlink());
-        assert!(to_dir_path.join(&b_txt).is_symlink());
-
-        Ok(())
-    });
+    let executor = memory_executor(dotfiles(), ConflictStrategy::Overwrite);
+    executor
+        .execute(
+            "/dotfiles",
+            "test",
+            &[step(Directive::Link {
+                from: "from/*.txt".to_string(),
+                to: "to".to_string(),
+                directory_behaviour: LinkDirectoryBehaviour::default(),
+            })],
+        )
+        .expect("Should be able to execute");
+
+    let fs = &executor.filesystem;
+    assert_eq!(
+        fs.read_dir(Path::new("/dotfiles/to")).unwrap(),
+        vec![PathBuf::from("/dotfiles/to/a.txt"), PathBuf::from("/dotfiles/to/b.txt")]
+    );
+    assert_eq!(fs.read_to_string(Path::new("/dotfiles/to/a.txt")).unwrap(), "a contents");
+    assert_eq!(fs.read_to_string(Path::new("/dotfiles/to/b.txt")).unwrap(), "b contents");
+
+    assert!(fs.symlink_metadata(Path::new("/dotfiles/to/a.txt")).unwrap().is_symlink());
+    assert!(fs.symlink_metadata(Path::new("/dotfiles/to/b.txt")).unwrap().is_symlink());
 }
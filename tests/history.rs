@@ -0,0 +1,221 @@
+use crate::test_tools::*;
+use chrono::{TimeZone, Utc};
+use dotfilers::{
+    hash_bytes, BackupStore, Change, CommandOutput, Config, ConflictStrategy, Filesystem, MemoryFilesystem, RecordingCommandRunner,
+    RunJournal,
+};
+use std::path::{Path, PathBuf};
+
+const RUNS: &str = "/home/tester/.local/state/dotfilers/runs";
+
+const CONFIG: &str = r#"
+shell:
+  - link_from: zshrc
+    link_to: /home/tester/.zshrc
+git:
+  - copy_from: gitconfig
+    copy_to: /home/tester/.config/git/config
+  - template: ignore.tpl
+    template_to: /home/tester/.config/git/ignore
+"#;
+
+fn dotfiles() -> MemoryFilesystem {
+    let fs = MemoryFilesystem::new();
+    fs.add_file("/dotfiles/zshrc", "export EDITOR=vim").unwrap();
+    fs.add_file("/dotfiles/gitconfig", "[user]").unwrap();
+    fs.add_file("/dotfiles/ignore.tpl", "*.swp").unwrap();
+    fs.add_file("/home/tester/.zshrc", "old zshrc").unwrap();
+    fs
+}
+
+fn journal_at(year: i32) -> RunJournal {
+    RunJournal::started_at(RUNS, Utc.with_ymd_and_hms(year, 1, 1, 0, 0, 0).unwrap())
+}
+
+/// The creation of `path`, whose symlink target or contents are `hashed`.
+fn created(path: &str, hashed: Option<&str>) -> Change {
+    Change::Created {
+        path: PathBuf::from(path),
+        hash: hashed.map(|h| hash_bytes(h.as_bytes())),
+    }
+}
+
+#[test]
+fn every_change_is_journaled() {
+    let mut executor = memory_executor(dotfiles(), ConflictStrategy::Overwrite);
+    executor.run_journal = Some(journal_at(2024));
+    deploy(&executor, CONFIG, None);
+
+    let journal = executor.run_journal.as_ref().unwrap();
+    let changes: Vec<Change> = journal
+        .records(&executor.filesystem, "2024-01-01T00-00-00.000Z")
+        .unwrap()
+        .into_iter()
+        .map(|r| r.change)
+        .collect();
+    assert_eq!(
+        changes,
+        vec![
+            Change::Removed {
+                path: PathBuf::from("/home/tester/.zshrc"),
+                stash: PathBuf::from(RUNS).join("2024-01-01T00-00-00.000Z/removed/home/tester/.zshrc"),
+            },
+            created("/home/tester/.zshrc", Some("/dotfiles/zshrc")),
+            created("/home/tester/.config", None),
+            created("/home/tester/.config/git", None),
+            created("/home/tester/.config/git/config", Some("[user]")),
+            created("/home/tester/.config/git/ignore", Some("*.swp")),
+        ]
+    );
+
+    let runs = journal.runs(&executor.filesystem).unwrap();
+    assert_eq!(runs.len(), 1);
+    assert_eq!(runs[0].id, "2024-01-01T00-00-00.000Z");
+    assert_eq!(runs[0].sections, vec!["shell".to_string(), "git".to_string()]);
+    assert_eq!(runs[0].changes, 6);
+    assert_eq!(runs[0].undone_at, None);
+}
+
+#[test]
+fn undo_puts_the_machine_back() {
+    let mut executor = memory_executor(dotfiles(), ConflictStrategy::Overwrite);
+    executor.run_journal = Some(journal_at(2024));
+    deploy(&executor, CONFIG, None);
+
+    executor.dry_run = true;
+    let summary = executor.undo_run(None).expect("Should be able to undo");
+    assert_eq!(summary, "6 change(s) would be undone");
+    assert!(executor
+        .filesystem
+        .symlink_metadata(Path::new("/home/tester/.zshrc"))
+        .unwrap()
+        .is_symlink());
+
+    executor.dry_run = false;
+    let summary = executor.undo_run(None).expect("Should be able to undo");
+    assert_eq!(summary, "6 change(s) were rolled back");
+    let fs = &executor.filesystem;
+    assert_eq!(fs.read_to_string(Path::new("/home/tester/.zshrc")).unwrap(), "old zshrc");
+    assert!(!fs.exists(Path::new("/home/tester/.config")));
+
+    let runs = executor.run_journal.as_ref().unwrap().runs(fs).unwrap();
+    assert!(runs[0].undone_at.is_some());
+    let error = executor.undo_run(None).expect_err("Should not find anything to undo");
+    assert!(format!("{:#}", error).contains("There is no run left to undo"), "{:#}", error);
+    let error = executor
+        .undo_run(Some("2024-01-01T00-00-00.000Z"))
+        .expect_err("Should not undo twice");
+    assert!(format!("{:#}", error).contains("was already undone"), "{:#}", error);
+}
+
+#[test]
+fn undo_keeps_what_changed_since_the_run() {
+    let mut executor = memory_executor(dotfiles(), ConflictStrategy::Overwrite);
+    executor.run_journal = Some(journal_at(2024));
+    deploy(&executor, CONFIG, None);
+    let fs = &executor.filesystem;
+    fs.add_file("/home/tester/.config/git/config", "[user]\n  name = Edited").unwrap();
+    fs.add_file("/home/tester/.config/alacritty.toml", "added by hand").unwrap();
+
+    let error = executor.undo_run(None).expect_err("Should not throw away local changes");
+    let error = format!("{:#}", error);
+    assert!(
+        error.contains("Refusing to remove /home/tester/.config/git/config as it changed since it was created"),
+        "{}",
+        error
+    );
+    assert!(
+        error.contains("Refusing to remove /home/tester/.config as it is not empty"),
+        "{}",
+        error
+    );
+
+    let fs = &executor.filesystem;
+    assert_eq!(
+        fs.read_to_string(Path::new("/home/tester/.config/git/config")).unwrap(),
+        "[user]\n  name = Edited"
+    );
+    assert_eq!(
+        fs.read_to_string(Path::new("/home/tester/.config/alacritty.toml")).unwrap(),
+        "added by hand"
+    );
+    // Everything else is undone
+    assert!(!fs.exists(Path::new("/home/tester/.config/git/ignore")));
+    assert_eq!(fs.read_to_string(Path::new("/home/tester/.zshrc")).unwrap(), "old zshrc");
+}
+
+#[test]
+fn undo_picks_the_last_run_unless_told_otherwise() {
+    let mut executor = memory_executor(dotfiles(), ConflictStrategy::RenameOld);
+    executor.run_journal = Some(journal_at(2023));
+    deploy(&executor, CONFIG, Some(&["shell"]));
+    executor.run_journal = Some(journal_at(2024));
+    deploy(&executor, CONFIG, Some(&["git"]));
+
+    executor.undo_run(None).expect("Should be able to undo");
+    let fs = &executor.filesystem;
+    assert!(!fs.exists(Path::new("/home/tester/.config")));
+    assert!(fs.symlink_metadata(Path::new("/home/tester/.zshrc")).unwrap().is_symlink());
+
+    executor.undo_run(Some("2023-01-01T00-00-00.000Z")).expect("Should be able to undo");
+    let fs = &executor.filesystem;
+    assert_eq!(fs.read_to_string(Path::new("/home/tester/.zshrc")).unwrap(), "old zshrc");
+    assert!(!fs.exists(Path::new("/home/tester/.zshrc.bak")));
+}
+
+#[test]
+fn undo_brings_backups_out_of_the_store() {
+    let mut executor = memory_executor(dotfiles(), ConflictStrategy::Backup);
+    executor.run_journal = Some(journal_at(2024));
+    let store = BackupStore::started_at(
+        "/home/tester/.local/share/dotfilers/backups",
+        Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap(),
+    );
+    executor.backup_store = Some(store.clone());
+    deploy(&executor, CONFIG, Some(&["shell"]));
+    assert_eq!(store.runs(&executor.filesystem).unwrap().len(), 1);
+
+    executor.undo_run(None).expect("Should be able to undo");
+    let fs = &executor.filesystem;
+    assert_eq!(fs.read_to_string(Path::new("/home/tester/.zshrc")).unwrap(), "old zshrc");
+    assert!(store.runs(fs).unwrap().is_empty());
+}
+
+#[test]
+fn rolled_back_runs_are_marked_as_undone() {
+    let runner = RecordingCommandRunner::new();
+    runner.push_output(CommandOutput::exit_code(1));
+    let mut executor = memory_executor(dotfiles(), ConflictStrategy::Overwrite).with_command_runner(runner);
+    executor.run_journal = Some(journal_at(2024));
+    let config = Config::from_yaml("shell:\n  - link_from: zshrc\n    link_to: /home/tester/.zshrc\n  - run: ./install.sh\n").unwrap();
+
+    executor
+        .transaction(|executor| executor.execute_section("/dotfiles", "shell", &config.state_config.states["shell"]))
+        .expect_err("Should fail");
+
+    let fs = &executor.filesystem;
+    assert_eq!(fs.read_to_string(Path::new("/home/tester/.zshrc")).unwrap(), "old zshrc");
+    let runs = executor.run_journal.as_ref().unwrap().runs(fs).unwrap();
+    assert_eq!(runs.len(), 1);
+    assert!(runs[0].undone_at.is_some());
+}
+
+#[test]
+fn only_the_last_runs_are_kept() {
+    let mut executor = memory_executor(dotfiles(), ConflictStrategy::Overwrite);
+    for year in [2022, 2023, 2024] {
+        executor.filesystem.remove(Path::new("/home/tester/.zshrc")).unwrap();
+        executor.filesystem.add_file("/home/tester/.zshrc", "edited by hand").unwrap();
+        executor.run_journal = Some(journal_at(year).with_kept_runs(2));
+        deploy(&executor, CONFIG, Some(&["shell"]));
+    }
+    // Nothing changes, so nothing is journaled nor pruned
+    executor.run_journal = Some(journal_at(2025).with_kept_runs(1));
+    deploy(&executor, CONFIG, Some(&["shell"]));
+
+    let fs = &executor.filesystem;
+    let runs = executor.run_journal.as_ref().unwrap().runs(fs).unwrap();
+    let ids: Vec<&str> = runs.iter().map(|r| r.id.as_str()).collect();
+    assert_eq!(ids, vec!["2023-01-01T00-00-00.000Z", "2024-01-01T00-00-00.000Z"]);
+    assert!(!fs.exists(&Path::new(RUNS).join("2022-01-01T00-00-00.000Z")));
+}
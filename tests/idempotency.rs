@@ -0,0 +1,184 @@
+use crate::test_tools::*;
+use dotfilers::{ConflictStrategy, Directive, Filesystem, LinkDirectoryBehaviour, MemoryFilesystem};
+use std::path::{Path, PathBuf};
+
+fn execute_twice(fs: MemoryFilesystem, directive: Directive) -> MemoryFilesystem {
+    let executor = memory_executor(fs, ConflictStrategy::RenameOld);
+    let steps = [step(directive)];
+    executor.execute("/dotfiles", "test", &steps).expect("Should be able to execute");
+    executor
+        .execute("/dotfiles", "test", &steps)
+        .expect("Should be able to execute again");
+    executor.filesystem
+}
+
+fn backups(fs: &MemoryFilesystem, dir: &str) -> Vec<String> {
+    fs.read_dir(Path::new(dir))
+        .unwrap()
+        .iter()
+        .filter_map(|p| p.file_name().map(|f| f.to_string_lossy().to_string()))
+        .filter(|name| name.contains(".bak"))
+        .collect()
+}
+
+#[test]
+fn link_is_not_backed_up_when_unchanged() {
+    let fs = MemoryFilesystem::new();
+    fs.add_file("/dotfiles/original", "contents").unwrap();
+    fs.create_dir_all("/dotfiles/original_dir").unwrap();
+
+    let fs = execute_twice(
+        fs,
+        Directive::Link {
+            from: "original".to_string(),
+            to: "dest".to_string(),
+            directory_behaviour: LinkDirectoryBehaviour::default(),
+        },
+    );
+    let fs = execute_twice(
+        fs,
+        Directive::Link {
+            from: "original_dir".to_string(),
+            to: "dest_dir".to_string(),
+            directory_behaviour: LinkDirectoryBehaviour::LinkDirectory,
+        },
+    );
+
+    assert!(fs.symlink_metadata(Path::new("/dotfiles/dest")).unwrap().is_symlink());
+    assert!(fs.symlink_metadata(Path::new("/dotfiles/dest_dir")).unwrap().is_symlink());
+    assert_eq!(backups(&fs, "/dotfiles"), Vec::<String>::new());
+}
+
+#[test]
+fn link_pointing_elsewhere_is_backed_up() {
+    let fs = MemoryFilesystem::new();
+    fs.add_file("/dotfiles/original", "contents").unwrap();
+    fs.add_file("/dotfiles/other", "other contents").unwrap();
+    fs.symlink(Path::new("/dotfiles/other"), Path::new("/dotfiles/dest")).unwrap();
+
+    let fs = execute_twice(
+        fs,
+        Directive::Link {
+            from: "original".to_string(),
+            to: "dest".to_string(),
+            directory_behaviour: LinkDirectoryBehaviour::default(),
+        },
+    );
+
+    assert_eq!(
+        fs.read_link(Path::new("/dotfiles/dest")).unwrap(),
+        PathBuf::from("/dotfiles/original")
+    );
+    assert_eq!(backups(&fs, "/dotfiles"), vec!["dest.bak".to_string()]);
+}
+
+#[test]
+fn create_directory_is_not_backed_up_when_unchanged() {
+    let fs = MemoryFilesystem::new();
+    fs.add_file("/dotfiles/original/afile", "contents").unwrap();
+
+    let fs = execute_twice(
+        fs,
+        Directive::Link {
+            from: "original".to_string(),
+            to: "dest".to_string(),
+            directory_behaviour: LinkDirectoryBehaviour::CreateDirectory,
+        },
+    );
+
+    assert!(fs.symlink_metadata(Path::new("/dotfiles/dest")).unwrap().is_dir());
+    assert!(fs.symlink_metadata(Path::new("/dotfiles/dest/afile")).unwrap().is_symlink());
+    assert_eq!(backups(&fs, "/dotfiles"), Vec::<String>::new());
+    assert_eq!(backups(&fs, "/dotfiles/dest"), Vec::<String>::new());
+}
+
+#[test]
+fn copy_is_not_backed_up_when_unchanged() {
+    let fs = MemoryFilesystem::new();
+    fs.add_file("/dotfiles/original", "contents").unwrap();
+
+    let fs = execute_twice(
+        fs,
+        Directive::Copy {
+            from: "original".to_string(),
+            to: "dest".to_string(),
+        },
+    );
+
+    assert!(fs.symlink_metadata(Path::new("/dotfiles/dest")).unwrap().is_file());
+    assert_eq!(backups(&fs, "/dotfiles"), Vec::<String>::new());
+}
+
+#[test]
+fn modified_copy_is_backed_up() {
+    let fs = MemoryFilesystem::new();
+    fs.add_file("/dotfiles/original", "original contents").unwrap();
+    fs.add_file("/dotfiles/dest", "local changes").unwrap();
+
+    let fs = execute_twice(
+        fs,
+        Directive::Copy {
+            from: "original".to_string(),
+            to: "dest".to_string(),
+        },
+    );
+
+    assert_eq!(fs.read_to_string(Path::new("/dotfiles/dest")).unwrap(), "original contents");
+    assert_eq!(backups(&fs, "/dotfiles"), vec!["dest.bak".to_string()]);
+}
+
+#[test]
+fn copy_with_different_mode_is_backed_up() {
+    let fs = MemoryFilesystem::new();
+    fs.add_file("/dotfiles/original", "contents").unwrap();
+    fs.add_file("/dotfiles/dest", "contents").unwrap();
+    fs.set_mode("/dotfiles/original", 0o755).unwrap();
+    fs.set_mode("/dotfiles/dest", 0o644).unwrap();
+
+    let fs = execute_twice(
+        fs,
+        Directive::Copy {
+            from: "original".to_string(),
+            to: "dest".to_string(),
+        },
+    );
+
+    assert_eq!(backups(&fs, "/dotfiles"), vec!["dest.bak".to_string()]);
+}
+
+#[test]
+fn template_is_not_backed_up_when_unchanged() {
+    let fs = MemoryFilesystem::new();
+    fs.add_file("/dotfiles/config.tpl", "Host {{ dotfilers_hostname }}").unwrap();
+
+    let fs = execute_twice(
+        fs,
+        Directive::Template {
+            template: "config.tpl".to_string(),
+            dest: "config".to_string(),
+            vars: None,
+        },
+    );
+
+    assert_eq!(fs.read_to_string(Path::new("/dotfiles/config")).unwrap(), "Host work-laptop");
+    assert_eq!(backups(&fs, "/dotfiles"), Vec::<String>::new());
+}
+
+#[test]
+fn outdated_template_is_backed_up() {
+    let fs = MemoryFilesystem::new();
+    fs.add_file("/dotfiles/config.tpl", "Host {{ dotfilers_hostname }}").unwrap();
+    fs.add_file("/dotfiles/config", "Host old-laptop").unwrap();
+
+    let fs = execute_twice(
+        fs,
+        Directive::Template {
+            template: "config.tpl".to_string(),
+            dest: "config".to_string(),
+            vars: None,
+        },
+    );
+
+    assert_eq!(fs.read_to_string(Path::new("/dotfiles/config.bak")).unwrap(), "Host old-laptop");
+    assert_eq!(backups(&fs, "/dotfiles"), vec!["config.bak".to_string()]);
+}
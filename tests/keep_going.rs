@@ -0,0 +1,59 @@
+use crate::test_tools::*;
+use dotfilers::{Config, ConflictStrategy, Filesystem, MemoryFilesystem};
+use std::path::Path;
+
+const CONFIG: &str = r#"
+first:
+  - template: missing.tpl
+    template_to: /home/tester/missing
+  - link_from: zshrc
+    link_to: /home/tester/.zshrc
+second:
+  on_error: abort
+  steps:
+    - copy_from: missing
+      copy_to: /home/tester/copied
+      on_error: continue
+    - copy_from: vimrc
+      copy_to: /home/tester/.vimrc
+"#;
+
+fn dotfiles() -> MemoryFilesystem {
+    let fs = MemoryFilesystem::new();
+    fs.add_file("/dotfiles/zshrc", "export EDITOR=vim").unwrap();
+    fs.add_file("/dotfiles/vimrc", "set number").unwrap();
+    fs.create_dir_all("/home/tester").unwrap();
+    fs
+}
+
+#[test]
+fn failures_are_collected_when_keeping_going() {
+    let mut executor = memory_executor(dotfiles(), ConflictStrategy::Abort);
+    executor.keep_going = true;
+    deploy(&executor, CONFIG, None);
+
+    assert!(executor.filesystem.exists(Path::new("/home/tester/.zshrc")));
+    assert!(executor.filesystem.exists(Path::new("/home/tester/.vimrc")));
+    let failures: Vec<(String, usize)> = executor.failures().iter().map(|f| (f.section.clone(), f.index)).collect();
+    assert_eq!(failures, vec![("first".to_string(), 0), ("second".to_string(), 0)]);
+    assert!(executor.failures()[0].to_string().starts_with("[section=first] [index=0]: "));
+}
+
+#[test]
+fn most_specific_on_error_wins() {
+    let config = Config::from_yaml(CONFIG).unwrap();
+    let executor = memory_executor(dotfiles(), ConflictStrategy::Abort);
+
+    // Without keep going, the first section stops at its first directive
+    executor
+        .execute_section("/dotfiles", "first", &config.state_config.states["first"])
+        .expect_err("Should fail");
+    assert!(!executor.filesystem.exists(Path::new("/home/tester/.zshrc")));
+
+    // The directive is allowed to carry on even if its section is not
+    executor
+        .execute_section("/dotfiles", "second", &config.state_config.states["second"])
+        .expect("Should carry on");
+    assert!(executor.filesystem.exists(Path::new("/home/tester/.vimrc")));
+    assert_eq!(executor.failures().len(), 1);
+}
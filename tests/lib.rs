@@ -1,5 +1,23 @@
 pub mod test_tools;
 
+mod backups;
+mod conditions;
+mod conflict_strategy;
+mod dry_run;
+mod filesystem;
 mod globs;
+mod history;
+mod idempotency;
+mod keep_going;
 mod link_directory_behaviour;
+mod manifest;
+mod os_detection;
+mod plan;
+mod prompt;
+mod run;
+mod sections;
+mod status;
 mod templating;
+mod transaction;
+mod undeploy;
+mod validation;
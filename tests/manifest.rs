@@ -0,0 +1,110 @@
+use crate::test_tools::*;
+use dotfilers::{hash_bytes, ConflictStrategy, Directive, EntryKind, Filesystem, LinkDirectoryBehaviour, Manifest, MemoryFilesystem};
+use std::path::{Path, PathBuf};
+
+#[test]
+fn records_everything_deployed() {
+    let fs = MemoryFilesystem::new();
+    fs.add_file("/dotfiles/linked", "linked contents").unwrap();
+    fs.add_file("/dotfiles/copied", "copied contents").unwrap();
+    fs.add_file("/dotfiles/config.tpl", "Host {{ dotfilers_hostname }}").unwrap();
+
+    let mut executor = memory_executor(fs, ConflictStrategy::RenameOld);
+    open_manifest(&mut executor);
+    executor
+        .execute(
+            "/dotfiles",
+            "test",
+            &[
+                step(Directive::Link {
+                    from: "linked".to_string(),
+                    to: "out/nested/linked".to_string(),
+                    directory_behaviour: LinkDirectoryBehaviour::default(),
+                }),
+                step(Directive::Copy {
+                    from: "copied".to_string(),
+                    to: "out/copied".to_string(),
+                }),
+                step(Directive::Template {
+                    template: "config.tpl".to_string(),
+                    dest: "out/config".to_string(),
+                    vars: None,
+                }),
+            ],
+        )
+        .expect("Should be able to execute");
+
+    let pb = PathBuf::from("/dotfiles");
+    let manifest = Manifest::load(&executor.filesystem, MANIFEST).expect("Should be able to load the manifest");
+    let entries: Vec<_> = manifest.entries.iter().map(|e| (e.kind, e.destination.clone())).collect();
+    assert_eq!(
+        entries,
+        vec![
+            (EntryKind::Directory, pb.join("out")),
+            (EntryKind::Directory, pb.join("out/nested")),
+            (EntryKind::Symlink, pb.join("out/nested/linked")),
+            (EntryKind::Copy, pb.join("out/copied")),
+            (EntryKind::Template, pb.join("out/config")),
+        ]
+    );
+
+    let copied = manifest.get(pb.join("out/copied")).unwrap();
+    assert_eq!(copied.section, "test");
+    assert_eq!(copied.source, Some(pb.join("copied")));
+    assert_eq!(copied.hash, Some(hash_bytes(b"copied contents")));
+    let template = manifest.get(pb.join("out/config")).unwrap();
+    assert_eq!(template.hash, Some(hash_bytes(b"Host work-laptop")));
+    let link = manifest.get(pb.join("out/nested/linked")).unwrap();
+    assert_eq!(link.hash, Some(hash_bytes(b"/dotfiles/linked")));
+}
+
+#[test]
+fn keeps_what_was_deployed_before_a_failure() {
+    let fs = MemoryFilesystem::new();
+    fs.add_file("/dotfiles/copied", "copied contents").unwrap();
+
+    let mut executor = memory_executor(fs, ConflictStrategy::RenameOld);
+    open_manifest(&mut executor);
+    executor
+        .execute(
+            "/dotfiles",
+            "test",
+            &[
+                step(Directive::Copy {
+                    from: "copied".to_string(),
+                    to: "dest".to_string(),
+                }),
+                step(Directive::Copy {
+                    from: "missing".to_string(),
+                    to: "other".to_string(),
+                }),
+            ],
+        )
+        .expect_err("Should have failed");
+
+    let manifest = Manifest::load(&executor.filesystem, MANIFEST).expect("Should be able to load the manifest");
+    assert_eq!(manifest.entries.len(), 1);
+    assert_eq!(manifest.entries[0].destination, PathBuf::from("/dotfiles/dest"));
+}
+
+#[test]
+fn dry_run_does_not_record() {
+    let fs = MemoryFilesystem::new();
+    fs.add_file("/dotfiles/copied", "copied contents").unwrap();
+
+    let mut executor = memory_executor(fs, ConflictStrategy::RenameOld);
+    executor.dry_run = true;
+    open_manifest(&mut executor);
+    executor
+        .execute(
+            "/dotfiles",
+            "test",
+            &[step(Directive::Copy {
+                from: "copied".to_string(),
+                to: "dest".to_string(),
+            })],
+        )
+        .expect("Should be able to execute");
+
+    assert!(!executor.filesystem.exists(Path::new(MANIFEST)));
+}
@@ -0,0 +1,81 @@
+use crate::test_tools::*;
+use dotfilers::OsDetector;
+
+// The OS is detected while gathering facts, before there is an executor and a Filesystem to go through, so the
+// detector reads a fake root laid out on the real disk.
+fn write_os_release(root: &std::path::Path, contents: &str) {
+    let etc = root.join("etc");
+    std::fs::create_dir_all(&etc).expect("Error creating etc dir");
+    write_file(&etc, "os-release", contents);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn detects_distro_from_os_release() {
+    run_with_temp_dir(|pb| {
+        write_os_release(
+            &pb,
+            r#"
+NAME="Arch Linux"
+ID=arch
+BUILD_ID=rolling
+"#,
+        );
+
+        let info = OsDetector::with_root(&pb).detect().expect("Should be able to detect");
+        assert_eq!(info.distro, Some("arch".to_string()));
+        assert_eq!(info.distro_version, None);
+        assert!(!info.is_wsl);
+        assert!(!info.is_container);
+
+        Ok(())
+    });
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn falls_back_to_usr_lib_os_release() {
+    run_with_temp_dir(|pb| {
+        let usr_lib = pb.join("usr").join("lib");
+        std::fs::create_dir_all(&usr_lib).unwrap();
+        write_file(&usr_lib, "os-release", "ID=fedora\nVERSION_ID=38\n");
+
+        let info = OsDetector::with_root(&pb).detect().expect("Should be able to detect");
+        assert_eq!(info.distro, Some("fedora".to_string()));
+        assert_eq!(info.distro_version, Some("38".to_string()));
+
+        Ok(())
+    });
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn detects_wsl_and_containers() {
+    run_with_temp_dir(|pb| {
+        write_os_release(&pb, "ID=ubuntu\nVERSION_ID=\"22.04\"\n");
+        let kernel = pb.join("proc").join("sys").join("kernel");
+        std::fs::create_dir_all(&kernel).unwrap();
+        write_file(&kernel, "osrelease", "5.15.90.1-microsoft-standard-WSL2\n");
+        write_file(&pb, ".dockerenv", "");
+
+        let info = OsDetector::with_root(&pb).detect().expect("Should be able to detect");
+        assert_eq!(info.distro, Some("ubuntu".to_string()));
+        assert_eq!(info.distro_version, Some("22.04".to_string()));
+        assert!(info.is_wsl);
+        assert!(info.is_container);
+
+        Ok(())
+    });
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn missing_os_release_is_not_an_error() {
+    run_with_temp_dir(|pb| {
+        let info = OsDetector::with_root(&pb).detect().expect("Should be able to detect");
+        assert_eq!(info.distro, None);
+        assert_eq!(info.distro_version, None);
+
+        Ok(())
+    });
+}
@@ -0,0 +1,95 @@
+use crate::test_tools::*;
+use dotfilers::{Config, ConflictStrategy, EntryKind, Filesystem, MemoryFilesystem, Operation, RecordingCommandRunner};
+use std::path::PathBuf;
+
+const CONFIG: &str = r#"
+files:
+  - link_from: linked
+    link_to: out/linked
+  - copy_from: dir
+    copy_to: out/copied
+  - template: config.tpl
+    template_to: out/config
+  - run: touch ran
+"#;
+
+fn dotfiles() -> MemoryFilesystem {
+    let fs = MemoryFilesystem::new();
+    fs.add_file("/dotfiles/linked", "linked contents").unwrap();
+    fs.add_file("/dotfiles/dir/file", "copied contents").unwrap();
+    fs.add_file("/dotfiles/config.tpl", "Host {{ dotfilers_hostname }}").unwrap();
+    fs
+}
+
+#[test]
+fn plan_does_not_change_anything() {
+    let fs = dotfiles();
+    fs.add_file("/dotfiles/out/linked", "in the way").unwrap();
+    let config = Config::from_yaml(CONFIG).unwrap();
+    let executor = memory_executor(fs, ConflictStrategy::Overwrite).with_command_runner(RecordingCommandRunner::new());
+    let pb = PathBuf::from("/dotfiles");
+
+    let plan = executor
+        .plan("/dotfiles", &config.state_config, None)
+        .expect("Should be able to plan");
+    let operations: Vec<&Operation> = plan.operations.iter().map(|o| &o.operation).collect();
+    assert_eq!(
+        operations[0],
+        &Operation::Remove {
+            path: pb.join("out/linked")
+        }
+    );
+    assert!(operations.contains(&&Operation::Copy {
+        source: pb.join("dir/file"),
+        destination: pb.join("out/copied/dir/file"),
+    }));
+    assert!(operations.contains(&&Operation::WriteRendered {
+        template: pb.join("config.tpl"),
+        destination: pb.join("out/config"),
+        contents: "Host work-laptop".to_string(),
+    }));
+    assert!(matches!(operations.last(), Some(Operation::Run { command, .. }) if command == "touch ran"));
+
+    let fs = &executor.filesystem;
+    assert_eq!(fs.read_to_string(&pb.join("out/linked")).unwrap(), "in the way");
+    assert!(executor.command_runner.invocations().is_empty());
+}
+
+#[test]
+fn applying_the_plan_deploys_everything() {
+    let config = Config::from_yaml(CONFIG).unwrap();
+    let executor = memory_executor(dotfiles(), ConflictStrategy::RenameOld).with_command_runner(RecordingCommandRunner::new());
+    let pb = PathBuf::from("/dotfiles");
+
+    let plan = executor
+        .plan("/dotfiles", &config.state_config, None)
+        .expect("Should be able to plan");
+    executor.apply(&plan).expect("Should be able to apply");
+
+    let fs = &executor.filesystem;
+    assert!(fs.symlink_metadata(&pb.join("out/linked")).unwrap().is_symlink());
+    assert_eq!(fs.read_to_string(&pb.join("out/copied/dir/file")).unwrap(), "copied contents");
+    assert_eq!(fs.read_to_string(&pb.join("out/config")).unwrap(), "Host work-laptop");
+    let commands: Vec<String> = executor
+        .command_runner
+        .invocations()
+        .into_iter()
+        .map(|i| i.args[1].clone())
+        .collect();
+    assert_eq!(commands, vec!["touch ran".to_string()]);
+
+    // Planning again only finds things to leave as they are
+    let plan = executor
+        .plan("/dotfiles", &config.state_config, None)
+        .expect("Should be able to plan");
+    let unchanged: Vec<EntryKind> = plan
+        .operations
+        .iter()
+        .filter_map(|o| match &o.operation {
+            Operation::Unchanged { kind, .. } => Some(*kind),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(unchanged, vec![EntryKind::Symlink, EntryKind::Copy, EntryKind::Template]);
+    assert_eq!(plan.operations.len(), 4);
+}
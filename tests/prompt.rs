@@ -0,0 +1,142 @@
+use crate::test_tools::*;
+use chrono::{TimeZone, Utc};
+use dotfilers::{Answer, BackupStore, Config, ConflictStrategy, Existing, Filesystem, MemoryFilesystem, ScriptedPrompter};
+use std::path::{Path, PathBuf};
+
+const CONFIG: &str = r#"
+shell:
+  - link_from: zshrc
+    link_to: /home/tester/.zshrc
+  - link_from: bashrc
+    link_to: /home/tester/.bashrc
+git:
+  - template: gitconfig.tpl
+    template_to: /home/tester/.gitconfig
+    template_vars: git.vars
+"#;
+
+fn dotfiles() -> MemoryFilesystem {
+    let fs = MemoryFilesystem::new();
+    fs.add_file("/dotfiles/zshrc", "export EDITOR=vim").unwrap();
+    fs.add_file("/dotfiles/bashrc", "export EDITOR=nano").unwrap();
+    fs.add_file("/dotfiles/gitconfig.tpl", "[user]\n  name = {{ name }}\n").unwrap();
+    fs.add_file("/dotfiles/git.vars", "name=Tester").unwrap();
+    fs.add_file("/home/tester/.zshrc", "old zshrc").unwrap();
+    fs.add_file("/home/tester/.bashrc", "old bashrc").unwrap();
+    fs.add_file("/home/tester/.gitconfig", "[user]\n  name = Someone\n").unwrap();
+    fs
+}
+
+fn store() -> BackupStore {
+    BackupStore::started_at(
+        "/home/tester/.local/share/dotfilers/backups",
+        Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap(),
+    )
+}
+
+fn deploy(section: &str, prompter: ScriptedPrompter, prompt_fallback: ConflictStrategy) -> (MemoryFilesystem, ScriptedPrompter, String) {
+    let config = Config::from_yaml(CONFIG).unwrap();
+    let mut executor = memory_executor(dotfiles(), ConflictStrategy::Prompt).with_prompter(prompter);
+    executor.prompt_fallback = prompt_fallback;
+    executor.backup_store = Some(store());
+    let result = executor.execute_section("/dotfiles", section, &config.state_config.states[section]);
+    let error = result.err().map(|e| format!("{:#}", e)).unwrap_or_default();
+    (executor.filesystem, executor.prompter, error)
+}
+
+#[test]
+fn answers_are_applied() {
+    let prompter = ScriptedPrompter::new();
+    prompter.push_answer(Answer::Backup, false);
+    prompter.push_answer(Answer::Skip, false);
+
+    let (fs, prompter, error) = deploy("shell", prompter, ConflictStrategy::Abort);
+
+    assert_eq!(error, "");
+    assert!(fs.symlink_metadata(Path::new("/home/tester/.zshrc")).unwrap().is_symlink());
+    let backup = store().path_for(Path::new("/home/tester/.zshrc"));
+    assert_eq!(fs.read_to_string(&backup).unwrap(), "old zshrc");
+    assert!(!fs.exists(Path::new("/home/tester/.zshrc.bak")));
+    assert_eq!(fs.read_to_string(Path::new("/home/tester/.bashrc")).unwrap(), "old bashrc");
+
+    let conflicts = prompter.conflicts();
+    assert_eq!(conflicts.len(), 2);
+    assert_eq!(conflicts[0].section, "shell");
+    assert_eq!(conflicts[0].destination, PathBuf::from("/home/tester/.zshrc"));
+    assert_eq!(conflicts[0].existing, Existing::File);
+    // Symlinks are not diffed
+    assert_eq!(conflicts[0].diff, None);
+}
+
+#[test]
+fn answer_for_all_is_only_asked_once() {
+    let prompter = ScriptedPrompter::new();
+    prompter.push_answer(Answer::Overwrite, true);
+
+    let (fs, prompter, error) = deploy("shell", prompter, ConflictStrategy::Abort);
+
+    assert_eq!(error, "");
+    assert_eq!(prompter.conflicts().len(), 1);
+    assert_eq!(
+        fs.read_dir(Path::new("/home/tester")).unwrap(),
+        vec![
+            PathBuf::from("/home/tester/.bashrc"),
+            PathBuf::from("/home/tester/.gitconfig"),
+            PathBuf::from("/home/tester/.zshrc")
+        ]
+    );
+    assert!(fs.symlink_metadata(Path::new("/home/tester/.bashrc")).unwrap().is_symlink());
+}
+
+#[test]
+fn templates_show_a_diff() {
+    let prompter = ScriptedPrompter::new();
+    prompter.push_answer(Answer::Overwrite, false);
+
+    let (fs, prompter, error) = deploy("git", prompter, ConflictStrategy::Abort);
+
+    assert_eq!(error, "");
+    assert_eq!(
+        fs.read_to_string(Path::new("/home/tester/.gitconfig")).unwrap(),
+        "[user]\n  name = Tester\n"
+    );
+    let diff = prompter.conflicts()[0].diff.clone().expect("Should have a diff");
+    assert!(diff.contains("--- /home/tester/.gitconfig"), "{}", diff);
+    assert!(diff.contains("-  name = Someone"), "{}", diff);
+    assert!(diff.contains("+  name = Tester"), "{}", diff);
+}
+
+#[test]
+fn symlinks_are_shown_with_their_target() {
+    let fs = dotfiles();
+    fs.remove(Path::new("/home/tester/.zshrc")).unwrap();
+    fs.symlink(Path::new("/elsewhere/zshrc"), Path::new("/home/tester/.zshrc")).unwrap();
+    let config = Config::from_yaml(CONFIG).unwrap();
+    let prompter = ScriptedPrompter::new();
+    prompter.push_answer(Answer::Skip, true);
+    let executor = memory_executor(fs, ConflictStrategy::Prompt).with_prompter(prompter);
+
+    executor
+        .execute_section("/dotfiles", "shell", &config.state_config.states["shell"])
+        .expect("Should be able to execute");
+
+    let conflict = &executor.prompter.conflicts()[0];
+    assert_eq!(conflict.existing, Existing::Symlink(PathBuf::from("/elsewhere/zshrc")));
+    assert_eq!(
+        conflict.to_string(),
+        "[section=shell] /home/tester/.zshrc already exists as a symlink to /elsewhere/zshrc"
+    );
+}
+
+#[test]
+fn non_interactive_runs_use_the_fallback() {
+    let (fs, prompter, error) = deploy("shell", ScriptedPrompter::non_interactive(), ConflictStrategy::RenameOld);
+
+    assert_eq!(error, "");
+    assert!(prompter.conflicts().is_empty());
+    assert_eq!(fs.read_to_string(Path::new("/home/tester/.zshrc.bak")).unwrap(), "old zshrc");
+    assert_eq!(fs.read_to_string(Path::new("/home/tester/.bashrc.bak")).unwrap(), "old bashrc");
+
+    let (_, _, error) = deploy("shell", ScriptedPrompter::non_interactive(), ConflictStrategy::Abort);
+    assert!(error.contains("already exists and ConflictStrategy is set to abort"), "{}", error);
+}
@@ -0,0 +1,48 @@
+use crate::test_tools::*;
+use dotfilers::{CommandInvocation, CommandOutput, Config, ConflictStrategy, MemoryFilesystem, RecordingCommandRunner};
+use std::path::PathBuf;
+
+const CONFIG: &str = r#"
+tools:
+  - run: ./install.sh
+  - run: echo done
+"#;
+
+#[test]
+fn commands_go_through_the_runner() {
+    let fs = MemoryFilesystem::new();
+    fs.create_dir_all("/dotfiles").unwrap();
+    let config = Config::from_yaml(CONFIG).unwrap();
+    let executor = memory_executor(fs, ConflictStrategy::Abort).with_command_runner(RecordingCommandRunner::new());
+
+    executor
+        .execute_section("/dotfiles", "tools", &config.state_config.states["tools"])
+        .expect("Should be able to execute");
+
+    let invocation = |cmd: &str| CommandInvocation {
+        program: "/bin/sh".to_string(),
+        args: vec!["-c".to_string(), cmd.to_string()],
+        current_dir: PathBuf::from("/dotfiles"),
+    };
+    assert_eq!(
+        executor.command_runner.invocations(),
+        vec![invocation("./install.sh"), invocation("echo done")]
+    );
+}
+
+#[test]
+fn failing_command_stops_the_section() {
+    let fs = MemoryFilesystem::new();
+    fs.create_dir_all("/dotfiles").unwrap();
+    let config = Config::from_yaml(CONFIG).unwrap();
+    let runner = RecordingCommandRunner::new();
+    runner.push_output(CommandOutput::exit_code(3).with_stderr("install.sh: not found\n"));
+    let executor = memory_executor(fs, ConflictStrategy::Abort).with_command_runner(runner);
+
+    let error = executor
+        .execute_section("/dotfiles", "tools", &config.state_config.states["tools"])
+        .expect_err("Should fail");
+
+    assert!(format!("{:#}", error).contains("Exit status: 3"));
+    assert_eq!(executor.command_runner.invocations().len(), 1);
+}
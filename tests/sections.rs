@@ -0,0 +1,80 @@
+use crate::test_tools::*;
+use dotfilers::{Condition, ConflictStrategy, Directive, Filesystem, MemoryFilesystem, RecordingCommandRunner, Section};
+use std::path::Path;
+
+fn copy_section(from: &str, to: &str) -> Section {
+    Section::from_steps(vec![step(Directive::Copy {
+        from: from.to_string(),
+        to: to.to_string(),
+    })])
+}
+
+#[test]
+fn sections_honour_enabled_and_conditions() {
+    let fs = MemoryFilesystem::new();
+    fs.add_file("/dotfiles/source", "contents").unwrap();
+    let executor = memory_executor(fs, ConflictStrategy::Overwrite);
+
+    let mut enabled = copy_section("source", "enabled");
+    enabled.description = Some("Copies the source".to_string());
+    executor
+        .execute_section("/dotfiles", "enabled", &enabled)
+        .expect("Should be able to execute");
+
+    let mut disabled = copy_section("source", "disabled");
+    disabled.enabled = false;
+    executor
+        .execute_section("/dotfiles", "disabled", &disabled)
+        .expect("Should be able to execute");
+
+    let mut not_matching = copy_section("source", "not_matching");
+    not_matching.condition = Condition::Expression("hostname == 'home'".to_string());
+    executor
+        .execute_section("/dotfiles", "not_matching", &not_matching)
+        .expect("Should be able to execute");
+
+    let mut matching = copy_section("source", "matching");
+    matching.condition = Condition::Expression("hostname == 'work-laptop'".to_string());
+    executor
+        .execute_section("/dotfiles", "matching", &matching)
+        .expect("Should be able to execute");
+
+    let fs = &executor.filesystem;
+    assert!(fs.exists(Path::new("/dotfiles/enabled")));
+    assert!(!fs.exists(Path::new("/dotfiles/disabled")));
+    assert!(!fs.exists(Path::new("/dotfiles/not_matching")));
+    assert!(fs.exists(Path::new("/dotfiles/matching")));
+}
+
+#[test]
+fn included_sections_run_in_declaration_order() {
+    let included = r#"
+zsh:
+  - run: echo zsh
+git:
+  depends_on: [ssh]
+  steps:
+    - run: echo git
+alacritty:
+  - run: echo alacritty
+ssh:
+  - run: echo ssh
+bash:
+  - run: echo bash
+"#;
+    let fs = MemoryFilesystem::new();
+    fs.add_file("/dotfiles/included.yaml", included).unwrap();
+
+    let executor = memory_executor(fs, ConflictStrategy::Overwrite).with_command_runner(RecordingCommandRunner::new());
+    executor
+        .execute("/dotfiles", "test", &[step(Directive::Include("included.yaml".to_string()))])
+        .expect("Should be able to execute");
+
+    let order: Vec<String> = executor
+        .command_runner
+        .invocations()
+        .into_iter()
+        .map(|i| i.args[1].clone())
+        .collect();
+    assert_eq!(order, vec!["echo zsh", "echo ssh", "echo git", "echo alacritty", "echo bash"]);
+}
@@ -0,0 +1,170 @@
+use crate::test_tools::*;
+use dotfilers::{
+    CommandRunner, Config, ConflictStrategy, DestinationState, Executor, Filesystem, MemoryFilesystem, RecordingCommandRunner,
+};
+use std::path::Path;
+
+fn status_executor(fs: MemoryFilesystem) -> Executor<FakeFactsProvider, MemoryFilesystem> {
+    let mut executor = memory_executor(fs, ConflictStrategy::RenameOld);
+    open_manifest(&mut executor);
+    executor
+}
+
+fn states<C: CommandRunner>(executor: &Executor<FakeFactsProvider, MemoryFilesystem, C>, yaml: &str) -> Vec<(String, DestinationState)> {
+    let config = Config::from_yaml(yaml).expect("Should be able to parse the config");
+    executor
+        .status("/dotfiles", &config.state_config, None)
+        .expect("Should be able to get the status")
+        .into_iter()
+        .map(|s| {
+            let destination = s.destination.strip_prefix("/dotfiles").unwrap().display().to_string();
+            (destination, s.state)
+        })
+        .collect()
+}
+
+#[test]
+fn reports_links() {
+    let fs = MemoryFilesystem::new();
+    fs.add_file("/dotfiles/a", "a").unwrap();
+    fs.add_file("/dotfiles/b", "b").unwrap();
+    fs.add_file("/dotfiles/elsewhere", "elsewhere").unwrap();
+    fs.symlink(Path::new("/dotfiles/a"), Path::new("/dotfiles/correct")).unwrap();
+    fs.symlink(Path::new("/dotfiles/elsewhere"), Path::new("/dotfiles/points_elsewhere"))
+        .unwrap();
+    fs.symlink(Path::new("/dotfiles/does_not_exist"), Path::new("/dotfiles/broken"))
+        .unwrap();
+    fs.add_file("/dotfiles/regular", "not a link").unwrap();
+
+    let found = states(
+        &status_executor(fs),
+        r#"
+links:
+  - link_from: a
+    link_to: correct
+  - link_from: a
+    link_to: points_elsewhere
+  - link_from: a
+    link_to: broken
+  - link_from: b
+    link_to: regular
+  - link_from: b
+    link_to: missing
+"#,
+    );
+    assert_eq!(
+        found,
+        vec![
+            ("correct".to_string(), DestinationState::Correct),
+            ("points_elsewhere".to_string(), DestinationState::LinkPointsElsewhere),
+            ("broken".to_string(), DestinationState::BrokenLink),
+            ("regular".to_string(), DestinationState::FileInsteadOfLink),
+            ("missing".to_string(), DestinationState::Missing),
+        ]
+    );
+}
+
+#[test]
+fn reports_copies_and_templates() {
+    let fs = MemoryFilesystem::new();
+    fs.add_file("/dotfiles/copied", "copied contents").unwrap();
+    fs.add_file("/dotfiles/changed_source", "original contents").unwrap();
+    fs.add_file("/dotfiles/config.tpl", "Host {{ dotfilers_hostname }}").unwrap();
+    fs.add_file("/dotfiles/changed.tpl", "Old {{ dotfilers_hostname }}").unwrap();
+    let executor = status_executor(fs);
+    let yaml = r#"
+files:
+  - copy_from: copied
+    copy_to: out/copied
+  - copy_from: changed_source
+    copy_to: out/changed_source
+  - template: config.tpl
+    template_to: out/config
+  - template: changed.tpl
+    template_to: out/changed
+"#;
+    deploy(&executor, yaml, None);
+    assert!(states(&executor, yaml).iter().all(|(_, state)| state == &DestinationState::Correct));
+
+    let fs = &executor.filesystem;
+    fs.add_file("/dotfiles/out/copied", "local changes").unwrap();
+    fs.add_file("/dotfiles/changed_source", "new contents").unwrap();
+    fs.add_file("/dotfiles/changed.tpl", "New {{ dotfilers_hostname }}").unwrap();
+    assert_eq!(
+        states(&executor, yaml),
+        vec![
+            ("out/copied".to_string(), DestinationState::ModifiedLocally),
+            ("out/changed_source".to_string(), DestinationState::OutOfDate),
+            ("out/config".to_string(), DestinationState::Correct),
+            ("out/changed".to_string(), DestinationState::OutOfDate),
+        ]
+    );
+}
+
+#[test]
+fn does_not_change_anything() {
+    let fs = MemoryFilesystem::new();
+    fs.add_file("/dotfiles/dir/a", "a").unwrap();
+    fs.add_file("/dotfiles/file", "file").unwrap();
+    fs.add_file("/dotfiles/config.tpl", "Host {{ dotfilers_hostname }}").unwrap();
+    let executor = status_executor(fs).with_command_runner(RecordingCommandRunner::new());
+
+    let found = states(
+        &executor,
+        r#"
+all:
+  - link_from: dir
+    link_to: out/linked
+    link_directory_behaviour: create
+  - link_from: "*.tpl"
+    link_to: out/glob
+  - copy_from: file
+    copy_to: out/nested/file
+  - template: config.tpl
+    template_to: out/config
+  - run: touch should_not_exist
+"#,
+    );
+    assert_eq!(
+        found,
+        vec![
+            ("out/linked/a".to_string(), DestinationState::Missing),
+            ("out/glob/config.tpl".to_string(), DestinationState::Missing),
+            ("out/nested/file".to_string(), DestinationState::Missing),
+            ("out/config".to_string(), DestinationState::Missing),
+        ]
+    );
+    assert!(!executor.filesystem.exists(Path::new("/dotfiles/out")));
+    assert!(executor.command_runner.invocations().is_empty());
+    assert!(!executor.filesystem.exists(Path::new(MANIFEST)));
+}
+
+#[test]
+fn does_not_apply_conflict_strategies() {
+    let fs = MemoryFilesystem::new();
+    fs.add_file("/dotfiles/a", "a").unwrap();
+    fs.add_file("/dotfiles/regular", "not a link").unwrap();
+    fs.add_file("/dotfiles/copied", "copy").unwrap();
+    fs.add_file("/dotfiles/out/copied", "local changes").unwrap();
+
+    let found = states(
+        &status_executor(fs),
+        r#"
+aborting:
+  conflict_strategy: abort
+  steps:
+    - link_from: a
+      link_to: regular
+    - copy_from: copied
+      copy_to: out/copied
+      conflict_strategy: prompt
+"#,
+    );
+    assert_eq!(
+        found,
+        vec![
+            ("regular".to_string(), DestinationState::FileInsteadOfLink),
+            ("out/copied".to_string(), DestinationState::ModifiedLocally),
+        ]
+    );
+}
@@ -1,51 +1,86 @@
 use crate::test_tools::*;
-use dotfilers::{Condition, ConflictStrategy, Directive, DirectiveStep, Executor};
+use dotfilers::{Condition, ConflictStrategy, Directive, DirectiveStep, Filesystem, MemoryFilesystem};
+use std::path::Path;
 
 #[test]
 fn template_works() {
-    run_with_temp_dir(|pb| {
-        let template_contents = r#"
+    let template_contents = r#"
 Some text
 Created with os {{ dotfilers_os }}
 Here is a variable: {{ name }}
 "#;
-        let variable_contents = r#"
+    let variable_contents = r#"
 name=test
 "#;
-        let expected = r#"
+    let expected = r#"
 Some text
 Created with os linux
 Here is a variable: test
 "#;
 
-        let template_filename = random_string(10);
-        let variable_filename = random_string(10);
-        write_file(&pb, &template_filename, template_contents);
-        write_file(&pb, &variable_filename, variable_contents);
+    let fs = MemoryFilesystem::new();
+    fs.add_file("/dotfiles/template", template_contents).unwrap();
+    fs.add_file("/dotfiles/vars", variable_contents).unwrap();
 
-        let dest_filename = random_string(10);
-        let executor = Executor::new("", ConflictStrategy::Overwrite);
-        executor
-            .execute(
-                &pb,
-                "test",
-                &[DirectiveStep {
-                    condition: Condition::Always,
-                    directive: Directive::Template {
-                        template: template_filename,
-                        dest: dest_filename.clone(),
-                        vars: Some(variable_filename),
-                    },
-                }],
-            )
-            .expect("Should be able to execute");
+    let executor = memory_executor(fs, ConflictStrategy::Overwrite);
+    executor
+        .execute(
+            "/dotfiles",
+            "test",
+            &[step(Directive::Template {
+                template: "template".to_string(),
+                dest: "dest".to_string(),
+                vars: Some("vars".to_string()),
+            })],
+        )
+        .expect("Should be able to execute");
 
-        let dest = pb.join(dest_filename);
-        assert!(dest.exists());
+    let dest_contents = executor
+        .filesystem
+        .read_to_string(Path::new("/dotfiles/dest"))
+        .expect("Should be able to read dest contents");
+    assert_eq!(dest_contents, expected);
+}
 
-        let dest_contents = std::fs::read_to_string(dest).expect("Should be able to read dest contents");
-        assert_eq!(dest_contents, expected);
+#[test]
+fn template_receives_facts() {
+    let template_contents = r#"{{ dotfilers_os }} {{ dotfilers_distro }} {{ dotfilers_distro_version }} {{ dotfilers_arch }} {{ dotfilers_is_container }}
+{{ dotfilers_hostname }} {{ dotfilers_username }} {{ dotfilers_home }} {{ dotfilers_xdg_config_home }} {{ dotfilers_cpu_count }} {{ dotfilers_env.WORK }}"#;
+    let fs = MemoryFilesystem::new();
+    fs.add_file("/dotfiles/template", template_contents).unwrap();
+
+    let executor = memory_executor(fs, ConflictStrategy::Overwrite);
+    executor
+        .execute(
+            "/dotfiles",
+            "test",
+            &[
+                DirectiveStep {
+                    condition: Condition::IfDistro("arch".to_string()),
+                    on_error: None,
+                    conflict_strategy: None,
+                    directive: Directive::Run("exit 1".to_string()),
+                },
+                DirectiveStep {
+                    condition: Condition::IfDistro("debian".to_string()),
+                    on_error: None,
+                    conflict_strategy: None,
+                    directive: Directive::Template {
+                        template: "template".to_string(),
+                        dest: "dest".to_string(),
+                        vars: None,
+                    },
+                },
+            ],
+        )
+        .expect("Should be able to execute");
 
-        Ok(())
-    });
+    let dest_contents = executor
+        .filesystem
+        .read_to_string(Path::new("/dotfiles/dest"))
+        .expect("Should be able to read dest contents");
+    assert_eq!(
+        dest_contents,
+        "linux debian 12 aarch64 true\nwork-laptop tester /home/tester /home/tester/.config 8 1"
+    );
 }
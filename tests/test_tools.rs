@@ -1,8 +1,45 @@
-use dotfilers::Result;
+use dotfilers::{
+    CommandRunner, Condition, Config, ConflictStrategy, Directive, DirectiveStep, Executor, Facts, FactsProvider, ManifestStore,
+    MemoryFilesystem, Os, OsInfo, Result, XdgDirs,
+};
 use rand::{distributions::Alphanumeric, Rng};
+use std::collections::BTreeMap;
 use std::env::temp_dir;
 use std::path::{Path, PathBuf};
 
+pub struct FakeFactsProvider(pub Facts);
+
+impl FactsProvider for FakeFactsProvider {
+    fn gather(&self) -> anyhow::Result<Facts> {
+        Ok(self.0.clone())
+    }
+}
+
+pub fn fake_facts() -> Facts {
+    let home_dir = PathBuf::from("/home/tester");
+    let mut env = BTreeMap::new();
+    env.insert("HOME".to_string(), "/home/tester".to_string());
+    env.insert("WORK".to_string(), "1".to_string());
+    Facts {
+        os: OsInfo {
+            os: Os::Linux,
+            arch: "aarch64".to_string(),
+            distro: Some("debian".to_string()),
+            distro_version: Some("12".to_string()),
+            is_wsl: false,
+            is_container: true,
+        },
+        hostname: "work-laptop".to_string(),
+        username: "tester".to_string(),
+        xdg: XdgDirs::from_env(&home_dir, &env),
+        home_dir,
+        shell: Some("/bin/zsh".to_string()),
+        kernel_version: Some("6.1.0".to_string()),
+        cpu_count: 8,
+        env,
+    }
+}
+
 pub fn run_with_temp_dir(cb: impl FnOnce(PathBuf) -> Result<()>) {
     let tmp = temp_dir();
     let tmp_dir = tmp.join(random_string(10));
@@ -20,11 +57,41 @@ pub fn write_file<P: AsRef<Path>>(dir: P, file_name: &str, contents: &str) {
     std::fs::write(file_path, contents).expect("Error writing test file");
 }
 
-pub fn dir_contents<P: AsRef<Path>>(dir: P) -> Vec<PathBuf> {
-    let mut contents = vec![];
-    let entries = std::fs::read_dir(&dir).unwrap();
-    for entry in entries {
-        contents.push(entry.unwrap().path());
+/// An executor deploying into `fs` instead of the real filesystem, so tests do not need temp dirs.
+pub fn memory_executor(fs: MemoryFilesystem, conflict_strategy: ConflictStrategy) -> Executor<FakeFactsProvider, MemoryFilesystem> {
+    Executor::new("/bin/sh -c", conflict_strategy)
+        .with_facts_provider(FakeFactsProvider(fake_facts()))
+        .with_filesystem(fs)
+}
+
+/// Where `open_manifest` keeps the manifest of a memory executor.
+pub const MANIFEST: &str = "/state/state.json";
+
+/// Makes `executor` record what it deploys in a manifest at [`MANIFEST`].
+pub fn open_manifest<C: CommandRunner>(executor: &mut Executor<FakeFactsProvider, MemoryFilesystem, C>) {
+    executor.manifest = Some(ManifestStore::open(&executor.filesystem, MANIFEST).expect("Should be able to open the manifest"));
+}
+
+/// Deploys the `sections` of `config` (or all of them) from /dotfiles, in the order the config defines.
+pub fn deploy(executor: &Executor<FakeFactsProvider, MemoryFilesystem>, config: &str, sections: Option<&[&str]>) {
+    let config = Config::from_yaml(config).expect("Should be able to parse the config");
+    for name in config
+        .state_config
+        .execution_order(sections)
+        .expect("Should be able to sort sections")
+    {
+        executor
+            .execute_section("/dotfiles", name, &config.state_config.states[name])
+            .expect("Should be able to execute");
+    }
+}
+
+/// A step that always runs `directive`, with no overrides.
+pub fn step(directive: Directive) -> DirectiveStep {
+    DirectiveStep {
+        condition: Condition::Always,
+        on_error: None,
+        conflict_strategy: None,
+        directive,
     }
-    contents
 }
@@ -0,0 +1,86 @@
+use crate::test_tools::*;
+use dotfilers::{CommandOutput, Config, ConflictStrategy, Filesystem, MemoryFilesystem, RecordingCommandRunner};
+use std::path::{Path, PathBuf};
+
+const CONFIG: &str = r#"
+shell:
+  - link_from: zshrc
+    link_to: /home/tester/.zshrc
+  - copy_from: vimrc
+    copy_to: /home/tester/.config/vim/vimrc
+  - run: ./install.sh
+"#;
+
+fn dotfiles() -> MemoryFilesystem {
+    let fs = MemoryFilesystem::new();
+    fs.add_file("/dotfiles/zshrc", "export EDITOR=vim").unwrap();
+    fs.add_file("/dotfiles/vimrc", "set number").unwrap();
+    fs.add_file("/home/tester/.zshrc", "old zshrc").unwrap();
+    fs
+}
+
+fn run_in_transaction(
+    fs: MemoryFilesystem,
+    conflict_strategy: ConflictStrategy,
+    runner: RecordingCommandRunner,
+) -> (MemoryFilesystem, String) {
+    let config = Config::from_yaml(CONFIG).unwrap();
+    let executor = memory_executor(fs, conflict_strategy).with_command_runner(runner);
+    let result = executor.transaction(|executor| executor.execute_section("/dotfiles", "shell", &config.state_config.states["shell"]));
+    let error = result.err().map(|e| format!("{:#}", e)).unwrap_or_default();
+    (executor.filesystem, error)
+}
+
+fn failing_runner() -> RecordingCommandRunner {
+    let runner = RecordingCommandRunner::new();
+    runner.push_output(CommandOutput::exit_code(1));
+    runner
+}
+
+#[test]
+fn failed_run_is_rolled_back() {
+    let config = Config::from_yaml(CONFIG).unwrap();
+    let mut executor = memory_executor(dotfiles(), ConflictStrategy::RenameOld).with_command_runner(failing_runner());
+    open_manifest(&mut executor);
+
+    let error = executor
+        .transaction(|executor| executor.execute_section("/dotfiles", "shell", &config.state_config.states["shell"]))
+        .expect_err("Should fail");
+
+    let error = format!("{:#}", error);
+    assert!(error.contains("5 change(s) were rolled back"), "{}", error);
+    assert!(error.contains("These commands cannot be undone: ./install.sh"), "{}", error);
+    assert!(error.contains("Exit status: 1"), "{}", error);
+
+    let fs = &executor.filesystem;
+    assert_eq!(fs.read_to_string(Path::new("/home/tester/.zshrc")).unwrap(), "old zshrc");
+    assert_eq!(
+        fs.read_dir(Path::new("/home/tester")).unwrap(),
+        vec![PathBuf::from("/home/tester/.zshrc")]
+    );
+    assert!(executor.manifest.as_ref().unwrap().manifest().entries.is_empty());
+}
+
+#[test]
+fn overwritten_files_are_restored() {
+    let (fs, error) = run_in_transaction(dotfiles(), ConflictStrategy::Overwrite, failing_runner());
+
+    assert!(error.contains("were rolled back"), "{}", error);
+    assert_eq!(fs.read_to_string(Path::new("/home/tester/.zshrc")).unwrap(), "old zshrc");
+    assert_eq!(
+        fs.read_dir(Path::new("/home/tester")).unwrap(),
+        vec![PathBuf::from("/home/tester/.zshrc")]
+    );
+}
+
+#[test]
+fn successful_run_gets_rid_of_overwritten_files() {
+    let (fs, error) = run_in_transaction(dotfiles(), ConflictStrategy::Overwrite, RecordingCommandRunner::new());
+
+    assert_eq!(error, "");
+    assert!(fs.symlink_metadata(Path::new("/home/tester/.zshrc")).unwrap().is_symlink());
+    assert_eq!(
+        fs.read_dir(Path::new("/home/tester")).unwrap(),
+        vec![PathBuf::from("/home/tester/.config"), PathBuf::from("/home/tester/.zshrc")]
+    );
+}
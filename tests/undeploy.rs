@@ -0,0 +1,145 @@
+use crate::test_tools::*;
+use dotfilers::{ConflictStrategy, Directive, DirectiveStep, Executor, Filesystem, LinkDirectoryBehaviour, MemoryFilesystem};
+use std::path::{Path, PathBuf};
+
+fn all_directives() -> Vec<DirectiveStep> {
+    vec![
+        step(Directive::Link {
+            from: "linked".to_string(),
+            to: "out/nested/linked".to_string(),
+            directory_behaviour: LinkDirectoryBehaviour::default(),
+        }),
+        step(Directive::Copy {
+            from: "copied".to_string(),
+            to: "out/copied".to_string(),
+        }),
+        step(Directive::Template {
+            template: "config.tpl".to_string(),
+            dest: "out/config".to_string(),
+            vars: None,
+        }),
+    ]
+}
+
+fn deployed(fs: MemoryFilesystem, sections: &[(&str, &[DirectiveStep])]) -> Executor<FakeFactsProvider, MemoryFilesystem> {
+    fs.add_file("/dotfiles/linked", "linked contents").unwrap();
+    fs.add_file("/dotfiles/copied", "copied contents").unwrap();
+    fs.add_file("/dotfiles/config.tpl", "Host {{ dotfilers_hostname }}").unwrap();
+    let mut executor = memory_executor(fs, ConflictStrategy::RenameOld);
+    open_manifest(&mut executor);
+    for (section, steps) in sections {
+        executor.execute("/dotfiles", section, steps).expect("Should be able to execute");
+    }
+    executor
+}
+
+fn is_symlink(fs: &MemoryFilesystem, path: &str) -> bool {
+    fs.symlink_metadata(Path::new(path)).map(|m| m.is_symlink()).unwrap_or(false)
+}
+
+#[test]
+fn removes_everything_deployed() {
+    let executor = deployed(MemoryFilesystem::new(), &[("test", &all_directives())]);
+    assert!(is_symlink(&executor.filesystem, "/dotfiles/out/nested/linked"));
+
+    executor.undeploy(&[], false).expect("Should be able to undeploy");
+
+    let fs = &executor.filesystem;
+    assert!(!fs.exists(Path::new("/dotfiles/out")));
+    assert!(fs.is_file(Path::new("/dotfiles/linked")));
+    assert!(executor.manifest.as_ref().unwrap().manifest().entries.is_empty());
+}
+
+#[test]
+fn only_undeploys_the_requested_sections() {
+    let directives = all_directives();
+    let executor = deployed(
+        MemoryFilesystem::new(),
+        &[("first", &directives[..1]), ("second", &directives[1..])],
+    );
+
+    executor.undeploy(&["second"], false).expect("Should be able to undeploy");
+
+    let fs = &executor.filesystem;
+    assert!(is_symlink(fs, "/dotfiles/out/nested/linked"));
+    assert!(!fs.exists(Path::new("/dotfiles/out/copied")));
+    assert!(!fs.exists(Path::new("/dotfiles/out/config")));
+}
+
+#[test]
+fn rejects_sections_that_deployed_nothing() {
+    let directives = all_directives();
+    let executor = deployed(MemoryFilesystem::new(), &[("nvim", &directives[..1]), ("ssh", &directives[1..])]);
+
+    let err = executor.undeploy(&["nvm"], false).expect_err("Should reject the section");
+    assert_eq!(err.to_string(), "Nothing was deployed for section nvm (did you mean nvim?)");
+    let err = executor
+        .undeploy(&["ssh", "unrelated"], false)
+        .expect_err("Should reject the section");
+    assert_eq!(err.to_string(), "Nothing was deployed for section unrelated");
+
+    let fs = &executor.filesystem;
+    assert!(is_symlink(fs, "/dotfiles/out/nested/linked"));
+    assert!(fs.exists(Path::new("/dotfiles/out/copied")));
+}
+
+#[test]
+fn keeps_dirs_that_are_not_empty() {
+    let executor = deployed(MemoryFilesystem::new(), &[("test", &all_directives())]);
+    executor
+        .filesystem
+        .add_file("/dotfiles/out/unmanaged", "not deployed by dotfilers")
+        .unwrap();
+
+    executor.undeploy(&[], false).expect("Should be able to undeploy");
+
+    let fs = &executor.filesystem;
+    assert!(!fs.exists(Path::new("/dotfiles/out/nested")));
+    assert_eq!(
+        fs.read_dir(Path::new("/dotfiles/out")).unwrap(),
+        vec![PathBuf::from("/dotfiles/out/unmanaged")]
+    );
+}
+
+#[test]
+fn restores_backups() {
+    let fs = MemoryFilesystem::new();
+    fs.add_file("/dotfiles/out/copied", "previous contents").unwrap();
+    let executor = deployed(fs, &[("test", &all_directives()[1..2])]);
+    assert!(executor.filesystem.exists(Path::new("/dotfiles/out/copied.bak")));
+
+    executor.undeploy(&[], false).expect("Should be able to undeploy");
+
+    let fs = &executor.filesystem;
+    assert_eq!(fs.read_to_string(Path::new("/dotfiles/out/copied")).unwrap(), "previous contents");
+    assert!(!fs.exists(Path::new("/dotfiles/out/copied.bak")));
+}
+
+#[test]
+fn refuses_to_remove_modified_files_unless_forced() {
+    let executor = deployed(MemoryFilesystem::new(), &[("test", &all_directives())]);
+    executor.filesystem.add_file("/dotfiles/out/copied", "local changes").unwrap();
+
+    let err = executor.undeploy(&[], false).expect_err("Should refuse to undeploy");
+    assert!(err.to_string().contains("copied"), "{}", err);
+    assert!(is_symlink(&executor.filesystem, "/dotfiles/out/nested/linked"));
+    assert!(executor.filesystem.exists(Path::new("/dotfiles/out/config")));
+
+    executor.undeploy(&[], true).expect("Should be able to undeploy");
+    assert!(!executor.filesystem.exists(Path::new("/dotfiles/out")));
+}
+
+#[test]
+fn dry_run_does_not_remove_anything() {
+    let mut executor = deployed(MemoryFilesystem::new(), &[("test", &all_directives())]);
+    let manifest_before = executor.filesystem.read_to_string(Path::new(MANIFEST)).unwrap();
+
+    executor.dry_run = true;
+    executor.undeploy(&[], false).expect("Should be able to undeploy");
+
+    let fs = &executor.filesystem;
+    assert!(is_symlink(fs, "/dotfiles/out/nested/linked"));
+    assert!(fs.exists(Path::new("/dotfiles/out/copied")));
+    assert!(fs.exists(Path::new("/dotfiles/out/config")));
+    assert_eq!(fs.read_to_string(Path::new(MANIFEST)).unwrap(), manifest_before);
+}
@@ -0,0 +1,100 @@
+use crate::test_tools::*;
+use dotfilers::{Config, ConflictStrategy, Executor, MemoryFilesystem};
+use std::path::PathBuf;
+
+#[test]
+fn valid_config_has_no_problems() {
+    let fs = MemoryFilesystem::new();
+    fs.add_file("/dotfiles/zsh/.zshrc", "export A=1").unwrap();
+    fs.add_file("/dotfiles/zsh/.zshenv", "export B=1").unwrap();
+    fs.add_file("/dotfiles/config.tpl", "Host {{ dotfilers_hostname }}").unwrap();
+    fs.add_file("/dotfiles/vars", "name=test").unwrap();
+    // The shell of the executor
+    fs.add_file("/bin/sh", "").unwrap();
+    fs.set_mode("/bin/sh", 0o755).unwrap();
+    fs.add_file(
+        "/dotfiles/included.yaml",
+        "extra:\n  - copy_from: zsh/.zshrc\n    copy_to: ~/.zshrc\n",
+    )
+    .unwrap();
+    let config = Config::from_yaml(
+        r#"
+zsh:
+  - link_from: zsh/.z*
+    link_to: ~/
+  - template: config.tpl
+    template_to: ~/.ssh/config
+    template_vars: vars
+  - include: included.yaml
+  - run: this-command-is-never-executed
+"#,
+    )
+    .unwrap();
+
+    let executor = memory_executor(fs, ConflictStrategy::Abort);
+    let problems = executor.validate("/dotfiles", "/dotfiles/dotfilers.yaml", &config.state_config);
+    assert_eq!(problems, vec![]);
+}
+
+#[test]
+fn reports_every_problem() {
+    let fs = MemoryFilesystem::new();
+    fs.add_file("/dotfiles/broken.tpl", "Hello {{ name ").unwrap();
+    fs.add_file(
+        "/dotfiles/nested/included.yaml",
+        "nested:\n  - link_from: missing_in_nested\n    link_to: ~/nested\n",
+    )
+    .unwrap();
+    fs.add_file("/dotfiles/nested/invalid.yaml", "invalid:\n  - link_from: a\n")
+        .unwrap();
+    let config = Config::from_yaml(
+        r#"
+first:
+  - link_from: does_not_exist
+    link_to: ~/file
+  - copy_from: "no_dir/*.txt"
+    copy_to: ~/dir
+second:
+  - template: broken.tpl
+    template_to: ~/broken
+    template_vars: missing.vars
+  - template: missing.tpl
+    template_to: ~/missing
+  - include: nested/included.yaml
+  - include: nested/invalid.yaml
+  - include: missing.yaml
+"#,
+    )
+    .unwrap();
+
+    let executor = Executor::new("/does/not/exist/sh -c", ConflictStrategy::Abort)
+        .with_facts_provider(FakeFactsProvider(fake_facts()))
+        .with_filesystem(fs);
+    let problems = executor.validate("/dotfiles", "/dotfiles/dotfilers.yaml", &config.state_config);
+    let found: Vec<(Option<&str>, Option<usize>, &str)> = problems
+        .iter()
+        .map(|p| (p.section.as_deref(), p.index, p.message.as_str()))
+        .collect();
+
+    assert_eq!(problems.len(), 9, "{:#?}", problems);
+    assert!(found[0].2.contains("Could not find shell"));
+    assert_eq!(found[1].0, Some("first"));
+    assert!(found[1].2.contains("does_not_exist"));
+    assert_eq!((found[2].0, found[2].1), (Some("first"), Some(1)));
+    assert!(found[2].2.contains("does not match any file"));
+    // A broken template does not hide the problems with its vars file
+    assert_eq!((found[3].0, found[3].1), (Some("second"), Some(0)));
+    assert!(found[3].2.contains("Error parsing template"));
+    assert_eq!((found[4].0, found[4].1), (Some("second"), Some(0)));
+    assert!(found[4].2.contains("Could not find vars file"));
+    assert!(found[5].2.contains("Could not find template"));
+
+    // Problems inside included files point to the included file
+    assert_eq!(problems[6].file, PathBuf::from("/dotfiles/nested/included.yaml"));
+    assert_eq!((found[6].0, found[6].1), (Some("nested"), Some(0)));
+    assert!(found[6].2.contains("missing_in_nested"));
+
+    assert_eq!((found[7].0, found[7].1), (Some("second"), Some(3)));
+    assert!(found[7].2.contains("invalid.yaml:2:5"), "{}", found[7].2);
+    assert_eq!((found[8].0, found[8].1), (Some("second"), Some(4)));
+}